@@ -0,0 +1,478 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::error::InjectError;
+use crate::matcher::ProcessMatcher;
+use crate::profile::LaunchOptions;
+
+#[cfg(test)]
+pub mod mock;
+
+/// The platform-specific operations the watcher needs, so the watch loop can be
+/// driven by the real Win32 APIs or by a scripted mock in tests.
+pub trait ProcessBackend {
+    /// An opened process.
+    type Process;
+    /// The main thread of a process started by `launch`.
+    type Thread;
+
+    /// Lists every running process `matcher` matches, with as much metadata as
+    /// can be read.
+    fn find_processes(&self, matcher: &ProcessMatcher) -> Vec<ProcessEntry>;
+
+    fn open_process(&self, pid: u32) -> Option<Self::Process>;
+
+    fn list_processes(&self) -> Vec<ProcessEntry>;
+
+    /// Checks whether the process has a visible window, optionally only counting
+    /// windows whose title contains `title` and whose class is exactly `class`.
+    fn has_window(&self, process: &Self::Process, title: Option<&str>, class: Option<&str>)
+    -> bool;
+
+    /// Waits up to `timeout_ms` for the process to wait for input, returns
+    /// false if it's still busy.
+    fn wait_for_input_idle(&self, process: &Self::Process, timeout_ms: u32) -> bool;
+
+    /// Checks whether a module called `name` (ignoring case) is loaded.
+    fn has_module(&self, process: &Self::Process, name: &str) -> bool;
+
+    /// Whether DLLs can be injected into the process yet. A 32-bit game under
+    /// WOW64 only has the kernel32 that loads them once it has started
+    /// running.
+    fn can_inject(&self, process: &Self::Process) -> bool;
+
+    /// Lists the modules loaded in the process.
+    fn list_modules(&self, process: &Self::Process) -> Vec<LoadedModule>;
+
+    /// The folder the process' executable is in.
+    fn process_directory(&self, process: &Self::Process) -> Option<PathBuf>;
+
+    /// Loads the DLL at `dll_path` (absolute) into the process with `method`,
+    /// searching for its dependencies as set in `options`, and returns where
+    /// it ended up.
+    fn inject(
+        &self,
+        process: &Self::Process,
+        dll_path: &Path,
+        method: InjectionMethod,
+        options: &LoadOptions,
+    ) -> Result<LoadedModule, InjectError>;
+
+    /// Calls the function `module` exports as `export` in the process, with a
+    /// pointer to a copy of `argument` (or null), and returns the low 32 bits
+    /// of what it returned. Falls back through `methods` like
+    /// `inject_with_fallback`.
+    fn call_export(
+        &self,
+        process: &Self::Process,
+        module: &LoadedModule,
+        export: &str,
+        argument: Option<&[u8]>,
+        methods: &[InjectionMethod],
+    ) -> Result<u32, InjectError>;
+
+    /// Unloads the DLL at `dll_path` from the process, calling the function it
+    /// exports as `shutdown_export` first if set. Falls back through
+    /// `methods` like `inject_with_fallback`.
+    fn eject(
+        &self,
+        process: &Self::Process,
+        dll_path: &Path,
+        methods: &[InjectionMethod],
+        shutdown_export: Option<&str>,
+    ) -> Result<(), InjectError>;
+
+    /// Waits up to `timeout_ms` for the process to exit, returns whether it did.
+    fn wait_for_exit(&self, process: &Self::Process, timeout_ms: u32) -> bool;
+
+    /// The exit code of a process that has exited.
+    fn exit_code(&self, process: &Self::Process) -> Option<u32>;
+
+    /// Starts `executable` with its main thread suspended.
+    fn launch(
+        &self,
+        executable: &Path,
+        options: &LaunchOptions,
+    ) -> Result<(ProcessEntry, Self::Process, Self::Thread), String>;
+
+    fn resume(&self, thread: &Self::Thread) -> Result<(), String>;
+
+    /// Kills the process and waits for it to be gone.
+    fn terminate(&self, pid: u32) -> Result<(), String>;
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct ProcessEntry {
+    pub pid: u32,
+    pub name: String,
+    pub parent_pid: u32,
+    /// Full path of the executable.
+    pub path: Option<PathBuf>,
+    /// When the process was created, in seconds since the Unix epoch.
+    pub start_time: Option<u64>,
+    pub session_id: Option<u32>,
+}
+
+/// A module loaded in a process.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct LoadedModule {
+    pub base: u64,
+    pub size: u32,
+    pub path: PathBuf,
+}
+
+/// A way of getting the game to load a DLL.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionMethod {
+    /// `ZwCreateThreadEx`, which goes around hooks on the Win32 functions.
+    NtCreateThread,
+    /// The documented `VirtualAllocEx`, `WriteProcessMemory` and
+    /// `CreateRemoteThread`.
+    CreateRemoteThread,
+    /// `QueueUserAPC` on every thread of the game, for when creating threads
+    /// is blocked. The DLL is loaded once one of them waits alertably, maybe
+    /// more than once, which only adds references to it.
+    QueueUserApc,
+}
+
+impl InjectionMethod {
+    /// Every method, in the default fallback order.
+    pub const ALL: [InjectionMethod; 3] = [
+        InjectionMethod::NtCreateThread,
+        InjectionMethod::CreateRemoteThread,
+        InjectionMethod::QueueUserApc,
+    ];
+}
+
+impl fmt::Display for InjectionMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectionMethod::NtCreateThread => write!(f, "ZwCreateThreadEx"),
+            InjectionMethod::CreateRemoteThread => write!(f, "CreateRemoteThread"),
+            InjectionMethod::QueueUserApc => write!(f, "QueueUserAPC"),
+        }
+    }
+}
+
+#[cfg(any(windows, test))]
+const LOAD_WITH_ALTERED_SEARCH_PATH: u32 = 0x0000_0008;
+#[cfg(any(windows, test))]
+const LOAD_LIBRARY_SEARCH_DLL_LOAD_DIR: u32 = 0x0000_0100;
+#[cfg(any(windows, test))]
+const LOAD_LIBRARY_SEARCH_DEFAULT_DIRS: u32 = 0x0000_1000;
+
+/// Where `LoadLibraryExW` looks for the DLLs an injected DLL imports.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchPath {
+    /// The game's own search path, like `LoadLibraryW`.
+    #[default]
+    Game,
+    /// `LOAD_WITH_ALTERED_SEARCH_PATH`: the standard search order, with the
+    /// DLL's folder instead of the game's.
+    Altered,
+    /// `LOAD_LIBRARY_SEARCH_DLL_LOAD_DIR`: the DLL's folder, then the game's,
+    /// System32 and the added directories.
+    DllLoadDir,
+}
+
+impl fmt::Display for SearchPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchPath::Game => write!(f, "Game folder"),
+            SearchPath::Altered => write!(f, "DLL folder (altered search path)"),
+            SearchPath::DllLoadDir => write!(f, "DLL folder, game folder and System32"),
+        }
+    }
+}
+
+/// How a DLL's dependencies are found when it's injected.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct LoadOptions {
+    pub search_path: SearchPath,
+    /// Added with `AddDllDirectory` before loading, they stay added until the
+    /// game exits.
+    pub dll_directories: Vec<PathBuf>,
+}
+
+#[cfg(any(windows, test))]
+impl LoadOptions {
+    /// The flags to pass to `LoadLibraryExW`.
+    pub fn flags(&self) -> u32 {
+        // Added directories are only searched with the LOAD_LIBRARY_SEARCH_*
+        // flags, which can't be combined with LOAD_WITH_ALTERED_SEARCH_PATH
+        match (self.search_path, self.dll_directories.is_empty()) {
+            (SearchPath::Game, true) => 0,
+            (SearchPath::Game, false) => LOAD_LIBRARY_SEARCH_DEFAULT_DIRS,
+            (SearchPath::Altered, true) => LOAD_WITH_ALTERED_SEARCH_PATH,
+            (SearchPath::Altered, false) | (SearchPath::DllLoadDir, _) => {
+                LOAD_LIBRARY_SEARCH_DLL_LOAD_DIR | LOAD_LIBRARY_SEARCH_DEFAULT_DIRS
+            }
+        }
+    }
+}
+
+/// Tries `inject` with each of `methods` in order, or all of them if there
+/// are none, and returns the result with the method that worked. The next
+/// method is only tried when nothing ran in the game yet.
+pub fn inject_with_fallback<T>(
+    methods: &[InjectionMethod],
+    mut inject: impl FnMut(InjectionMethod) -> Result<T, InjectError>,
+) -> Result<(T, InjectionMethod), InjectError> {
+    let methods = if methods.is_empty() {
+        &InjectionMethod::ALL[..]
+    } else {
+        methods
+    };
+    let mut methods = methods.iter().peekable();
+    loop {
+        let method = *methods.next().unwrap();
+        match inject(method) {
+            Ok(value) => return Ok((value, method)),
+            Err(e) if e.allows_fallback() && methods.peek().is_some() => {
+                warn!(
+                    "Injecting with {} failed, trying the next method: {}",
+                    method, e
+                )
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(any(windows, test))]
+/// Finds `dll` among the modules of a process by its full path, ignoring case,
+/// the kind of slashes and `.` or `..` components. Failing that, a module that
+/// is the same file reached another way (an 8.3 name, a SUBST drive or a
+/// junction) is a match. Another module with the same file name, such as a
+/// system `version.dll`, is not.
+pub fn find_loaded<'a>(modules: &'a [LoadedModule], dll: &Path) -> Option<&'a LoadedModule> {
+    let key = comparable_path(dll);
+    if let Some(module) = modules.iter().find(|m| comparable_path(&m.path) == key) {
+        return Some(module);
+    }
+    let canonical = std::fs::canonicalize(dll).ok()?;
+    modules
+        .iter()
+        .find(|m| std::fs::canonicalize(&m.path).is_ok_and(|path| path == canonical))
+}
+
+/// `path` lowercased with backslashes and without `.` or `..` components.
+#[cfg(any(windows, test))]
+fn comparable_path(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('/', "\\").to_lowercase();
+    let mut parts = Vec::new();
+    for part in path.split('\\') {
+        match part {
+            "." => {}
+            // `..` stops at the root, like on Windows
+            ".." => {
+                if parts.len() > 1 {
+                    parts.pop();
+                }
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("\\")
+}
+
+#[cfg(windows)]
+pub type PlatformBackend = crate::win32::Win32Backend;
+
+#[cfg(not(windows))]
+pub type PlatformBackend = UnsupportedBackend;
+
+/// Stand-in for platforms Tamamo-X can't inject on, it never finds any process.
+#[cfg(not(windows))]
+#[derive(Default)]
+pub struct UnsupportedBackend;
+
+#[cfg(not(windows))]
+impl ProcessBackend for UnsupportedBackend {
+    type Process = u32;
+    type Thread = u32;
+
+    fn find_processes(&self, _matcher: &ProcessMatcher) -> Vec<ProcessEntry> {
+        Vec::new()
+    }
+
+    fn open_process(&self, _pid: u32) -> Option<u32> {
+        None
+    }
+
+    fn list_processes(&self) -> Vec<ProcessEntry> {
+        Vec::new()
+    }
+
+    fn has_window(&self, _process: &u32, _title: Option<&str>, _class: Option<&str>) -> bool {
+        false
+    }
+
+    fn wait_for_input_idle(&self, _process: &u32, _timeout_ms: u32) -> bool {
+        true
+    }
+
+    fn has_module(&self, _process: &u32, _name: &str) -> bool {
+        false
+    }
+
+    fn list_modules(&self, _process: &u32) -> Vec<LoadedModule> {
+        Vec::new()
+    }
+
+    fn process_directory(&self, _process: &u32) -> Option<PathBuf> {
+        None
+    }
+
+    fn inject(
+        &self,
+        _process: &u32,
+        _dll_path: &Path,
+        _method: InjectionMethod,
+        _options: &LoadOptions,
+    ) -> Result<LoadedModule, InjectError> {
+        Err(InjectError::Unsupported)
+    }
+
+    fn call_export(
+        &self,
+        _process: &u32,
+        _module: &LoadedModule,
+        _export: &str,
+        _argument: Option<&[u8]>,
+        _methods: &[InjectionMethod],
+    ) -> Result<u32, InjectError> {
+        Err(InjectError::Unsupported)
+    }
+
+    fn can_inject(&self, _process: &u32) -> bool {
+        true
+    }
+
+    fn eject(
+        &self,
+        _process: &u32,
+        _dll_path: &Path,
+        _methods: &[InjectionMethod],
+        _shutdown_export: Option<&str>,
+    ) -> Result<(), InjectError> {
+        Err(InjectError::Unsupported)
+    }
+
+    fn wait_for_exit(&self, _process: &u32, _timeout_ms: u32) -> bool {
+        true
+    }
+
+    fn exit_code(&self, _process: &u32) -> Option<u32> {
+        None
+    }
+
+    fn launch(
+        &self,
+        _executable: &Path,
+        _options: &LaunchOptions,
+    ) -> Result<(ProcessEntry, u32, u32), String> {
+        Err("Launching is only supported on Windows".to_string())
+    }
+
+    fn resume(&self, _thread: &u32) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn terminate(&self, _pid: u32) -> Result<(), String> {
+        Err("Terminating is only supported on Windows".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn module(base: u64, path: &str) -> LoadedModule {
+        LoadedModule {
+            base,
+            size: 0x1000,
+            path: PathBuf::from(path),
+        }
+    }
+
+    #[test]
+    fn finds_loaded_module_by_full_path_only() {
+        // Forward slashes so file names are split the same way off Windows
+        let modules = [
+            module(0x7ff6_0000_0000, "C:/Game/UmamusumePrettyDerby.exe"),
+            module(0x7ffa_1234_0000, "C:/Mods/other/hachimi.dll"),
+            module(0x7ffb_0000_0000, "C:/Mods/Hachimi.dll"),
+        ];
+
+        let found = find_loaded(&modules, Path::new("c:/mods/hachimi.dll")).unwrap();
+        assert_eq!(found.base, 0x7ffb_0000_0000);
+
+        let found = find_loaded(&modules, Path::new("C:\\Mods\\other\\HACHIMI.DLL")).unwrap();
+        assert_eq!(found.base, 0x7ffa_1234_0000);
+
+        let found =
+            find_loaded(&modules, Path::new("C:/Game/../Mods/./other/hachimi.dll")).unwrap();
+        assert_eq!(found.base, 0x7ffa_1234_0000);
+
+        // Same file name, different DLL
+        assert_eq!(
+            find_loaded(&modules, Path::new("D:/Mods/hachimi.dll")),
+            None
+        );
+
+        assert_eq!(
+            find_loaded(&modules, Path::new("C:/Mods/missing.dll")),
+            None
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_next_method() {
+        let blocked = InjectError::AccessDenied(ErrorCode::NtStatus(0xC000_0022));
+        let mut tried = Vec::new();
+        let result = inject_with_fallback(&InjectionMethod::ALL, |method| {
+            tried.push(method);
+            match method {
+                InjectionMethod::NtCreateThread => Err(blocked.clone()),
+                _ => Ok(1),
+            }
+        });
+        assert_eq!(result, Ok((1, InjectionMethod::CreateRemoteThread)));
+        assert_eq!(tried, InjectionMethod::ALL[..2]);
+
+        // The last method's error is the one reported
+        let result = inject_with_fallback::<()>(&[], |_| Err(blocked.clone()));
+        assert_eq!(result, Err(blocked));
+    }
+
+    #[test]
+    fn load_flags_search_added_directories() {
+        let mut options = LoadOptions::default();
+        assert_eq!(options.flags(), 0);
+        options.search_path = SearchPath::Altered;
+        assert_eq!(options.flags(), LOAD_WITH_ALTERED_SEARCH_PATH);
+
+        options.dll_directories.push(PathBuf::from("C:/Mods/libs"));
+        assert_eq!(options.flags(), 0x1100);
+        options.search_path = SearchPath::Game;
+        assert_eq!(options.flags(), LOAD_LIBRARY_SEARCH_DEFAULT_DIRS);
+    }
+
+    #[test]
+    fn does_not_fall_back_once_the_dll_ran() {
+        let mut tried = Vec::new();
+        let result = inject_with_fallback::<()>(&InjectionMethod::ALL, |method| {
+            tried.push(method);
+            Err(InjectError::DependencyNotFound)
+        });
+        assert_eq!(result, Err(InjectError::DependencyNotFound));
+        assert_eq!(tried, [InjectionMethod::NtCreateThread]);
+    }
+}
@@ -0,0 +1,508 @@
+//! A scriptable in-memory `ProcessBackend` for driving the watcher in tests.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{InjectionMethod, LoadOptions, LoadedModule, ProcessBackend, ProcessEntry, SearchPath};
+use crate::error::{ErrorCode, InjectError};
+use crate::matcher::{self, ProcessMatcher};
+use crate::profile::LaunchOptions;
+
+/// A scripted process, they start one after another in the order given to
+/// `MockBackend::new` and keep running until they exit.
+pub struct MockProcess {
+    pid: u32,
+    name: String,
+    directory: PathBuf,
+    starts_after: u32,
+    window_after: u32,
+    exits_after: u32,
+    exit_code: u32,
+    /// What exports return when called, others aren't found.
+    exports: Vec<(String, u32)>,
+    /// Injection methods that are denied access.
+    blocked_methods: Vec<InjectionMethod>,
+    /// DLLs that fail to inject, with how many more times they do.
+    failing_dlls: Vec<(PathBuf, u32)>,
+    /// DLLs whose dependencies are only found in their own folder.
+    dlls_with_dependencies: Vec<PathBuf>,
+    /// Modules that get loaded, with the number of polls until they are.
+    modules: Vec<(String, u32)>,
+    /// Number of `can_inject` calls that return false.
+    injectable_after: u32,
+    /// Modules listed by `list_modules`, injected DLLs are added to them.
+    loaded: Vec<LoadedModule>,
+    /// Still listed after being terminated.
+    lingers: bool,
+    window_shown: bool,
+    found: bool,
+}
+
+impl MockProcess {
+    pub fn new(pid: u32, name: &str) -> Self {
+        Self {
+            pid,
+            name: name.to_string(),
+            directory: PathBuf::from("C:/Game"),
+            starts_after: 0,
+            window_after: 0,
+            exits_after: 0,
+            exit_code: 0,
+            exports: Vec::new(),
+            blocked_methods: Vec::new(),
+            failing_dlls: Vec::new(),
+            dlls_with_dependencies: Vec::new(),
+            modules: Vec::new(),
+            injectable_after: 0,
+            loaded: Vec::new(),
+            lingers: false,
+            window_shown: false,
+            found: false,
+        }
+    }
+
+    /// Number of `find_processes` calls after the previous process started
+    /// before this one starts.
+    pub fn starts_after(mut self, polls: u32) -> Self {
+        self.starts_after = polls;
+        self
+    }
+
+    /// Number of `has_window` calls that return false before the window appears.
+    pub fn window_after(mut self, polls: u32) -> Self {
+        self.window_after = polls;
+        self
+    }
+
+    /// Number of `wait_for_exit` calls that time out before the process exits.
+    pub fn exits_after(mut self, polls: u32) -> Self {
+        self.exits_after = polls;
+        self
+    }
+
+    /// The code the process exits with, 0 by default.
+    pub fn exit_code(mut self, code: u32) -> Self {
+        self.exit_code = code;
+        self
+    }
+
+    /// Number of `has_module` calls for `name` that return false before it's
+    /// loaded, other modules are never loaded.
+    pub fn module_after(mut self, name: &str, polls: u32) -> Self {
+        self.modules.push((name.to_string(), polls));
+        self
+    }
+
+    /// Number of `can_inject` calls that return false before DLLs can be
+    /// injected, like a 32-bit game that has yet to load its kernel32.
+    pub fn injectable_after(mut self, polls: u32) -> Self {
+        self.injectable_after = polls;
+        self
+    }
+
+    /// The process is still listed after being terminated, like one that
+    /// takes a while to exit.
+    pub fn lingers(mut self) -> Self {
+        self.lingers = true;
+        self
+    }
+
+    /// The folder the executable is in, `C:/Game` by default.
+    pub fn in_directory(mut self, directory: &Path) -> Self {
+        self.directory = directory.to_path_buf();
+        self
+    }
+
+    /// The DLL is loaded before anything is injected.
+    pub fn loaded_dll(mut self, path: &Path) -> Self {
+        self.loaded.push(LoadedModule {
+            base: 0x7ffe_0000_0000,
+            size: 0x1000,
+            path: path.to_path_buf(),
+        });
+        self
+    }
+
+    /// Every injected DLL exports `name`, which returns `value`.
+    pub fn export(mut self, name: &str, value: u32) -> Self {
+        self.exports.push((name.to_string(), value));
+        self
+    }
+
+    pub fn blocked_method(mut self, method: InjectionMethod) -> Self {
+        self.blocked_methods.push(method);
+        self
+    }
+
+    pub fn failing_dll(self, path: &Path) -> Self {
+        self.flaky_dll(path, u32::MAX)
+    }
+
+    /// The DLL fails to inject `failures` times, then succeeds.
+    pub fn flaky_dll(mut self, path: &Path, failures: u32) -> Self {
+        self.failing_dlls.push((path.to_path_buf(), failures));
+        self
+    }
+
+    /// The DLL's dependencies are next to it, so it only loads when its
+    /// folder is searched.
+    pub fn dll_with_dependencies(mut self, path: &Path) -> Self {
+        self.dlls_with_dependencies.push(path.to_path_buf());
+        self
+    }
+
+    fn entry(&self) -> ProcessEntry {
+        ProcessEntry {
+            pid: self.pid,
+            name: self.name.clone(),
+            path: Some(self.directory.join(&self.name)),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum MockEvent {
+    Found(u32),
+    WindowShown(u32),
+    InputIdle(u32),
+    Injected(u32, PathBuf),
+    InjectionFailed(u32, PathBuf),
+    /// An export was called with this argument.
+    Called(u32, String, Option<Vec<u8>>),
+    Ejected(u32, PathBuf),
+    Exited(u32),
+    Launched(u32),
+    Resumed(u32),
+    Terminated(u32),
+}
+
+#[derive(Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+#[derive(Default)]
+struct MockState {
+    pending: VecDeque<MockProcess>,
+    /// Started by `launch`, one at a time.
+    launches: VecDeque<MockProcess>,
+    running: Vec<MockProcess>,
+    exit_codes: Vec<(u32, u32)>,
+    events: Vec<MockEvent>,
+}
+
+impl MockBackend {
+    pub fn new(processes: impl IntoIterator<Item = MockProcess>) -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                pending: processes.into_iter().collect(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Processes started by `launch`, in order, instead of on their own.
+    pub fn launching(self, processes: impl IntoIterator<Item = MockProcess>) -> Self {
+        self.state.lock().unwrap().launches = processes.into_iter().collect();
+        self
+    }
+
+    pub fn events(&self) -> Vec<MockEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    /// Whether every scripted process has started and exited.
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.pending.is_empty() && state.running.is_empty()
+    }
+}
+
+impl MockState {
+    fn running_mut(&mut self, pid: u32) -> Option<&mut MockProcess> {
+        self.running.iter_mut().find(|p| p.pid == pid)
+    }
+}
+
+impl ProcessBackend for MockBackend {
+    type Process = u32;
+    type Thread = u32;
+
+    fn find_processes(&self, matcher: &ProcessMatcher) -> Vec<ProcessEntry> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(process) = state.pending.front_mut() {
+            if process.starts_after > 0 {
+                process.starts_after -= 1;
+                break;
+            }
+            let process = state.pending.pop_front().unwrap();
+            state.running.push(process);
+        }
+
+        let all: Vec<_> = state.running.iter().map(|p| p.entry()).collect();
+        let entries = matcher::select(matcher, &all, &[]);
+        let mut events = Vec::new();
+        for p in state.running.iter_mut() {
+            if !p.found && entries.iter().any(|e| e.pid == p.pid) {
+                p.found = true;
+                events.push(MockEvent::Found(p.pid));
+            }
+        }
+        state.events.extend(events);
+        entries
+    }
+
+    fn open_process(&self, pid: u32) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state.running.iter().any(|p| p.pid == pid).then_some(pid)
+    }
+
+    fn list_processes(&self) -> Vec<ProcessEntry> {
+        let state = self.state.lock().unwrap();
+        state.running.iter().map(|p| p.entry()).collect()
+    }
+
+    fn has_window(&self, process: &u32, _title: Option<&str>, _class: Option<&str>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(p) = state.running_mut(*process) else {
+            return false;
+        };
+        if p.window_after > 0 {
+            p.window_after -= 1;
+            return false;
+        }
+        if !p.window_shown {
+            p.window_shown = true;
+            state.events.push(MockEvent::WindowShown(*process));
+        }
+        true
+    }
+
+    fn wait_for_input_idle(&self, process: &u32, _timeout_ms: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.events.push(MockEvent::InputIdle(*process));
+        true
+    }
+
+    fn has_module(&self, process: &u32, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(p) = state.running_mut(*process) else {
+            return false;
+        };
+        let Some((_, polls)) = p
+            .modules
+            .iter_mut()
+            .find(|(m, _)| m.eq_ignore_ascii_case(name))
+        else {
+            return false;
+        };
+        if *polls > 0 {
+            *polls -= 1;
+            return false;
+        }
+        true
+    }
+
+    fn can_inject(&self, process: &u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(p) = state.running_mut(*process) else {
+            return false;
+        };
+        if p.injectable_after > 0 {
+            p.injectable_after -= 1;
+            return false;
+        }
+        true
+    }
+
+    fn list_modules(&self, process: &u32) -> Vec<LoadedModule> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .running_mut(*process)
+            .map(|p| p.loaded.clone())
+            .unwrap_or_default()
+    }
+
+    fn process_directory(&self, process: &u32) -> Option<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        state.running_mut(*process).map(|p| p.directory.clone())
+    }
+
+    fn inject(
+        &self,
+        process: &u32,
+        dll_path: &Path,
+        method: InjectionMethod,
+        options: &LoadOptions,
+    ) -> Result<LoadedModule, InjectError> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .running_mut(*process)
+            .is_some_and(|p| p.blocked_methods.contains(&method))
+        {
+            return Err(InjectError::AccessDenied(ErrorCode::NtStatus(0xC000_0022)));
+        }
+        if options.search_path == SearchPath::Game
+            && state
+                .running_mut(*process)
+                .is_some_and(|p| p.dlls_with_dependencies.iter().any(|d| d == dll_path))
+        {
+            state
+                .events
+                .push(MockEvent::InjectionFailed(*process, dll_path.to_path_buf()));
+            return Err(InjectError::DependencyNotFound);
+        }
+        let fails = state.running_mut(*process).is_none_or(|p| {
+            match p.failing_dlls.iter_mut().find(|(d, _)| d == dll_path) {
+                Some((_, failures)) if *failures > 0 => {
+                    *failures -= 1;
+                    true
+                }
+                _ => false,
+            }
+        });
+        let event = if fails {
+            MockEvent::InjectionFailed(*process, dll_path.to_path_buf())
+        } else {
+            MockEvent::Injected(*process, dll_path.to_path_buf())
+        };
+        state.events.push(event);
+        if fails {
+            return Err(InjectError::LoadLibraryFailed(ErrorCode::Win32(1114)));
+        }
+        // Above 4 GiB, like real modules in a 64-bit game
+        let module = LoadedModule {
+            base: 0x7ff0_0000_0000 + state.events.len() as u64 * 0x10_0000,
+            size: 0x1000,
+            path: dll_path.to_path_buf(),
+        };
+        if let Some(p) = state.running_mut(*process) {
+            p.loaded.push(module.clone());
+        }
+        Ok(module)
+    }
+
+    fn call_export(
+        &self,
+        process: &u32,
+        _module: &LoadedModule,
+        export: &str,
+        argument: Option<&[u8]>,
+        methods: &[InjectionMethod],
+    ) -> Result<u32, InjectError> {
+        let mut state = self.state.lock().unwrap();
+        let p = state
+            .running_mut(*process)
+            .ok_or(InjectError::NotInjected)?;
+        // Like the real backend, a queued call can't return a value
+        super::inject_with_fallback(methods, |method| match method {
+            _ if p.blocked_methods.contains(&method) => {
+                Err(InjectError::AccessDenied(ErrorCode::NtStatus(0xC000_0022)))
+            }
+            InjectionMethod::QueueUserApc => Err(InjectError::QueuedExportCall),
+            _ => Ok(()),
+        })?;
+        let value = p
+            .exports
+            .iter()
+            .find(|(name, _)| name == export)
+            .map(|(_, value)| *value);
+        let event = MockEvent::Called(*process, export.to_string(), argument.map(<[u8]>::to_vec));
+        state.events.push(event);
+        value.ok_or_else(|| InjectError::ExportNotFound {
+            name: export.to_string(),
+        })
+    }
+
+    fn eject(
+        &self,
+        process: &u32,
+        dll_path: &Path,
+        methods: &[InjectionMethod],
+        _shutdown_export: Option<&str>,
+    ) -> Result<(), InjectError> {
+        let mut state = self.state.lock().unwrap();
+        let p = state
+            .running_mut(*process)
+            .ok_or(InjectError::NotInjected)?;
+        super::inject_with_fallback(methods, |method| {
+            if p.blocked_methods.contains(&method) {
+                Err(InjectError::AccessDenied(ErrorCode::NtStatus(0xC000_0022)))
+            } else {
+                Ok(())
+            }
+        })?;
+        let index = p
+            .loaded
+            .iter()
+            .position(|m| m.path == dll_path)
+            .ok_or(InjectError::NotInjected)?;
+        p.loaded.remove(index);
+        state
+            .events
+            .push(MockEvent::Ejected(*process, dll_path.to_path_buf()));
+        Ok(())
+    }
+
+    fn wait_for_exit(&self, process: &u32, _timeout_ms: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(p) = state.running_mut(*process) else {
+            return true;
+        };
+        if p.exits_after > 0 {
+            p.exits_after -= 1;
+            return false;
+        }
+        let exit_code = p.exit_code;
+        state.running.retain(|p| p.pid != *process);
+        state.exit_codes.push((*process, exit_code));
+        state.events.push(MockEvent::Exited(*process));
+        true
+    }
+
+    fn exit_code(&self, process: &u32) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state
+            .exit_codes
+            .iter()
+            .find(|(pid, _)| pid == process)
+            .map(|(_, code)| *code)
+    }
+
+    fn launch(
+        &self,
+        executable: &Path,
+        _options: &LaunchOptions,
+    ) -> Result<(ProcessEntry, u32, u32), String> {
+        let mut state = self.state.lock().unwrap();
+        let Some(mut process) = state.launches.pop_front() else {
+            return Err(format!("{} not found", executable.display()));
+        };
+        // Launched processes are handed to the watcher, not found by it
+        process.found = true;
+        let entry = process.entry();
+        state.events.push(MockEvent::Launched(process.pid));
+        state.running.push(process);
+        Ok((entry.clone(), entry.pid, entry.pid))
+    }
+
+    fn resume(&self, thread: &u32) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        state.events.push(MockEvent::Resumed(*thread));
+        Ok(())
+    }
+
+    fn terminate(&self, pid: u32) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let Some(process) = state.running_mut(pid) else {
+            return Err(format!("Process {} is not running", pid));
+        };
+        if !process.lingers {
+            state.running.retain(|p| p.pid != pid);
+        }
+        state.events.push(MockEvent::Terminated(pid));
+        Ok(())
+    }
+}
@@ -0,0 +1,517 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{self, InjectionMethod, LoadOptions, PlatformBackend, ProcessBackend};
+use crate::duplicates;
+use crate::settings::{self, SettingsStore};
+use crate::watcher::{self, DllResult, WatchOutcome, WatcherEvent};
+use crate::{HachimiVersion, game, github, profile};
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+// 2 is used by clap for usage errors.
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_INJECTION_FAILED: i32 = 4;
+
+/// Dynamically injects Hachimi (and other DLL(s)) to UM:PD at runtime.
+///
+/// Starts the GUI when no command is given.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Print machine-readable JSON to stdout instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Wait for the game and inject the DLLs of a profile
+    Watch {
+        /// Profile to use, defaults to the one selected in the GUI
+        #[arg(long)]
+        profile: Option<String>,
+        /// Launch the game suspended and inject before it runs, even if the
+        /// profile doesn't launch it
+        #[arg(long)]
+        launch: bool,
+        /// Close the running game first, then launch it
+        #[arg(long)]
+        restart: bool,
+    },
+    /// Inject DLLs into a running process
+    Inject {
+        #[arg(long)]
+        pid: u32,
+        /// DLL to inject, can be given multiple times
+        #[arg(long = "dll", required = true)]
+        dlls: Vec<PathBuf>,
+        /// Inject DLLs even if they are already loaded
+        #[arg(long)]
+        force: bool,
+        /// Injection method to try, can be given multiple times to fall back
+        /// in that order, defaults to all of them
+        #[arg(long = "method", value_enum)]
+        methods: Vec<MethodArg>,
+    },
+    /// Unload an injected DLL from a running process
+    Eject {
+        #[arg(long)]
+        pid: u32,
+        #[arg(long)]
+        dll: PathBuf,
+        /// Function to call in the DLL before unloading it, defaults to the
+        /// one set for it in the active profile
+        #[arg(long)]
+        shutdown_export: Option<String>,
+        /// Method to run FreeLibrary with, can be given multiple times to
+        /// fall back in that order, defaults to all of them
+        #[arg(long = "method", value_enum)]
+        methods: Vec<MethodArg>,
+    },
+    /// Download the latest Hachimi release
+    Download {
+        #[arg(value_enum)]
+        variant: HachimiArg,
+    },
+    /// List running processes of the known games
+    ListProcesses {
+        /// List every process instead of just the games
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show the configuration and which games are running
+    Status,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum HachimiArg {
+    Hachimi,
+    Edge,
+}
+
+impl From<HachimiArg> for HachimiVersion {
+    fn from(arg: HachimiArg) -> Self {
+        match arg {
+            HachimiArg::Hachimi => HachimiVersion::Original,
+            HachimiArg::Edge => HachimiVersion::Edge,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum MethodArg {
+    NtCreateThread,
+    CreateRemoteThread,
+    QueueUserApc,
+}
+
+impl From<MethodArg> for InjectionMethod {
+    fn from(arg: MethodArg) -> Self {
+        match arg {
+            MethodArg::NtCreateThread => InjectionMethod::NtCreateThread,
+            MethodArg::CreateRemoteThread => InjectionMethod::CreateRemoteThread,
+            MethodArg::QueueUserApc => InjectionMethod::QueueUserApc,
+        }
+    }
+}
+
+/// Runs a command and returns the process exit code.
+pub fn run(command: Command, json: bool) -> i32 {
+    match command {
+        Command::Watch {
+            profile,
+            launch,
+            restart,
+        } => watch(profile, launch, restart, json),
+        Command::Inject {
+            pid,
+            dlls,
+            force,
+            methods,
+        } => {
+            let methods: Vec<_> = methods.into_iter().map(InjectionMethod::from).collect();
+            inject(pid, &dlls, &methods, force, json)
+        }
+        Command::Eject {
+            pid,
+            dll,
+            shutdown_export,
+            methods,
+        } => {
+            let methods: Vec<_> = methods.into_iter().map(InjectionMethod::from).collect();
+            eject(pid, &dll, &methods, shutdown_export, json)
+        }
+        Command::Download { variant } => download(variant.into(), json),
+        Command::ListProcesses { all } => list_processes(all, json),
+        Command::Status => status(json),
+    }
+}
+
+fn fail(json: bool, code: i32, message: String) -> i32 {
+    if json {
+        println!("{}", json!({ "error": message }));
+    } else {
+        eprintln!("error: {}", message);
+    }
+    code
+}
+
+fn watch(profile_name: Option<String>, launch: bool, restart: bool, json: bool) -> i32 {
+    let (_, settings, _) = SettingsStore::load();
+    let (games, _) = game::load_definitions(&settings::config_dir().join("games"));
+
+    let profile = match &profile_name {
+        Some(name) => match settings.profiles.iter().find(|p| &p.name == name) {
+            Some(profile) => profile,
+            None => return fail(json, EXIT_NOT_FOUND, format!("Unknown profile '{}'", name)),
+        },
+        None => settings.active_profile(),
+    };
+    let Some(game) = game::find(&games, &profile.game) else {
+        return fail(
+            json,
+            EXIT_NOT_FOUND,
+            format!(
+                "Unknown game '{}' in profile '{}'",
+                profile.game, profile.name
+            ),
+        );
+    };
+
+    let mut profile = profile.clone();
+    profile.launch.enabled |= launch;
+    let handle = watcher::spawn(
+        PlatformBackend::default(),
+        profile,
+        game.clone(),
+        restart,
+        || {},
+    );
+
+    // One JSON object per line in JSON mode, so progress can be followed live.
+    for event in handle.events.iter() {
+        if json {
+            println!("{}", json!(event));
+        }
+        match event {
+            WatcherEvent::State(state) if !json => println!("{}", state),
+            WatcherEvent::ProcessFound(process) if !json => {
+                println!("Found {} (PID {})", process.name, process.pid)
+            }
+            WatcherEvent::InjectionResult(result) if !json => print_result(&result),
+            WatcherEvent::Warning(warning) if !json => eprintln!("warning: {}", warning),
+            WatcherEvent::Crashed(report) if !json => eprintln!("crash: {}", report),
+            WatcherEvent::DllDisabled(path) if !json => {
+                eprintln!(
+                    "warning: disabled {} after repeated crashes",
+                    path.display()
+                )
+            }
+            WatcherEvent::Finished(WatchOutcome::Injected { succeeded, total }) => {
+                return if succeeded == total {
+                    EXIT_SUCCESS
+                } else {
+                    EXIT_INJECTION_FAILED
+                };
+            }
+            WatcherEvent::Finished(WatchOutcome::Stopped) => return EXIT_SUCCESS,
+            WatcherEvent::Finished(
+                WatchOutcome::Failed { .. } | WatchOutcome::CrashLoop { .. },
+            ) => {
+                return EXIT_FAILURE;
+            }
+            _ => {}
+        }
+    }
+    EXIT_FAILURE
+}
+
+fn inject(pid: u32, dlls: &[PathBuf], methods: &[InjectionMethod], force: bool, json: bool) -> i32 {
+    let backend = PlatformBackend::default();
+    let Some(process) = backend.open_process(pid) else {
+        return fail(
+            json,
+            EXIT_NOT_FOUND,
+            format!("Failed to open process {}", pid),
+        );
+    };
+
+    let game_dir = backend.process_directory(&process);
+    let mut results = Vec::new();
+    for dll in dlls {
+        let path = profile::absolute_dll_path(dll).unwrap_or_else(|_| dll.clone());
+        let modules = backend.list_modules(&process);
+        match duplicates::find(&modules, game_dir.as_deref(), &path) {
+            Some(duplicate) if duplicate.skips() && !force => {
+                results.push(DllResult {
+                    pid,
+                    dll: path,
+                    module: None,
+                    method: None,
+                    skipped: Some(duplicate),
+                    init_returned: None,
+                    error: None,
+                });
+                continue;
+            }
+            Some(duplicate) if !json => eprintln!("warning: {}: {}", path.display(), duplicate),
+            _ => {}
+        }
+        let result = backend::inject_with_fallback(methods, |method| {
+            backend.inject(&process, &path, method, &LoadOptions::default())
+        });
+        let (module, method, error) = match result {
+            Ok((module, method)) => (Some(module), Some(method), None),
+            Err(e) => (None, None, Some(e)),
+        };
+        results.push(DllResult {
+            pid,
+            dll: path,
+            module,
+            method,
+            skipped: None,
+            init_returned: None,
+            error,
+        });
+    }
+
+    if json {
+        let results: Vec<_> = results
+            .iter()
+            .map(|r| {
+                json!({
+                    "dll": r.dll,
+                    "ok": r.error.is_none(),
+                    "module": r.module,
+                    "method": r.method,
+                    "skipped": r.skipped,
+                    "error": r.error,
+                })
+            })
+            .collect();
+        println!("{}", json!({ "pid": pid, "results": results }));
+    } else {
+        for result in &results {
+            print_result(result);
+        }
+    }
+
+    if results.iter().all(|r| r.error.is_none()) {
+        EXIT_SUCCESS
+    } else {
+        EXIT_INJECTION_FAILED
+    }
+}
+
+fn eject(
+    pid: u32,
+    dll: &Path,
+    methods: &[InjectionMethod],
+    shutdown_export: Option<String>,
+    json: bool,
+) -> i32 {
+    let backend = PlatformBackend::default();
+    let Some(process) = backend.open_process(pid) else {
+        return fail(
+            json,
+            EXIT_NOT_FOUND,
+            format!("Failed to open process {}", pid),
+        );
+    };
+    let path = profile::absolute_dll_path(dll).unwrap_or_else(|_| dll.to_path_buf());
+    let shutdown_export = shutdown_export.or_else(|| {
+        let (_, settings, _) = SettingsStore::load();
+        let dll = settings.active_profile().custom_dll(&path)?;
+        dll.shutdown_export.clone()
+    });
+
+    let result = backend.eject(&process, &path, methods, shutdown_export.as_deref());
+    if json {
+        println!(
+            "{}",
+            json!({ "pid": pid, "dll": path, "ok": result.is_ok(), "error": result.as_ref().err() })
+        );
+    } else {
+        match &result {
+            Ok(()) => println!("OK     {} ejected", path.display()),
+            Err(e) => println!("FAILED {}: {}", path.display(), e),
+        }
+    }
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(_) => EXIT_INJECTION_FAILED,
+    }
+}
+
+fn print_result(result: &DllResult) {
+    if let Some(duplicate) = &result.skipped {
+        println!("SKIP   {}: {}", result.dll.display(), duplicate);
+        return;
+    }
+    match (&result.module, &result.error) {
+        (_, Some(e)) => println!("FAILED {}: {}", result.dll.display(), e),
+        (Some(module), None) => println!(
+            "OK     {} at 0x{:016X}, {} bytes",
+            result.dll.display(),
+            module.base,
+            module.size
+        ),
+        (None, None) => println!("OK     {}", result.dll.display()),
+    }
+}
+
+fn download(version: HachimiVersion, json: bool) -> i32 {
+    match github::hachimi_download_latest(version) {
+        Ok(path) => {
+            let path = std::path::absolute(&path).unwrap_or(path);
+            if json {
+                println!("{}", json!({ "variant": version, "path": path }));
+            } else {
+                println!("{}", path.display());
+            }
+            EXIT_SUCCESS
+        }
+        Err(e) => fail(json, EXIT_FAILURE, e),
+    }
+}
+
+fn list_processes(all: bool, json: bool) -> i32 {
+    let (games, _) = game::load_definitions(&settings::config_dir().join("games"));
+    let backend = PlatformBackend::default();
+    let found: Vec<_> = games
+        .iter()
+        .flat_map(|g| {
+            backend
+                .find_processes(&g.process_matcher())
+                .into_iter()
+                .map(|p| (p, Some(g.id.clone())))
+        })
+        .collect();
+    let processes: Vec<_> = if all {
+        backend
+            .list_processes()
+            .into_iter()
+            .map(|p| {
+                let game = found.iter().find(|(f, _)| f.pid == p.pid);
+                let game = game.and_then(|(_, game)| game.clone());
+                (p, game)
+            })
+            .collect()
+    } else {
+        found
+    };
+
+    if json {
+        let processes: Vec<_> = processes
+            .iter()
+            .map(|(p, game)| {
+                json!({
+                    "pid": p.pid,
+                    "name": p.name,
+                    "game": game,
+                    "parent_pid": p.parent_pid,
+                    "path": p.path,
+                    "start_time": p.start_time,
+                    "session_id": p.session_id,
+                })
+            })
+            .collect();
+        println!("{}", json!(processes));
+    } else {
+        for (p, game) in &processes {
+            match game {
+                Some(game) => println!("{:>8}  {} ({})", p.pid, p.name, game),
+                None => println!("{:>8}  {}", p.pid, p.name),
+            }
+            if let Some(path) = &p.path {
+                println!("          {}", path.display());
+            }
+        }
+    }
+    EXIT_SUCCESS
+}
+
+fn status(json: bool) -> i32 {
+    let config_dir = settings::config_dir();
+    let (_, settings, _) = SettingsStore::load();
+    let (games, errors) = game::load_definitions(&config_dir.join("games"));
+    let backend = PlatformBackend::default();
+
+    let running: Vec<_> = games
+        .iter()
+        .map(|g| {
+            let pids: Vec<u32> = backend
+                .find_processes(&g.process_matcher())
+                .iter()
+                .map(|p| p.pid)
+                .collect();
+            (g, pids)
+        })
+        .collect();
+    let hachimi: Vec<_> = [HachimiVersion::Original, HachimiVersion::Edge]
+        .into_iter()
+        .map(|v| (v, Path::new(github::hachimi_dll_path(v)).exists()))
+        .collect();
+
+    if json {
+        println!(
+            "{}",
+            json!({
+                "config_dir": config_dir,
+                "active_profile": settings.active_profile,
+                "profiles": settings.profiles.iter().map(|p| &p.name).collect::<Vec<_>>(),
+                "games": running.iter().map(|(g, pids)| json!({
+                    "id": g.id,
+                    "name": g.name,
+                    "running": pids,
+                })).collect::<Vec<_>>(),
+                "game_definition_errors": errors,
+                "hachimi_downloaded": hachimi.iter().map(|(v, downloaded)| json!({
+                    "variant": v,
+                    "downloaded": downloaded,
+                })).collect::<Vec<_>>(),
+            })
+        );
+    } else {
+        println!("Config directory: {}", config_dir.display());
+        println!("Active profile:   {}", settings.active_profile);
+        println!(
+            "Profiles:         {}",
+            settings
+                .profiles
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        println!("Games:");
+        for (g, pids) in &running {
+            if pids.is_empty() {
+                println!("  {} ({}): not running", g.name, g.id);
+            } else {
+                let pids: Vec<_> = pids.iter().map(|p| p.to_string()).collect();
+                println!("  {} ({}): running, PID {}", g.name, g.id, pids.join(", "));
+            }
+        }
+        for e in &errors {
+            println!("  failed to load {}", e);
+        }
+        for (v, downloaded) in &hachimi {
+            println!(
+                "{}: {}",
+                v.display_name(),
+                if *downloaded {
+                    "downloaded"
+                } else {
+                    "not downloaded"
+                }
+            );
+        }
+    }
+    EXIT_SUCCESS
+}
@@ -0,0 +1,447 @@
+//! Finds the DLLs an injected DLL imports that the game won't be able to load,
+//! before it fails with nothing more than `ERROR_MOD_NOT_FOUND`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{LoadOptions, SearchPath};
+use crate::pe;
+
+/// Where DLLs are looked up and read from, so tests can use a fake one.
+pub trait FileSystem {
+    fn is_file(&self, path: &Path) -> bool;
+    fn read(&self, path: &Path) -> Option<Vec<u8>>;
+}
+
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        fs::read(path).ok()
+    }
+}
+
+/// System32 and the Windows folder, where Windows' own DLLs are.
+pub fn system_dirs() -> Vec<PathBuf> {
+    let root = std::env::var_os("SystemRoot")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(r"C:\Windows"));
+    vec![root.join("System32"), root]
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Resolution {
+    Found(PathBuf),
+    /// Only next to the injected DLL, whose folder isn't searched with its
+    /// search path.
+    NotSearched(PathBuf),
+    Missing,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Dependency {
+    /// File name of the DLL, as imported.
+    pub name: String,
+    /// Delay-loaded DLLs are only needed once one of their functions is called.
+    pub delayed: bool,
+    pub resolution: Resolution,
+    /// What it imports itself, only looked at for DLLs outside the system
+    /// folders.
+    pub dependencies: Vec<Dependency>,
+}
+
+impl Dependency {
+    /// Whether it, or anything it imports, can't be found.
+    pub fn is_broken(&self) -> bool {
+        !matches!(self.resolution, Resolution::Found(_))
+            || self.dependencies.iter().any(Dependency::is_broken)
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if self.delayed {
+            write!(f, " (delay-loaded)")?;
+        }
+        match &self.resolution {
+            Resolution::Found(_) => Ok(()),
+            Resolution::NotSearched(path) => write!(
+                f,
+                ": only in {}, which is not searched, change where dependencies are searched in the DLL's options",
+                path.parent().unwrap_or(path).display()
+            ),
+            Resolution::Missing => write!(f, ": not found"),
+        }
+    }
+}
+
+/// Keeps only the dependencies that can't be found and the DLLs leading to them.
+pub fn broken_only(dependencies: Vec<Dependency>) -> Vec<Dependency> {
+    dependencies
+        .into_iter()
+        .filter(Dependency::is_broken)
+        .map(|mut dependency| {
+            dependency.dependencies = broken_only(dependency.dependencies);
+            dependency
+        })
+        .collect()
+}
+
+/// DLLs that import each other, so none of them can be injected first.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ImportCycle(pub Vec<PathBuf>);
+
+impl fmt::Display for ImportCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self
+            .0
+            .iter()
+            .map(|path| path.file_name().unwrap_or_default().to_string_lossy())
+            .collect();
+        write!(f, "The DLLs import each other: {}", names.join(" → "))
+    }
+}
+
+/// The order to inject `dlls` in, as indices into it: their own order, except
+/// that each comes after the others it imports. Delay-loaded imports don't
+/// count, they are only loaded once called.
+pub fn import_order(files: &impl FileSystem, dlls: &[&Path]) -> Result<Vec<usize>, ImportCycle> {
+    let name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_lowercase());
+    // For each DLL, the others it imports
+    let imports: Vec<Vec<usize>> = dlls
+        .iter()
+        .enumerate()
+        .map(|(i, dll)| {
+            let imported: Vec<_> = files
+                .read(dll)
+                .and_then(|data| pe::parse_imports(&data).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|import| !import.delayed)
+                .map(|import| import.dll.to_lowercase())
+                .collect();
+            (0..dlls.len())
+                .filter(|&j| j != i && name(dlls[j]).is_some_and(|name| imported.contains(&name)))
+                .collect()
+        })
+        .collect();
+
+    let mut placed = vec![false; dlls.len()];
+    let mut order = Vec::new();
+    while order.len() < dlls.len() {
+        // The first DLL in the list whose imports are all placed already
+        let ready = (0..dlls.len()).find(|&i| !placed[i] && imports[i].iter().all(|&j| placed[j]));
+        if let Some(i) = ready {
+            placed[i] = true;
+            order.push(i);
+            continue;
+        }
+
+        // Every DLL left imports another one that's left, follow them around
+        let mut path = vec![placed.iter().position(|&p| !p).unwrap()];
+        loop {
+            let next = imports[*path.last().unwrap()]
+                .iter()
+                .copied()
+                .find(|&j| !placed[j])
+                .unwrap();
+            if let Some(start) = path.iter().position(|&i| i == next) {
+                let cycle = path[start..].iter().chain([&next]);
+                return Err(ImportCycle(cycle.map(|&i| dlls[i].to_path_buf()).collect()));
+            }
+            path.push(next);
+        }
+    }
+    Ok(order)
+}
+
+/// Looks up the dependencies of a DLL the way the game will when it's loaded
+/// with `LoadOptions`.
+pub struct Resolver<'a, F: FileSystem> {
+    files: &'a F,
+    /// Searched in order, before the system folders.
+    dirs: Vec<PathBuf>,
+    system_dirs: Vec<PathBuf>,
+    /// The injected DLL's folder, when it's not searched.
+    unsearched_dir: Option<PathBuf>,
+}
+
+impl<'a, F: FileSystem> Resolver<'a, F> {
+    pub fn new(
+        files: &'a F,
+        dll: &Path,
+        load: &LoadOptions,
+        game_dir: Option<&Path>,
+        system_dirs: Vec<PathBuf>,
+    ) -> Self {
+        let dll_dir = dll.parent().map(Path::to_path_buf);
+        let searches_dll_dir = load.search_path != SearchPath::Game;
+        let mut dirs = Vec::new();
+        if searches_dll_dir {
+            dirs.extend(dll_dir.clone());
+        }
+        dirs.extend(load.dll_directories.iter().cloned());
+        dirs.extend(game_dir.map(Path::to_path_buf));
+        Self {
+            files,
+            dirs,
+            system_dirs,
+            unsearched_dir: dll_dir.filter(|_| !searches_dll_dir),
+        }
+    }
+
+    /// The tree of DLLs the DLL at `path` imports. A DLL imported more than
+    /// once is only listed where it's first imported.
+    pub fn resolve(&self, path: &Path) -> Result<Vec<Dependency>, String> {
+        let data = self
+            .files
+            .read(path)
+            .ok_or_else(|| format!("Failed to read {}", path.display()))?;
+        self.dependencies(&data, &mut HashSet::new())
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn dependencies(
+        &self,
+        data: &[u8],
+        seen: &mut HashSet<String>,
+    ) -> Result<Vec<Dependency>, String> {
+        let mut dependencies = Vec::new();
+        for import in pe::parse_imports(data)? {
+            let name = import.dll.to_lowercase();
+            // API sets are mapped to system DLLs by the loader, they aren't files
+            if name.starts_with("api-ms-") || name.starts_with("ext-ms-") || !seen.insert(name) {
+                continue;
+            }
+            let resolution = self.find(&import.dll);
+            // Windows' own DLLs are assumed to have what they need
+            let imports = match &resolution {
+                Resolution::Found(path)
+                    if !self.system_dirs.iter().any(|d| path.starts_with(d)) =>
+                {
+                    self.files
+                        .read(path)
+                        .and_then(|data| self.dependencies(&data, seen).ok())
+                        .unwrap_or_default()
+                }
+                _ => Vec::new(),
+            };
+            dependencies.push(Dependency {
+                name: import.dll,
+                delayed: import.delayed,
+                resolution,
+                dependencies: imports,
+            });
+        }
+        Ok(dependencies)
+    }
+
+    fn find(&self, name: &str) -> Resolution {
+        let found = self
+            .dirs
+            .iter()
+            .chain(&self.system_dirs)
+            .map(|dir| dir.join(name))
+            .find(|path| self.files.is_file(path));
+        if let Some(path) = found {
+            return Resolution::Found(path);
+        }
+        match self.unsearched_dir.as_ref().map(|dir| dir.join(name)) {
+            Some(path) if self.files.is_file(&path) => Resolution::NotSearched(path),
+            _ => Resolution::Missing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::fixtures::dll_with_imports;
+    use std::collections::HashMap;
+
+    /// Files by path, ignoring case like Windows.
+    #[derive(Default)]
+    struct FakeFileSystem(HashMap<String, Vec<u8>>);
+
+    impl FakeFileSystem {
+        fn with(mut self, path: &str, data: Vec<u8>) -> Self {
+            self.0.insert(path.to_lowercase(), data);
+            self
+        }
+    }
+
+    impl FileSystem for FakeFileSystem {
+        fn is_file(&self, path: &Path) -> bool {
+            self.0.contains_key(&path.to_string_lossy().to_lowercase())
+        }
+
+        fn read(&self, path: &Path) -> Option<Vec<u8>> {
+            self.0.get(&path.to_string_lossy().to_lowercase()).cloned()
+        }
+    }
+
+    fn files() -> FakeFileSystem {
+        FakeFileSystem::default()
+            .with(
+                "C:/Mods/plugin.dll",
+                dll_with_imports(
+                    &[
+                        "KERNEL32.dll",
+                        "api-ms-win-crt-runtime-l1-1-0.dll",
+                        "helper.dll",
+                        "UnityPlayer.dll",
+                        "VCRUNTIME140D.dll",
+                    ],
+                    &["d3dcompiler_47.dll", "optional.dll"],
+                ),
+            )
+            .with(
+                "C:/Mods/helper.dll",
+                dll_with_imports(&["MSVCP140.dll", "libfoo.dll"], &[]),
+            )
+            .with(
+                "C:/Game/UnityPlayer.dll",
+                dll_with_imports(&["nothere.dll"], &[]),
+            )
+            .with("C:/Windows/System32/kernel32.dll", b"MZ".to_vec())
+            .with("C:/Windows/System32/msvcp140.dll", b"MZ".to_vec())
+            .with("C:/Windows/System32/d3dcompiler_47.dll", b"MZ".to_vec())
+    }
+
+    fn system() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("C:/Windows/System32"),
+            PathBuf::from("C:/Windows"),
+        ]
+    }
+
+    fn dependency(name: &str, resolution: Resolution, dependencies: Vec<Dependency>) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            delayed: false,
+            resolution,
+            dependencies,
+        }
+    }
+
+    #[test]
+    fn finds_missing_dependencies() {
+        let files = files();
+        let load = LoadOptions {
+            search_path: SearchPath::DllLoadDir,
+            ..Default::default()
+        };
+        let dll = Path::new("C:/Mods/plugin.dll");
+        let resolver = Resolver::new(&files, dll, &load, Some(Path::new("C:/Game")), system());
+        let tree = resolver.resolve(dll).unwrap();
+
+        let names: Vec<_> = tree.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "KERNEL32.dll",
+                "helper.dll",
+                "UnityPlayer.dll",
+                "VCRUNTIME140D.dll",
+                "d3dcompiler_47.dll",
+                "optional.dll"
+            ]
+        );
+        assert_eq!(
+            tree[0].resolution,
+            Resolution::Found(PathBuf::from("C:/Windows/System32/KERNEL32.dll"))
+        );
+        // The game's own DLLs are checked too
+        assert!(tree[2].is_broken());
+
+        let mut optional = dependency("optional.dll", Resolution::Missing, Vec::new());
+        optional.delayed = true;
+        assert_eq!(
+            broken_only(tree),
+            [
+                dependency(
+                    "helper.dll",
+                    Resolution::Found(PathBuf::from("C:/Mods/helper.dll")),
+                    vec![dependency("libfoo.dll", Resolution::Missing, Vec::new())]
+                ),
+                dependency(
+                    "UnityPlayer.dll",
+                    Resolution::Found(PathBuf::from("C:/Game/UnityPlayer.dll")),
+                    vec![dependency("nothere.dll", Resolution::Missing, Vec::new())]
+                ),
+                dependency("VCRUNTIME140D.dll", Resolution::Missing, Vec::new()),
+                optional,
+            ]
+        );
+    }
+
+    #[test]
+    fn orders_dlls_after_those_they_import() {
+        let files = FakeFileSystem::default()
+            .with(
+                "C:/Mods/a.dll",
+                dll_with_imports(&["KERNEL32.dll", "C.DLL"], &[]),
+            )
+            .with("C:/Mods/b.dll", dll_with_imports(&["KERNEL32.dll"], &[]))
+            .with("C:/Mods/c.dll", dll_with_imports(&["b.dll"], &["a.dll"]))
+            .with("C:/Mods/d.dll", dll_with_imports(&["e.dll"], &[]))
+            .with("C:/Mods/e.dll", dll_with_imports(&["d.dll"], &[]));
+        let paths = [
+            "C:/Mods/a.dll",
+            "C:/Mods/b.dll",
+            "C:/Mods/c.dll",
+            "C:/Mods/missing.dll",
+        ];
+        let dlls: Vec<_> = paths.iter().map(Path::new).collect();
+        assert_eq!(import_order(&files, &dlls), Ok(vec![1, 2, 0, 3]));
+
+        let dlls: Vec<_> = ["C:/Mods/b.dll", "C:/Mods/d.dll", "C:/Mods/e.dll"]
+            .iter()
+            .map(Path::new)
+            .collect();
+        let cycle = import_order(&files, &dlls).unwrap_err();
+        assert_eq!(
+            cycle.to_string(),
+            "The DLLs import each other: d.dll → e.dll → d.dll"
+        );
+    }
+
+    #[test]
+    fn points_out_dlls_next_to_the_dll_that_are_not_searched() {
+        let files = files();
+        let dll = Path::new("C:/Mods/plugin.dll");
+        let resolver = Resolver::new(&files, dll, &LoadOptions::default(), None, system());
+        let tree = broken_only(resolver.resolve(dll).unwrap());
+
+        let helper = &tree[0];
+        assert_eq!(
+            helper.resolution,
+            Resolution::NotSearched(PathBuf::from("C:/Mods/helper.dll"))
+        );
+        assert_eq!(
+            helper.to_string(),
+            "helper.dll: only in C:/Mods, which is not searched, change where dependencies are searched in the DLL's options"
+        );
+        // Without the game folder, its DLLs can't be found either
+        assert_eq!(tree[1].resolution, Resolution::Missing);
+
+        // Added directories are searched
+        let load = LoadOptions {
+            dll_directories: vec![PathBuf::from("C:/Mods")],
+            ..Default::default()
+        };
+        let resolver = Resolver::new(&files, dll, &load, None, system());
+        let tree = resolver.resolve(dll).unwrap();
+        assert_eq!(
+            tree[1].resolution,
+            Resolution::Found(PathBuf::from("C:/Mods/helper.dll"))
+        );
+    }
+}
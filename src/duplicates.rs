@@ -0,0 +1,193 @@
+//! Finds DLLs that are already in the game, so they aren't injected twice.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backend::LoadedModule;
+use crate::github;
+
+/// File names Hachimi can be installed as in the game folder, where the game
+/// loads it by itself.
+const HACHIMI_PROXY_NAMES: &[&str] = &[
+    "cri_mana_vpx.dll",
+    "version.dll",
+    "winhttp.dll",
+    "dxgi.dll",
+    "opengl32.dll",
+];
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Duplicate {
+    /// The same file, or an identical copy of it, is loaded.
+    Loaded { module: LoadedModule },
+    /// Hachimi is installed in the game folder under this path.
+    HachimiProxy { path: PathBuf },
+    /// A different DLL with the same name is loaded.
+    NameClash { module: LoadedModule },
+}
+
+impl Duplicate {
+    /// Whether injecting should be skipped, a name clash only warrants a warning.
+    pub fn skips(&self) -> bool {
+        !matches!(self, Duplicate::NameClash { .. })
+    }
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Duplicate::Loaded { module } => write!(
+                f,
+                "already loaded from {} at 0x{:016X}",
+                module.path.display(),
+                module.base
+            ),
+            Duplicate::HachimiProxy { path } => {
+                write!(f, "Hachimi is already installed as {}", path.display())
+            }
+            Duplicate::NameClash { module } => write!(
+                f,
+                "a different DLL with the same name is loaded from {}",
+                module.path.display()
+            ),
+        }
+    }
+}
+
+/// Checks whether `dll` is already among the modules of a process, or, for
+/// Hachimi, installed in `game_dir`.
+pub fn find(modules: &[LoadedModule], game_dir: Option<&Path>, dll: &Path) -> Option<Duplicate> {
+    let name = file_name(dll)?;
+    let mut hash = None;
+    let mut clash = None;
+    for module in modules
+        .iter()
+        .filter(|m| file_name(&m.path).as_ref() == Some(&name))
+    {
+        // Only hashed when there is a module with the same name
+        let hash = hash.get_or_insert_with(|| hashed(dll));
+        if lower(&module.path) == lower(dll) || (hash.is_some() && *hash == hashed(&module.path)) {
+            return Some(Duplicate::Loaded {
+                module: module.clone(),
+            });
+        }
+        clash.get_or_insert_with(|| module.clone());
+    }
+
+    if name == "hachimi.dll"
+        && let Some(path) = game_dir.and_then(find_hachimi_proxy)
+    {
+        return Some(Duplicate::HachimiProxy { path });
+    }
+    clash.map(|module| Duplicate::NameClash { module })
+}
+
+/// Looks for a copy of Hachimi under one of its proxy names in `dir`.
+fn find_hachimi_proxy(dir: &Path) -> Option<PathBuf> {
+    HACHIMI_PROXY_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| fs::read(path).is_ok_and(|data| mentions_hachimi(&data)))
+}
+
+/// The game's own DLLs never mention Hachimi, Hachimi does in its strings.
+fn mentions_hachimi(data: &[u8]) -> bool {
+    data.windows(7).any(|w| w.eq_ignore_ascii_case(b"hachimi"))
+}
+
+fn hashed(path: &Path) -> Option<String> {
+    github::calculate_sha256(path).ok()
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    path.file_name().map(|n| n.to_string_lossy().to_lowercase())
+}
+
+fn lower(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(path: &Path) -> LoadedModule {
+        LoadedModule {
+            base: 0x7ffb_0000_0000,
+            size: 0x1000,
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tamamo-x-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_loaded_copies_by_path_and_hash() {
+        let dir = temp_dir("duplicates");
+        let (dll, copy, other) = (
+            dir.join("a.dll"),
+            dir.join("copy/a.dll"),
+            dir.join("other/a.dll"),
+        );
+        fs::create_dir_all(copy.parent().unwrap()).unwrap();
+        fs::create_dir_all(other.parent().unwrap()).unwrap();
+        fs::write(&dll, b"MZ one").unwrap();
+        fs::write(&copy, b"MZ one").unwrap();
+        fs::write(&other, b"MZ two").unwrap();
+
+        let found = find(&[module(&dll)], None, &dll);
+        assert_eq!(
+            found,
+            Some(Duplicate::Loaded {
+                module: module(&dll)
+            })
+        );
+
+        let found = find(&[module(&other), module(&copy)], None, &dll);
+        assert_eq!(
+            found,
+            Some(Duplicate::Loaded {
+                module: module(&copy)
+            })
+        );
+
+        let found = find(&[module(&other)], None, &dll).unwrap();
+        assert_eq!(
+            found,
+            Duplicate::NameClash {
+                module: module(&other)
+            }
+        );
+        assert!(!found.skips());
+
+        assert_eq!(find(&[module(&dir.join("b.dll"))], None, &dll), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_hachimi_installed_as_a_proxy() {
+        let dir = temp_dir("proxy");
+        let hachimi = dir.join("mods/hachimi.dll");
+        fs::write(dir.join("cri_mana_vpx.dll"), b"MZ game codec").unwrap();
+        assert_eq!(find(&[], Some(&dir), &hachimi), None);
+
+        fs::write(dir.join("version.dll"), b"MZ ... Hachimi v0.14 ...").unwrap();
+        assert_eq!(
+            find(&[], Some(&dir), &hachimi),
+            Some(Duplicate::HachimiProxy {
+                path: dir.join("version.dll")
+            })
+        );
+        // Only Hachimi itself is checked against the game folder
+        assert_eq!(find(&[], Some(&dir), &dir.join("mods/other.dll")), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,476 @@
+// Most errors are only constructed by the Win32 backend.
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+
+/// A status code returned by Windows, either an `NTSTATUS` from the `Zw*`
+/// functions or a Win32 error from `GetLastError`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorCode {
+    NtStatus(u32),
+    Win32(u32),
+}
+
+/// What `LoadLibrary` fails with when the DLL or one of its dependencies is
+/// missing.
+pub const ERROR_MOD_NOT_FOUND: u32 = 126;
+/// What `GetProcAddress` fails with when the DLL doesn't export the function.
+#[cfg(windows)]
+pub const ERROR_PROC_NOT_FOUND: u32 = 127;
+
+// (code, symbolic name, explanation)
+const NTSTATUS_TABLE: &[(u32, &str, &str)] = &[
+    (
+        0x8000_000D,
+        "STATUS_PARTIAL_COPY",
+        "Only part of the memory could be accessed",
+    ),
+    (
+        0xC000_0005,
+        "STATUS_ACCESS_VIOLATION",
+        "An invalid memory address was accessed",
+    ),
+    (
+        0xC000_0008,
+        "STATUS_INVALID_HANDLE",
+        "The process handle is not valid anymore",
+    ),
+    (
+        0xC000_000D,
+        "STATUS_INVALID_PARAMETER",
+        "An invalid parameter was passed",
+    ),
+    (0xC000_0017, "STATUS_NO_MEMORY", "The game is out of memory"),
+    (
+        0xC000_0018,
+        "STATUS_CONFLICTING_ADDRESSES",
+        "The memory range is already in use",
+    ),
+    (
+        0xC000_0022,
+        "STATUS_ACCESS_DENIED",
+        "Access was denied, try running Tamamo-X as administrator",
+    ),
+    (
+        0xC000_004B,
+        "STATUS_THREAD_IS_TERMINATING",
+        "The thread is exiting",
+    ),
+    (
+        0xC000_007B,
+        "STATUS_INVALID_IMAGE_FORMAT",
+        "The DLL is not a valid image for this process",
+    ),
+    (
+        0xC000_010A,
+        "STATUS_PROCESS_IS_TERMINATING",
+        "The game is exiting",
+    ),
+    (
+        0xC000_012D,
+        "STATUS_COMMITMENT_LIMIT",
+        "The system is out of virtual memory",
+    ),
+    (
+        0xC000_0135,
+        "STATUS_DLL_NOT_FOUND",
+        "A DLL could not be found",
+    ),
+    (
+        0xC000_0139,
+        "STATUS_ENTRYPOINT_NOT_FOUND",
+        "A function a DLL imports could not be found",
+    ),
+    (
+        0xC000_0142,
+        "STATUS_DLL_INIT_FAILED",
+        "A DLL failed to initialize",
+    ),
+    (
+        0xC000_0712,
+        "STATUS_PROCESS_IS_PROTECTED",
+        "The game is a protected process and can't be injected into",
+    ),
+];
+
+const WIN32_TABLE: &[(u32, &str, &str)] = &[
+    (2, "ERROR_FILE_NOT_FOUND", "The file could not be found"),
+    (3, "ERROR_PATH_NOT_FOUND", "The path could not be found"),
+    (
+        5,
+        "ERROR_ACCESS_DENIED",
+        "Access was denied, try running Tamamo-X as administrator",
+    ),
+    (6, "ERROR_INVALID_HANDLE", "The handle is not valid anymore"),
+    (
+        8,
+        "ERROR_NOT_ENOUGH_MEMORY",
+        "Not enough memory is available",
+    ),
+    (
+        87,
+        "ERROR_INVALID_PARAMETER",
+        "An invalid parameter was passed",
+    ),
+    (
+        ERROR_MOD_NOT_FOUND,
+        "ERROR_MOD_NOT_FOUND",
+        "The DLL or one of its dependencies could not be found",
+    ),
+    (
+        127,
+        "ERROR_PROC_NOT_FOUND",
+        "A function could not be found in a DLL",
+    ),
+    (
+        193,
+        "ERROR_BAD_EXE_FORMAT",
+        "The DLL is not a valid image for this process",
+    ),
+    (258, "WAIT_TIMEOUT", "The wait timed out"),
+    (
+        299,
+        "ERROR_PARTIAL_COPY",
+        "Only part of the memory could be accessed",
+    ),
+    (
+        998,
+        "ERROR_NOACCESS",
+        "An invalid memory address was accessed",
+    ),
+    (1114, "ERROR_DLL_INIT_FAILED", "A DLL failed to initialize"),
+];
+
+impl ErrorCode {
+    fn lookup(&self) -> Option<&'static (u32, &'static str, &'static str)> {
+        let (table, code) = match *self {
+            ErrorCode::NtStatus(code) => (NTSTATUS_TABLE, code),
+            ErrorCode::Win32(code) => (WIN32_TABLE, code),
+        };
+        table.iter().find(|(c, _, _)| *c == code)
+    }
+
+    pub fn code(&self) -> u32 {
+        match *self {
+            ErrorCode::NtStatus(code) | ErrorCode::Win32(code) => code,
+        }
+    }
+
+    /// The symbolic name, e.g. `STATUS_ACCESS_DENIED`, if the code is known.
+    pub fn name(&self) -> Option<&'static str> {
+        self.lookup().map(|(_, name, _)| *name)
+    }
+
+    #[cfg(any(windows, test))]
+    pub fn is_access_denied(&self) -> bool {
+        matches!(
+            self.name(),
+            Some("STATUS_ACCESS_DENIED" | "STATUS_PROCESS_IS_PROTECTED" | "ERROR_ACCESS_DENIED")
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::NtStatus(code) => write!(f, "0x{:08X}", code)?,
+            ErrorCode::Win32(code) => write!(f, "{}", code)?,
+        }
+        if let Some((_, name, explanation)) = self.lookup() {
+            write!(f, " {}: {}", name, explanation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a DLL could not be injected.
+#[derive(Clone, PartialEq, Debug)]
+// Most variants are only constructed by the Win32 backend
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum InjectError {
+    /// The DLL failed the checks done before touching the process.
+    InvalidDll(String),
+    ModuleResolution {
+        name: String,
+        code: ErrorCode,
+    },
+    Allocation(ErrorCode),
+    Write(ErrorCode),
+    /// The code written to the game could not be made executable, which games
+    /// blocking dynamic code (e.g. with ACG) refuse.
+    NotExecutable(ErrorCode),
+    ThreadCreation(ErrorCode),
+    AccessDenied(ErrorCode),
+    WaitTimeout {
+        timeout_ms: u32,
+    },
+    Wait(ErrorCode),
+    /// The injection method can't run code in a 32-bit game from 64-bit
+    /// Tamamo-X.
+    Wow64Unsupported,
+    /// The injection method only queues calls, so it can't call an export and
+    /// get what it returned.
+    QueuedExportCall,
+    /// The module the DLL waits for was not loaded in time.
+    ModuleWaitTimeout {
+        name: String,
+        timeout_ms: u32,
+    },
+    /// `AddDllDirectory` failed in the game for one of the DLL's directories.
+    AddDllDirectoryFailed {
+        path: PathBuf,
+        code: ErrorCode,
+    },
+    /// `LoadLibraryExW` failed in the game because a DLL the injected one
+    /// imports could not be found.
+    DependencyNotFound,
+    /// `LoadLibraryExW` ran in the game but returned `NULL`.
+    LoadLibraryFailed(ErrorCode),
+    /// `LoadLibraryExW` returned, but the DLL is not in the module list.
+    NotLoaded,
+    /// None of the game's threads ran the `LoadLibraryW` queued on them.
+    QueuedCallNotRun {
+        timeout_ms: u32,
+    },
+    /// The DLL to eject is not in the module list.
+    NotInjected,
+    ExportNotFound {
+        name: String,
+    },
+    /// `FreeLibrary` ran in the game but returned `FALSE`.
+    FreeLibraryFailed,
+    /// `FreeLibrary` returned, but the DLL is still in the module list.
+    StillLoaded,
+    Unsupported,
+}
+
+impl InjectError {
+    /// Wraps `code` with `kind`, unless it means access was denied.
+    #[cfg(any(windows, test))]
+    pub fn from_code(code: ErrorCode, kind: fn(ErrorCode) -> InjectError) -> Self {
+        if code.is_access_denied() {
+            InjectError::AccessDenied(code)
+        } else {
+            kind(code)
+        }
+    }
+
+    /// Whether the error happened before anything ran in the game, so another
+    /// injection method can safely be tried.
+    pub fn allows_fallback(&self) -> bool {
+        matches!(
+            self,
+            InjectError::ModuleResolution { .. }
+                | InjectError::Allocation(_)
+                | InjectError::Write(_)
+                | InjectError::NotExecutable(_)
+                | InjectError::ThreadCreation(_)
+                | InjectError::AccessDenied(_)
+                | InjectError::Wow64Unsupported
+                | InjectError::QueuedExportCall
+        )
+    }
+
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            InjectError::ModuleResolution { code, .. }
+            | InjectError::Allocation(code)
+            | InjectError::Write(code)
+            | InjectError::NotExecutable(code)
+            | InjectError::ThreadCreation(code)
+            | InjectError::AccessDenied(code)
+            | InjectError::Wait(code)
+            | InjectError::AddDllDirectoryFailed { code, .. }
+            | InjectError::LoadLibraryFailed(code) => Some(*code),
+            InjectError::DependencyNotFound => Some(ErrorCode::Win32(ERROR_MOD_NOT_FOUND)),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            InjectError::InvalidDll(_) => "invalid_dll",
+            InjectError::ModuleResolution { .. } => "module_resolution",
+            InjectError::Allocation(_) => "allocation",
+            InjectError::Write(_) => "write",
+            InjectError::NotExecutable(_) => "not_executable",
+            InjectError::ThreadCreation(_) => "thread_creation",
+            InjectError::AccessDenied(_) => "access_denied",
+            InjectError::WaitTimeout { .. } => "wait_timeout",
+            InjectError::Wait(_) => "wait",
+            InjectError::Wow64Unsupported => "wow64_unsupported",
+            InjectError::QueuedExportCall => "queued_export_call",
+            InjectError::ModuleWaitTimeout { .. } => "module_wait_timeout",
+            InjectError::AddDllDirectoryFailed { .. } => "add_dll_directory_failed",
+            InjectError::DependencyNotFound => "dependency_not_found",
+            InjectError::LoadLibraryFailed(_) => "load_library_failed",
+            InjectError::NotLoaded => "not_loaded",
+            InjectError::QueuedCallNotRun { .. } => "queued_call_not_run",
+            InjectError::NotInjected => "not_injected",
+            InjectError::ExportNotFound { .. } => "export_not_found",
+            InjectError::FreeLibraryFailed => "free_library_failed",
+            InjectError::StillLoaded => "still_loaded",
+            InjectError::Unsupported => "unsupported",
+        }
+    }
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::InvalidDll(reason) => write!(f, "{}", reason),
+            InjectError::ModuleResolution { name, code } => {
+                write!(f, "Failed to resolve {} ({})", name, code)
+            }
+            InjectError::Allocation(code) => {
+                write!(f, "Failed to allocate memory in the game ({})", code)
+            }
+            InjectError::Write(code) => write!(f, "Failed to write memory in the game ({})", code),
+            InjectError::NotExecutable(code) => {
+                write!(
+                    f,
+                    "Failed to make the code written to the game executable ({})",
+                    code
+                )
+            }
+            InjectError::ThreadCreation(code) => {
+                write!(f, "Failed to create a thread in the game ({})", code)
+            }
+            InjectError::AccessDenied(code) => write!(f, "Access denied ({})", code),
+            InjectError::WaitTimeout { timeout_ms } => write!(
+                f,
+                "The thread running in the game did not finish within {} ms",
+                timeout_ms
+            ),
+            InjectError::Wait(code) => {
+                write!(
+                    f,
+                    "Failed to wait for the thread running in the game ({})",
+                    code
+                )
+            }
+            InjectError::Wow64Unsupported => {
+                write!(f, "This injection method doesn't work with 32-bit games")
+            }
+            InjectError::QueuedExportCall => {
+                write!(f, "This injection method can't call exports of the DLL")
+            }
+            InjectError::ModuleWaitTimeout { name, timeout_ms } => write!(
+                f,
+                "{} was not loaded in the game within {} ms",
+                name, timeout_ms
+            ),
+            InjectError::AddDllDirectoryFailed { path, code } => write!(
+                f,
+                "Failed to add {} to the DLL directories of the game ({})",
+                path.display(),
+                code
+            ),
+            InjectError::DependencyNotFound => write!(
+                f,
+                "A DLL it depends on could not be found, check where its dependencies are searched for in its options"
+            ),
+            InjectError::LoadLibraryFailed(code) => {
+                write!(f, "LoadLibraryExW failed in the game ({})", code)
+            }
+            InjectError::NotLoaded => {
+                write!(f, "The DLL is not loaded in the game after injecting")
+            }
+            InjectError::QueuedCallNotRun { timeout_ms } => write!(
+                f,
+                "No thread of the game loaded the DLL within {} ms, none of them may wait alertably",
+                timeout_ms
+            ),
+            InjectError::NotInjected => write!(f, "The DLL is not loaded in the game"),
+            InjectError::ExportNotFound { name } => {
+                write!(f, "The DLL does not export a function called {}", name)
+            }
+            InjectError::FreeLibraryFailed => write!(f, "FreeLibrary failed in the game"),
+            InjectError::StillLoaded => write!(
+                f,
+                "FreeLibrary ran, but the DLL is still loaded because something else holds a reference to it"
+            ),
+            InjectError::Unsupported => write!(f, "Injection is only supported on Windows"),
+        }
+    }
+}
+
+impl Serialize for InjectError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("InjectError", 4)?;
+        s.serialize_field("kind", self.kind())?;
+        s.serialize_field("code", &self.code().map(|c| c.code()))?;
+        s.serialize_field("code_name", &self.code().and_then(|c| c.name()))?;
+        s.serialize_field("message", &self.to_string())?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_known_codes() {
+        let code = ErrorCode::NtStatus(0xC000_0022);
+        assert_eq!(code.name(), Some("STATUS_ACCESS_DENIED"));
+        assert!(code.is_access_denied());
+        assert_eq!(
+            code.to_string(),
+            "0xC0000022 STATUS_ACCESS_DENIED: Access was denied, try running Tamamo-X as administrator"
+        );
+
+        let code = ErrorCode::Win32(126);
+        assert_eq!(code.name(), Some("ERROR_MOD_NOT_FOUND"));
+        assert!(!code.is_access_denied());
+    }
+
+    #[test]
+    fn unknown_codes_are_printed_raw() {
+        assert_eq!(ErrorCode::NtStatus(0xC0DE_0001).to_string(), "0xC0DE0001");
+        assert_eq!(ErrorCode::Win32(12345).to_string(), "12345");
+        assert_eq!(ErrorCode::Win32(12345).name(), None);
+    }
+
+    #[test]
+    fn access_denied_takes_precedence() {
+        assert_eq!(
+            InjectError::from_code(ErrorCode::NtStatus(0xC000_0712), InjectError::Allocation),
+            InjectError::AccessDenied(ErrorCode::NtStatus(0xC000_0712))
+        );
+        assert_eq!(
+            InjectError::from_code(ErrorCode::NtStatus(0xC000_0017), InjectError::Allocation),
+            InjectError::Allocation(ErrorCode::NtStatus(0xC000_0017))
+        );
+    }
+
+    #[test]
+    fn tables_are_consistent() {
+        for table in [NTSTATUS_TABLE, WIN32_TABLE] {
+            for (i, (code, name, _)) in table.iter().enumerate() {
+                assert!(
+                    table[i + 1..]
+                        .iter()
+                        .all(|(c, n, _)| c != code && n != name),
+                    "{} is listed twice",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn serializes_with_code_name() {
+        let json =
+            serde_json::to_value(InjectError::Write(ErrorCode::NtStatus(0x8000_000D))).unwrap();
+        assert_eq!(json["kind"], "write");
+        assert_eq!(json["code"], 0x8000_000Du32);
+        assert_eq!(json["code_name"], "STATUS_PARTIAL_COPY");
+
+        let json = serde_json::to_value(InjectError::WaitTimeout { timeout_ms: 5000 }).unwrap();
+        assert_eq!(json["kind"], "wait_timeout");
+        assert!(json["code"].is_null());
+    }
+}
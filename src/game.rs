@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+use crate::HachimiVersion;
+use crate::matcher::ProcessMatcher;
+
+pub const DEFAULT_GAME_ID: &str = "global";
+
+/// Definitions shipped with Tamamo-X, user definitions with the same `id`
+/// replace them.
+const BUILTIN_DEFINITIONS: &[(&str, &str)] = &[
+    ("global.toml", include_str!("../games/global.toml")),
+    ("japanese.toml", include_str!("../games/japanese.toml")),
+];
+
+/// Describes a game Tamamo-X can inject into: how to find its process, which
+/// DLLs it always needs and when it's ready to be injected.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GameDefinition {
+    pub id: String,
+    pub name: String,
+    /// Executable names of the game, ignoring case.
+    #[serde(default)]
+    pub process_names: Vec<String>,
+    /// Finer rule for finding the game's processes, replaces `process_names`.
+    #[serde(default)]
+    pub matcher: Option<ProcessMatcher>,
+    /// Only count windows whose title contains this text.
+    #[serde(default)]
+    pub window_title: Option<String>,
+    /// Only count windows with exactly this window class.
+    #[serde(default)]
+    pub window_class: Option<String>,
+    /// DLLs injected before the profile's own DLLs for every profile that
+    /// targets this game.
+    #[serde(default)]
+    pub default_dlls: Vec<DllSource>,
+    #[serde(default = "default_readiness")]
+    pub readiness: Vec<Readiness>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DllSource {
+    /// The latest release of Hachimi, downloaded before watching starts.
+    Hachimi(HachimiVersion),
+    Path(PathBuf),
+}
+
+/// A condition that has to be met, in order, before injecting.
+///
+/// Conditions with a `timeout_ms` give up on the instance when it passes, it
+/// is then not injected into. `InputIdle` is the exception.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Readiness {
+    /// The process has a visible window (matching `window_title`/`window_class`).
+    Window {
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    /// A module with this file name is loaded, e.g. `GameAssembly.dll`.
+    ModuleLoaded {
+        name: String,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    /// The process is waiting for user input, see `WaitForInputIdle`. Injection
+    /// goes ahead anyway once `timeout_ms` passes, `INPUT_IDLE_TIMEOUT_MS` by
+    /// default.
+    InputIdle {
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+    Delay {
+        ms: u64,
+    },
+    /// A file exists, relative paths are resolved against the game's folder.
+    FileExists {
+        path: PathBuf,
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+    },
+}
+
+/// How long `Readiness::InputIdle` waits for a busy game by default.
+pub const INPUT_IDLE_TIMEOUT_MS: u64 = 10000;
+
+fn default_readiness() -> Vec<Readiness> {
+    vec![
+        Readiness::Window { timeout_ms: None },
+        Readiness::InputIdle { timeout_ms: None },
+        Readiness::Delay { ms: 1000 },
+    ]
+}
+
+impl GameDefinition {
+    pub fn label(&self) -> String {
+        if self.process_names.is_empty() {
+            return self.name.clone();
+        }
+        format!("{} ({})", self.name, self.process_names.join(", "))
+    }
+
+    pub fn process_matcher(&self) -> ProcessMatcher {
+        match &self.matcher {
+            Some(matcher) => matcher.clone(),
+            None => ProcessMatcher::names(&self.process_names),
+        }
+    }
+
+    fn parse(file_name: &str, contents: &str) -> Result<Self, String> {
+        let definition: GameDefinition = if file_name.ends_with(".json") {
+            serde_json::from_str(contents).map_err(|e| e.to_string())?
+        } else {
+            toml::from_str(contents).map_err(|e| e.to_string())?
+        };
+        if definition.process_names.is_empty() && definition.matcher.is_none() {
+            return Err("no process names or matcher given".to_string());
+        }
+        Ok(definition)
+    }
+}
+
+pub fn find<'a>(games: &'a [GameDefinition], id: &str) -> Option<&'a GameDefinition> {
+    games.iter().find(|g| g.id == id)
+}
+
+/// Loads the built-in definitions plus every `.toml`/`.json` file in `dir`.
+///
+/// Files that fail to parse are skipped, their errors are returned alongside
+/// the definitions so they can be shown to the user.
+pub fn load_definitions(dir: &Path) -> (Vec<GameDefinition>, Vec<String>) {
+    let mut games = Vec::new();
+    let mut errors = Vec::new();
+
+    for (file_name, contents) in BUILTIN_DEFINITIONS {
+        games.push(
+            GameDefinition::parse(file_name, contents)
+                .expect("built-in game definitions should be valid"),
+        );
+    }
+
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.extension()
+                    .is_some_and(|ext| ext == "toml" || ext == "json")
+            })
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+
+    for path in paths {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let result = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| GameDefinition::parse(&file_name, &contents));
+        match result {
+            Ok(definition) => {
+                info!(
+                    "Loaded game definition '{}' from {}",
+                    definition.id,
+                    path.display()
+                );
+                match games.iter_mut().find(|g| g.id == definition.id) {
+                    Some(existing) => *existing = definition,
+                    None => games.push(definition),
+                }
+            }
+            Err(e) => {
+                error!("Failed to load game definition {}: {}", path.display(), e);
+                errors.push(format!("{}: {}", file_name, e));
+            }
+        }
+    }
+
+    (games, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_readiness_conditions() {
+        let definition = GameDefinition::parse(
+            "test.toml",
+            r#"
+            id = "test"
+            name = "Test"
+            process_names = ["game.exe"]
+
+            [[readiness]]
+            type = "window"
+
+            [[readiness]]
+            type = "module_loaded"
+            name = "GameAssembly.dll"
+            timeout_ms = 30000
+
+            [[readiness]]
+            type = "file_exists"
+            path = "hachimi/config.json"
+
+            [[readiness]]
+            type = "input_idle"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            definition.readiness,
+            [
+                Readiness::Window { timeout_ms: None },
+                Readiness::ModuleLoaded {
+                    name: "GameAssembly.dll".to_string(),
+                    timeout_ms: Some(30000),
+                },
+                Readiness::FileExists {
+                    path: PathBuf::from("hachimi/config.json"),
+                    timeout_ms: None,
+                },
+                Readiness::InputIdle { timeout_ms: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn builtin_definitions_are_valid() {
+        for (file_name, contents) in BUILTIN_DEFINITIONS {
+            let definition = GameDefinition::parse(file_name, contents).unwrap();
+            assert!(!definition.readiness.is_empty());
+        }
+    }
+}
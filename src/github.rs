@@ -1,13 +1,13 @@
 use reqwest::blocking as reqwest;
 use std::fs::{create_dir, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use sha2::{Sha256, Digest};
 use std::io::{self, Read};
 use tracing::info;
 
 use crate::HachimiVersion;
 
-fn calculate_sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
+pub fn calculate_sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
     let mut file = File::open(path)?;
     let mut hasher = Sha256::new();
     let mut buffer = [0; 1024];
@@ -21,41 +21,38 @@ fn calculate_sha256<P: AsRef<Path>>(path: P) -> io::Result<String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
-pub fn hachimi_download_latest(version: HachimiVersion) -> Result<String, String> {
+/// Where the latest downloaded DLL of `version` is stored, relative to the
+/// working directory.
+pub fn hachimi_dll_path(version: HachimiVersion) -> &'static str {
+    match version {
+        HachimiVersion::Original => "external/hachimi/hachimi.dll",
+        HachimiVersion::Edge => "external/hachimi-edge/hachimi.dll",
+    }
+}
+
+pub fn hachimi_download_latest(version: HachimiVersion) -> Result<PathBuf, String> {
     let mut should_check_sha256 = true;
     if !Path::new("external").exists() {
         let _ = create_dir("external");
         should_check_sha256 = false;
     }
 
-    let file_name;
-    match version {
-        HachimiVersion::Original => {
-            if !Path::new("external/hachimi").exists() {
-                let _ = create_dir("external/hachimi");
-                should_check_sha256 = false;
-            }
-            file_name = "external/hachimi/hachimi.dll";
-        }
-        HachimiVersion::Edge => {
-            if !Path::new("external/hachimi-edge").exists() {
-                let _ = create_dir("external/hachimi-edge");
-                should_check_sha256 = false;
-            }
-            file_name = "external/hachimi-edge/hachimi.dll";
-        }
+    let file_name = hachimi_dll_path(version);
+    let dir = Path::new(file_name).parent().unwrap();
+    if !dir.exists() {
+        let _ = create_dir(dir);
+        should_check_sha256 = false;
     }
 
     let client = reqwest::Client::new();
-    let api_url;
-    match version {
+    let api_url = match version {
         HachimiVersion::Original => {
-            api_url = "https://api.github.com/repos/Hachimi-Hachimi/Hachimi/releases/latest";
+            "https://api.github.com/repos/Hachimi-Hachimi/Hachimi/releases/latest"
         }
         HachimiVersion::Edge => {
-            api_url = "https://api.github.com/repos/kairusds/Hachimi-Edge/releases/latest";
+            "https://api.github.com/repos/kairusds/Hachimi-Edge/releases/latest"
         }
-    }
+    };
     
     let rsp = client
         .get(api_url)
@@ -74,15 +71,13 @@ pub fn hachimi_download_latest(version: HachimiVersion) -> Result<String, String
 
         if should_check_sha256 && Path::new(file_name).exists() {
             info!("Checking existing file SHA256...");
-            if let Some(digest) = asset["digest"].as_str() {
-                if let Some(sha256_remote) = digest.strip_prefix("sha256:") {
-                    if let Ok(existing_file_sha256) = calculate_sha256(file_name) {
-                        if existing_file_sha256 == sha256_remote {
-                            info!("Hachimi is already up-to-date.");
-                            return Ok(file_name.to_string());
-                        }
-                    }
-                }
+            if let Some(digest) = asset["digest"].as_str()
+                && let Some(sha256_remote) = digest.strip_prefix("sha256:")
+                && let Ok(existing_file_sha256) = calculate_sha256(file_name)
+                && existing_file_sha256 == sha256_remote
+            {
+                info!("Hachimi is already up-to-date.");
+                return Ok(PathBuf::from(file_name));
             }
             info!("Hachimi is outdated or hash check failed. Downloading latest version...");
         }
@@ -101,7 +96,7 @@ pub fn hachimi_download_latest(version: HachimiVersion) -> Result<String, String
         std::io::copy(&mut rsp, &mut out).map_err(|e| format!("Failed to write to file: {}", e))?;
 
         info!("Successfully downloaded latest Hachimi.");
-        return Ok(file_name.to_string());
+        return Ok(PathBuf::from(file_name));
     }
     
     Err("Failed to find hachimi.dll in the latest release.".to_string())
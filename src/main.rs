@@ -1,261 +1,863 @@
-mod win32;
+mod backend;
+mod cli;
+mod dependencies;
+mod duplicates;
+mod error;
+mod game;
 mod github;
+mod matcher;
+mod pe;
+mod profile;
+mod settings;
+mod watcher;
+#[cfg(any(windows, test))]
+mod wide;
+#[cfg(windows)]
+mod win32;
 
+use backend::{InjectionMethod, PlatformBackend, ProcessBackend, ProcessEntry, SearchPath};
+use clap::Parser;
+use cli::Cli;
+use dependencies::{Dependency, ImportCycle, RealFileSystem, Resolver};
 use eframe::egui;
+use error::InjectError;
+use game::GameDefinition;
+use profile::{ArgumentFormat, CrashAction, CustomDll, DllOrder, InitCall, Profile};
+use serde::{Deserialize, Serialize};
+use settings::{Settings, SettingsStore};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::thread;
-use tracing::warn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
 use tracing_subscriber::EnvFilter;
-use windows::Win32::System::Threading::WaitForSingleObject;
-
-#[derive(PartialEq, Clone, Copy)]
-enum GameVersion {
-    Global,
-    Japanese,
-}
+use watcher::{CrashReport, DllResult, WatcherEvent, WatcherHandle, WatcherState};
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum HachimiVersion {
     Original,
     Edge,
 }
 
+impl HachimiVersion {
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            HachimiVersion::Original => "Hachimi",
+            HachimiVersion::Edge => "Hachimi Edge",
+        }
+    }
+}
+
+/// How long settings have to stay unchanged before they're written to disk, so
+/// dragging the window around doesn't rewrite the file every frame.
+const SETTINGS_SAVE_DELAY: Duration = Duration::from_millis(500);
+
 struct TamamoApp {
-    hachimi_enabled: bool,
-    hachimi_edge_enabled: bool,
-    game_version: GameVersion,
-    custom_dlls: Vec<PathBuf>,
-    auto_restart: bool,
-    is_watching: bool,
+    settings: Settings,
+    settings_store: SettingsStore,
+    settings_dirty_since: Option<Instant>,
+    games: Vec<GameDefinition>,
+    profile_rename: Option<String>,
+    share_string: String,
     status: String,
-    state: Arc<Mutex<AppState>>,
+    watcher: Option<WatcherHandle>,
+    /// Name of the profile the watcher was started with.
+    watched_profile: String,
+    instances: Vec<GameInstance>,
+    /// Crashes since the watcher was started, newest last.
+    crashes: Vec<CrashReport>,
+    ejections_tx: mpsc::Sender<Ejection>,
+    ejections: mpsc::Receiver<Ejection>,
+    pending_start: Option<PendingStart>,
+    import_order: Option<ImportOrder>,
+}
+
+/// The order the enabled custom DLLs are injected in, kept until they change.
+struct ImportOrder {
+    dlls: Vec<PathBuf>,
+    order: Result<Vec<usize>, ImportCycle>,
+}
+
+/// Watching that waits for the user to confirm it, because some DLLs have
+/// dependencies that can't be found.
+struct PendingStart {
+    profile: Profile,
+    game: GameDefinition,
+    restart: bool,
+    /// Each DLL with what it's missing.
+    problems: Vec<(PathBuf, Vec<Dependency>)>,
+}
+
+/// A DLL ejected on a background thread, or not.
+struct Ejection {
+    pid: u32,
+    dll: PathBuf,
+    result: Result<(), InjectError>,
 }
 
-struct AppState {
-    is_watching: bool,
+/// A running game process the watcher is handling.
+struct GameInstance {
+    process: ProcessEntry,
     status: String,
-    should_stop: bool,
+    /// Results of the last injection, one per DLL.
+    dll_results: Vec<DllResult>,
+}
+
+impl GameInstance {
+    fn details(&self) -> String {
+        let p = &self.process;
+        let mut details = Vec::new();
+        if let Some(path) = &p.path {
+            details.push(path.display().to_string());
+        }
+        details.push(format!("Parent PID: {}", p.parent_pid));
+        if let Some(session) = p.session_id {
+            details.push(format!("Session: {}", session));
+        }
+        if let Some(start) = p.start_time
+            && let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH)
+        {
+            let minutes = now.as_secs().saturating_sub(start) / 60;
+            details.push(format!("Started {} min ago", minutes));
+        }
+        details.join("\n")
+    }
 }
 
 impl TamamoApp {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        settings_store: SettingsStore,
+        settings: Settings,
+        games: Vec<GameDefinition>,
+        notice: Option<String>,
+    ) -> Self {
         // Install image loaders for egui
         egui_extras::install_image_loaders(&cc.egui_ctx);
+        let (ejections_tx, ejections) = mpsc::channel();
 
         Self {
-            hachimi_enabled: true,
-            hachimi_edge_enabled: false,
-            game_version: GameVersion::Global,
-            custom_dlls: Vec::new(),
-            auto_restart: true,
-            is_watching: false,
-            status: "Idle".to_string(),
-            state: Arc::new(Mutex::new(AppState {
-                is_watching: false,
-                status: "Idle".to_string(),
-                should_stop: false,
-            })),
+            settings,
+            settings_store,
+            settings_dirty_since: None,
+            games,
+            profile_rename: None,
+            share_string: String::new(),
+            status: notice.unwrap_or_else(|| "Idle".to_string()),
+            watcher: None,
+            watched_profile: String::new(),
+            instances: Vec::new(),
+            crashes: Vec::new(),
+            ejections_tx,
+            ejections,
+            pending_start: None,
+            import_order: None,
         }
     }
 
-    fn start_watching(&mut self) {
-        let state = self.state.clone();
-        let hachimi = self.hachimi_enabled;
-        let hachimi_edge = self.hachimi_edge_enabled;
-        let game_version = self.game_version;
-        let custom = self.custom_dlls.clone();
-        let auto_restart = self.auto_restart;
+    fn sync_window_geometry(&mut self, ctx: &egui::Context) {
+        let (inner_rect, outer_rect) =
+            ctx.input(|i| (i.viewport().inner_rect, i.viewport().outer_rect));
+        if let Some(rect) = inner_rect {
+            self.settings.window.size = Some([rect.width(), rect.height()]);
+        }
+        if let Some(rect) = outer_rect {
+            self.settings.window.position = Some([rect.min.x, rect.min.y]);
+        }
+    }
 
-        {
-            let mut s = state.lock().unwrap();
-            s.is_watching = true;
-            s.should_stop = false;
-            s.status = "Watching for Umamusume...".to_string();
+    fn save_settings_if_changed(&mut self, ctx: &egui::Context) {
+        if self.settings_store.is_saved(&self.settings) {
+            self.settings_dirty_since = None;
+            return;
         }
-        self.is_watching = true;
 
-        thread::spawn(move || {
-            let mut downloaded_dlls = Vec::new();
+        let dirty_since = *self.settings_dirty_since.get_or_insert_with(Instant::now);
+        let elapsed = dirty_since.elapsed();
+        if elapsed < SETTINGS_SAVE_DELAY {
+            ctx.request_repaint_after(SETTINGS_SAVE_DELAY - elapsed);
+            return;
+        }
 
-            if hachimi {
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Downloading latest Hachimi...".to_string();
+        if let Err(e) = self.settings_store.save(&self.settings) {
+            error!("{}", e);
+            self.set_status(e);
+        }
+        self.settings_dirty_since = None;
+    }
+
+    fn set_status(&mut self, status: impl Into<String>) {
+        self.status = status.into();
+    }
+
+    fn profiles_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Profile:");
+            if let Some(name) = &mut self.profile_rename {
+                let response = ui.text_edit_singleline(name);
+                let submitted =
+                    response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Save").clicked() || submitted {
+                    let name = name.clone();
+                    self.settings.rename_active_profile(&name);
+                    self.profile_rename = None;
+                } else if ui.button("Cancel").clicked() {
+                    self.profile_rename = None;
                 }
-                match github::hachimi_download_latest(HachimiVersion::Original) {
-                    Ok(path) => downloaded_dlls.push(path),
-                    Err(e) => {
-                        let mut s = state.lock().unwrap();
-                        s.status = format!("Download failed: {}", e);
-
-                        rfd::MessageDialog::new()
-                            .set_title("Download Error")
-                            .set_description(format!(
-                                "Failed to download Hachimi: {}\n\nInjection will continue without this DLL.",
-                                e
-                            ))
-                            .set_level(rfd::MessageLevel::Error)
-                            .show();
+            } else {
+                let mut active = self.settings.active_profile.clone();
+                egui::ComboBox::from_id_salt("profile")
+                    .selected_text(&active)
+                    .show_ui(ui, |ui| {
+                        for profile in &self.settings.profiles {
+                            ui.selectable_value(&mut active, profile.name.clone(), &profile.name);
+                        }
+                    });
+                self.settings.active_profile = active;
+            }
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("New").clicked() {
+                self.settings.add_profile(Profile {
+                    name: "New Profile".to_string(),
+                    ..Default::default()
+                });
+            }
+            if ui.button("Duplicate").clicked() {
+                let profile = self.settings.active_profile().clone();
+                self.settings.add_profile(profile);
+            }
+            if ui.button("Rename").clicked() {
+                self.profile_rename = Some(self.settings.active_profile.clone());
+            }
+            if ui
+                .add_enabled(
+                    self.settings.profiles.len() > 1,
+                    egui::Button::new("Delete"),
+                )
+                .clicked()
+            {
+                self.settings.remove_active_profile();
+            }
+        });
+
+        ui.collapsing("Import / Export", |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Export to File...").clicked() {
+                    let profile = self.settings.active_profile();
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("JSON Files", &["json"])
+                        .set_file_name(format!("{}.json", profile.name))
+                        .save_file()
+                    {
+                        let status = match profile.export_to_file(&path) {
+                            Ok(_) => format!("Exported profile '{}'", profile.name),
+                            Err(e) => e,
+                        };
+                        self.set_status(status);
                     }
                 }
-            }
-            if hachimi_edge {
+                if ui.button("Import from File...").clicked()
+                    && let Some(path) = rfd::FileDialog::new()
+                        .add_filter("JSON Files", &["json"])
+                        .pick_file()
                 {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Downloading latest Hachimi Edge...".to_string();
+                    self.import_profile(Profile::import_from_file(&path));
                 }
-                match github::hachimi_download_latest(HachimiVersion::Edge) {
-                    Ok(path) => downloaded_dlls.push(path),
-                    Err(e) => {
-                        let mut s = state.lock().unwrap();
-                        s.status = format!("Download failed: {}", e);
-
-                        rfd::MessageDialog::new()
-                            .set_title("Download Error")
-                            .set_description(format!(
-                                "Failed to download Hachimi Edge: {}\n\nInjection will continue without this DLL.",
-                                e
-                            ))
-                            .set_level(rfd::MessageLevel::Error)
-                            .show();
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("Copy Share String").clicked() {
+                    match self.settings.active_profile().to_share_string() {
+                        Ok(share) => {
+                            ui.ctx().copy_text(share);
+                            self.set_status("Profile share string copied to clipboard");
+                        }
+                        Err(e) => self.set_status(e),
                     }
                 }
+            });
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.share_string)
+                        .hint_text("Paste a share string...")
+                        .desired_width(200.0),
+                );
+                if ui.button("Import").clicked() {
+                    self.import_profile(Profile::from_share_string(&self.share_string));
+                    self.share_string.clear();
+                }
+            });
+        });
+    }
+
+    fn import_profile(&mut self, profile: Result<Profile, String>) {
+        match profile {
+            Ok(profile) => {
+                self.settings.add_profile(profile);
+                self.set_status(format!(
+                    "Imported profile '{}'",
+                    self.settings.active_profile
+                ));
             }
+            Err(e) => self.set_status(e),
+        }
+    }
 
-            let process_name = match game_version {
-                GameVersion::Global => "UmamusumePrettyDerby.exe",
-                GameVersion::Japanese => "umamusume.exe",
-            };
+    /// Starts a watcher for `profile`, replacing the running one, once the
+    /// user confirmed it if DLLs have missing dependencies. With `restart` the
+    /// running game is closed and launched again.
+    fn start_watching(&mut self, ctx: &egui::Context, profile: Profile, restart: bool) {
+        let Some(game) = game::find(&self.games, &profile.game).cloned() else {
+            self.set_status(format!("Unknown game '{}', pick one above", profile.game));
+            return;
+        };
 
-            loop {
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = format!("Watching for {}...", process_name);
-                }
+        let problems = missing_dependencies(&profile, &game);
+        if !problems.is_empty() {
+            self.set_status("Some DLLs have missing dependencies");
+            self.pending_start = Some(PendingStart {
+                profile,
+                game,
+                restart,
+                problems,
+            });
+            return;
+        }
+        self.spawn_watcher(ctx, profile, game, restart);
+    }
 
-                // 1. Wait for process
-                let ph = loop {
-                    {
-                        let s = state.lock().unwrap();
-                        if s.should_stop {
-                            return;
+    fn spawn_watcher(
+        &mut self,
+        ctx: &egui::Context,
+        profile: Profile,
+        game: GameDefinition,
+        restart: bool,
+    ) {
+        if restart || profile.launch.enabled {
+            self.set_status(format!("Launching {}...", game.name));
+        } else {
+            self.set_status(format!("Watching for {}...", game.name));
+        }
+        self.instances.clear();
+        self.crashes.clear();
+        self.watched_profile = profile.name.clone();
+        let ctx = ctx.clone();
+        self.watcher = Some(watcher::spawn(
+            PlatformBackend::default(),
+            profile,
+            game,
+            restart,
+            move || ctx.request_repaint(),
+        ));
+    }
+
+    fn pending_start_ui(&mut self, ctx: &egui::Context) {
+        let Some(pending) = &self.pending_start else {
+            return;
+        };
+        let mut start = None;
+        egui::Window::new("Missing Dependencies")
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label("The game won't be able to load these DLLs, or parts of them:");
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for (dll, missing) in &pending.problems {
+                            egui::CollapsingHeader::new(
+                                dll.file_name().unwrap_or_default().to_string_lossy(),
+                            )
+                            .default_open(true)
+                            .show(ui, |ui| dependency_tree_ui(ui, missing))
+                            .header_response
+                            .on_hover_text(dll.display().to_string());
                         }
+                    });
+                ui.horizontal(|ui| {
+                    if ui.button("Start Anyway").clicked() {
+                        start = Some(true);
                     }
-                    if let Some(ph) = win32::find_process(process_name) {
-                        break ph;
+                    if ui.button("Cancel").clicked() {
+                        start = Some(false);
                     }
-                    thread::sleep(std::time::Duration::from_millis(500));
-                };
+                });
+            });
+        match start {
+            Some(true) => {
+                let pending = self.pending_start.take().unwrap();
+                self.spawn_watcher(ctx, pending.profile, pending.game, pending.restart);
+            }
+            Some(false) => {
+                self.pending_start = None;
+                self.set_status("Idle");
+            }
+            None => {}
+        }
+    }
 
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Process found! Waiting for window...".to_string();
+    fn stop_watching(&mut self) {
+        if let Some(watcher) = &self.watcher {
+            watcher.stop();
+            self.set_status("Stopping...");
+        }
+    }
+
+    /// Ejects `dll` from the process without blocking the UI, the result is
+    /// picked up by `poll_ejections`.
+    fn eject(&mut self, ctx: &egui::Context, pid: u32, dll: PathBuf) {
+        let profile = self
+            .settings
+            .profiles
+            .iter()
+            .find(|p| p.name == self.watched_profile);
+        let shutdown_export = profile
+            .and_then(|p| p.custom_dll(&dll))
+            .and_then(|d| d.shutdown_export.clone());
+        let methods = profile
+            .map(|p| p.injection_methods.clone())
+            .unwrap_or_default();
+        self.set_status(format!(
+            "Ejecting {} from process {}...",
+            dll.display(),
+            pid
+        ));
+        let tx = self.ejections_tx.clone();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            let backend = PlatformBackend::default();
+            let result = match backend.open_process(pid) {
+                Some(process) => {
+                    backend.eject(&process, &dll, &methods, shutdown_export.as_deref())
                 }
+                // The game is gone, and the DLL with it
+                None => Err(InjectError::NotInjected),
+            };
+            let _ = tx.send(Ejection { pid, dll, result });
+            ctx.request_repaint();
+        });
+    }
 
-                // 2. Wait for window
-                loop {
+    fn poll_ejections(&mut self) {
+        while let Ok(ejection) = self.ejections.try_recv() {
+            let name = ejection.dll.display().to_string();
+            match ejection.result {
+                Ok(()) => {
+                    if let Some(instance) = self
+                        .instances
+                        .iter_mut()
+                        .find(|i| i.process.pid == ejection.pid)
                     {
-                        let s = state.lock().unwrap();
-                        if s.should_stop {
-                            return;
-                        }
-                    }
-                    if win32::has_window(ph) {
-                        break;
+                        instance.dll_results.retain(|r| r.dll != ejection.dll);
                     }
-                    thread::sleep(std::time::Duration::from_millis(500));
+                    self.set_status(format!("Ejected {} from process {}", name, ejection.pid));
                 }
-
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Waiting for process to become idle...".to_string();
+                Err(e) => {
+                    let message = format!("Failed to eject {}: {}", name, e);
+                    self.set_status(message.clone());
+                    show_dialog(message, rfd::MessageLevel::Error);
                 }
-                win32::wait_for_input_idle(ph, 10000);
+            }
+        }
+    }
 
-                thread::sleep(std::time::Duration::from_millis(1000));
+    /// Applies the events the watcher sent since the last frame.
+    fn poll_watcher(&mut self) {
+        let Some(watcher) = &self.watcher else {
+            return;
+        };
 
-                let mut dlls_to_inject = downloaded_dlls.clone();
-                for d in &custom {
-                    if let Some(s) = d.to_str() {
-                        dlls_to_inject.push(s.to_string());
+        let mut finished = false;
+        for event in watcher.events.try_iter() {
+            match event {
+                WatcherEvent::State(state) => {
+                    self.status = state.to_string();
+                    if let WatcherState::CrashLoop { .. } = state {
+                        show_dialog(state.to_string(), rfd::MessageLevel::Error);
+                    } else if let WatcherState::Exited { pid } = state {
+                        self.instances.retain(|i| i.process.pid != pid);
+                    } else if let Some(pid) = state.pid()
+                        && let Some(instance) =
+                            self.instances.iter_mut().find(|i| i.process.pid == pid)
+                    {
+                        if matches!(state, WatcherState::Injecting { .. }) {
+                            instance.dll_results.clear();
+                        }
+                        instance.status = state.to_string();
                     }
                 }
-
-
-                let mut success_count = 0;
-                for dll in &dlls_to_inject {
-                    let dll_path = Path::new(dll);
-                    let absolute_dll_path = if dll_path.is_relative() {
-                        std::env::current_dir().unwrap().join(dll_path)
-                    } else {
-                        dll_path.to_path_buf()
-                    };
-
-                    if let Some(path_str) = absolute_dll_path.to_str() {
-                        if unsafe { win32::inject_dll_to_handle(ph, path_str) } {
-                            success_count += 1;
+                WatcherEvent::ProcessFound(process) => self.instances.push(GameInstance {
+                    process,
+                    status: "Found".to_string(),
+                    dll_results: Vec::new(),
+                }),
+                WatcherEvent::InjectionResult(result) => {
+                    if let Some(instance) = self
+                        .instances
+                        .iter_mut()
+                        .find(|i| i.process.pid == result.pid)
+                    {
+                        instance.dll_results.push(result);
+                    }
+                }
+                WatcherEvent::Warning(warning) => show_dialog(warning, rfd::MessageLevel::Warning),
+                WatcherEvent::Crashed(report) => self.crashes.push(report),
+                WatcherEvent::DllDisabled(path) => {
+                    // Also turn it off in the profile so the next run skips it
+                    if let Some(profile) = self
+                        .settings
+                        .profiles
+                        .iter_mut()
+                        .find(|p| p.name == self.watched_profile)
+                    {
+                        for dll in &mut profile.custom_dlls {
+                            if profile::absolute_dll_path(&dll.path).ok().as_ref() == Some(&path) {
+                                dll.enabled = false;
+                            }
                         }
                     }
+                    show_dialog(
+                        format!(
+                            "The game kept crashing right after injecting, {} was disabled",
+                            path.display()
+                        ),
+                        rfd::MessageLevel::Warning,
+                    );
                 }
+                WatcherEvent::Finished(_) => finished = true,
+            }
+        }
+        if finished {
+            self.watcher = None;
+        }
+    }
+}
 
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = format!("Injected {}/{} DLLs", success_count, dlls_to_inject.len());
+/// Shows a message box without blocking the UI.
+fn show_dialog(description: String, level: rfd::MessageLevel) {
+    thread::spawn(move || {
+        rfd::MessageDialog::new()
+            .set_title("Tamamo-X")
+            .set_description(description)
+            .set_level(level)
+            .show();
+    });
+}
+
+/// The enabled custom DLLs of `profile` whose dependencies can't all be found.
+fn missing_dependencies(
+    profile: &Profile,
+    game: &GameDefinition,
+) -> Vec<(PathBuf, Vec<Dependency>)> {
+    // The game may not be running yet, then only the executable to launch
+    // tells where it is
+    let game_dir = match &profile.launch.executable {
+        Some(executable) => executable.parent().map(Path::to_path_buf),
+        None => PlatformBackend::default()
+            .find_processes(&game.process_matcher())
+            .into_iter()
+            .find_map(|p| p.path?.parent().map(Path::to_path_buf)),
+    };
+    let system_dirs = dependencies::system_dirs();
+    profile
+        .custom_dlls
+        .iter()
+        .filter(|dll| dll.enabled)
+        .filter_map(|dll| {
+            let resolver = Resolver::new(
+                &RealFileSystem,
+                &dll.path,
+                &dll.load,
+                game_dir.as_deref(),
+                system_dirs.clone(),
+            );
+            // DLLs that can't be read are reported when injecting
+            let missing = dependencies::broken_only(resolver.resolve(&dll.path).ok()?);
+            (!missing.is_empty()).then(|| (dll.path.clone(), missing))
+        })
+        .collect()
+}
+
+fn dependency_tree_ui(ui: &mut egui::Ui, dependencies: &[Dependency]) {
+    for dependency in dependencies {
+        if dependency.dependencies.is_empty() {
+            // Delay-loaded DLLs may never be needed
+            let color = if dependency.delayed {
+                ui.visuals().warn_fg_color
+            } else {
+                ui.visuals().error_fg_color
+            };
+            ui.colored_label(color, format!("❌ {}", dependency));
+        } else {
+            egui::CollapsingHeader::new(dependency.to_string())
+                .default_open(true)
+                .show(ui, |ui| dependency_tree_ui(ui, &dependency.dependencies));
+        }
+    }
+}
+
+fn dll_order_ui(ui: &mut egui::Ui, profile: &mut Profile, import_order: &mut Option<ImportOrder>) {
+    ui.horizontal(|ui| {
+        ui.label("Injection order:");
+        ui.radio_value(&mut profile.dll_order, DllOrder::Imports, "By imports")
+            .on_hover_text("DLLs are injected after the other custom DLLs they import");
+        ui.radio_value(&mut profile.dll_order, DllOrder::List, "List order");
+    });
+    if profile.dll_order != DllOrder::Imports {
+        return;
+    }
+
+    let dlls: Vec<_> = profile
+        .custom_dlls
+        .iter()
+        .filter(|dll| dll.enabled)
+        .map(|dll| dll.path.clone())
+        .collect();
+    if dlls.len() < 2 {
+        return;
+    }
+    // Reading the import tables every frame would be too slow
+    if import_order
+        .as_ref()
+        .is_none_or(|cached| cached.dlls != dlls)
+    {
+        let paths: Vec<_> = dlls.iter().map(PathBuf::as_path).collect();
+        let order = dependencies::import_order(&RealFileSystem, &paths);
+        *import_order = Some(ImportOrder { dlls, order });
+    }
+    let Some(cached) = import_order else {
+        return;
+    };
+    match &cached.order {
+        Ok(order) => {
+            let names: Vec<_> = order
+                .iter()
+                .map(|&i| {
+                    cached.dlls[i]
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                })
+                .collect();
+            ui.weak(format!("Injected as: {}", names.join(" → ")));
+        }
+        Err(cycle) => {
+            ui.colored_label(
+                ui.visuals().warn_fg_color,
+                format!("⚠ {}, they are injected in list order", cycle),
+            );
+        }
+    }
+}
+
+/// Methods in use come first in the order they are tried, then the others.
+fn injection_methods_ui(ui: &mut egui::Ui, methods: &mut Vec<InjectionMethod>) {
+    let unused = InjectionMethod::ALL
+        .into_iter()
+        .filter(|m| !methods.contains(m));
+    let shown: Vec<_> = methods.iter().copied().chain(unused).collect();
+    for (i, method) in shown.into_iter().enumerate() {
+        ui.horizontal(|ui| {
+            let mut used = methods.contains(&method);
+            // At least one method has to stay in use
+            let can_toggle = !used || methods.len() > 1;
+            if ui
+                .add_enabled(
+                    can_toggle,
+                    egui::Checkbox::new(&mut used, method.to_string()),
+                )
+                .changed()
+            {
+                if used {
+                    methods.push(method);
+                } else {
+                    methods.retain(|m| *m != method);
                 }
+            }
+            if used && i > 0 && ui.small_button("⬆").on_hover_text("Try earlier").clicked() {
+                methods.swap(i - 1, i);
+            }
+        });
+    }
+}
 
-                if !auto_restart {
-                    let mut s = state.lock().unwrap();
-                    s.is_watching = false;
-                    return;
+/// The list of custom DLLs, reordered by dragging them by their handle.
+fn custom_dlls_ui(ui: &mut egui::Ui, dlls: &mut Vec<CustomDll>) {
+    ui.label("Custom DLLs:");
+    let mut to_remove = None;
+    let mut to_move = None;
+    for (i, dll) in dlls.iter_mut().enumerate() {
+        let row = ui
+            .horizontal(|ui| {
+                ui.dnd_drag_source(egui::Id::new(("custom_dll", i)), i, |ui| {
+                    ui.label("☰");
+                })
+                .response
+                .on_hover_text("Drag to change the injection order");
+                ui.checkbox(&mut dll.enabled, "")
+                    .on_hover_text("Inject this DLL");
+                let name = dll.path.file_name().unwrap_or_default().to_string_lossy();
+                if dll.enabled {
+                    ui.label(name)
+                } else {
+                    ui.weak(name)
+                }
+                .on_hover_text(dll.path.display().to_string());
+                if ui.button("❌").clicked() {
+                    to_remove = Some(i);
                 }
+            })
+            .response;
+        if let Some(from) = row.dnd_release_payload::<usize>() {
+            to_move = Some((*from, i));
+        }
 
+        egui::CollapsingHeader::new("Options")
+            .id_salt(("custom_dll_options", i))
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Delay before injecting:");
+                    ui.add(egui::DragValue::new(&mut dll.delay_ms).suffix(" ms"));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Wait for module:");
+                    let mut module = dll.wait_for_module.clone().unwrap_or_default();
+                    if ui
+                        .add(egui::TextEdit::singleline(&mut module).hint_text("GameAssembly.dll"))
+                        .changed()
+                    {
+                        let module = module.trim();
+                        dll.wait_for_module = (!module.is_empty()).then(|| module.to_string());
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Retries:");
+                    ui.add(egui::DragValue::new(&mut dll.retries).range(0..=10));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Dependencies from:");
+                    let search_path = &mut dll.load.search_path;
+                    egui::ComboBox::from_id_salt(("search_path", i))
+                        .selected_text(search_path.to_string())
+                        .show_ui(ui, |ui| {
+                            for path in [
+                                SearchPath::Game,
+                                SearchPath::Altered,
+                                SearchPath::DllLoadDir,
+                            ] {
+                                ui.selectable_value(search_path, path, path.to_string());
+                            }
+                        });
+                });
+                let mut dir_to_remove = None;
+                for (j, dir) in dll.load.dll_directories.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.label(dir.display().to_string());
+                        if ui.small_button("❌").clicked() {
+                            dir_to_remove = Some(j);
+                        }
+                    });
+                }
+                if let Some(j) = dir_to_remove {
+                    dll.load.dll_directories.remove(j);
+                }
+                if ui
+                    .button("Add DLL directory...")
+                    .on_hover_text("Also searched for the DLLs it depends on")
+                    .clicked()
+                    && let Some(dir) = rfd::FileDialog::new().pick_folder()
                 {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Injected. Waiting for process to exit...".to_string();
+                    dll.load.dll_directories.push(dir);
                 }
-
-                // 3. Wait for process to exit
-                loop {
+                ui.horizontal(|ui| {
+                    ui.label("Init export:");
+                    let mut export = dll
+                        .init
+                        .as_ref()
+                        .map(|i| i.export.clone())
+                        .unwrap_or_default();
+                    if ui
+                        .add(egui::TextEdit::singleline(&mut export).hint_text("Init"))
+                        .on_hover_text("Function called in the game once the DLL is injected")
+                        .changed()
                     {
-                        let s = state.lock().unwrap();
-                        if s.should_stop {
-                            return;
+                        let export = export.trim();
+                        if export.is_empty() {
+                            dll.init = None;
+                        } else {
+                            dll.init.get_or_insert_with(InitCall::default).export =
+                                export.to_string();
                         }
                     }
-                    unsafe {
-                        let wait_result = WaitForSingleObject(ph, 500);
-                        if wait_result == windows::Win32::Foundation::WAIT_OBJECT_0 {
-                            break;
+                });
+                if let Some(init) = &mut dll.init {
+                    ui.horizontal(|ui| {
+                        ui.label("Argument:");
+                        ui.radio_value(&mut init.format, ArgumentFormat::None, "None");
+                        ui.radio_value(&mut init.format, ArgumentFormat::Text, "Text");
+                        ui.radio_value(&mut init.format, ArgumentFormat::Json, "JSON");
+                    });
+                    if init.format != ArgumentFormat::None {
+                        ui.add(
+                            egui::TextEdit::multiline(&mut init.argument)
+                                .code_editor()
+                                .desired_rows(2),
+                        );
+                        if let Err(e) = init.argument_blob() {
+                            ui.colored_label(ui.visuals().error_fg_color, e);
                         }
                     }
                 }
-
-                {
-                    let mut s = state.lock().unwrap();
-                    s.status = "Process exited. Restarting watch...".to_string();
-                }
-            }
-        });
+                ui.horizontal(|ui| {
+                    ui.label("Shutdown export:");
+                    let mut export = dll.shutdown_export.clone().unwrap_or_default();
+                    if ui
+                        .add(egui::TextEdit::singleline(&mut export).hint_text("Shutdown"))
+                        .on_hover_text("Function called in the game before ejecting the DLL")
+                        .changed()
+                    {
+                        let export = export.trim();
+                        dll.shutdown_export = (!export.is_empty()).then(|| export.to_string());
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Note:");
+                    ui.text_edit_singleline(&mut dll.note);
+                });
+            });
+    }
+    if let Some(i) = to_remove {
+        dlls.remove(i);
+    } else if let Some((from, to)) = to_move {
+        let dll = dlls.remove(from);
+        dlls.insert(to, dll);
     }
 
-    fn stop_watching(&mut self) {
-        let mut s = self.state.lock().unwrap();
-        s.should_stop = true;
-        s.is_watching = false;
-        s.status = "Stopping...".to_string();
-        self.is_watching = false;
+    if ui.button("Add Custom DLL...").clicked()
+        && let Some(path) = rfd::FileDialog::new()
+            .add_filter("DLL Files", &["dll"])
+            .pick_file()
+    {
+        // The game isn't known yet, its architecture is checked when injecting
+        match pe::check_injectable_dll(&path) {
+            Ok(_) => dlls.push(CustomDll::new(path)),
+            Err(e) => {
+                rfd::MessageDialog::new()
+                    .set_title("Invalid DLL")
+                    .set_description(e)
+                    .set_level(rfd::MessageLevel::Error)
+                    .show();
+            }
+        }
     }
 }
 
 impl eframe::App for TamamoApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Sync state
-        {
-            let s = self.state.lock().unwrap();
-            self.is_watching = s.is_watching;
-            self.status = s.status.clone();
-        }
+        self.poll_watcher();
+        self.poll_ejections();
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
@@ -270,62 +872,164 @@ impl eframe::App for TamamoApp {
             ui.separator();
 
             ui.vertical(|ui| {
+                self.profiles_ui(ui);
+
+                ui.separator();
+
+                let profile = self.settings.active_profile_mut();
+
                 ui.label("Game Version:");
-                ui.radio_value(&mut self.game_version, GameVersion::Global, "Global (UmamusumePrettyDerby.exe)");
-                ui.radio_value(&mut self.game_version, GameVersion::Japanese, "Japanese (umamusume.exe)");
-                
+                for game in &self.games {
+                    ui.radio_value(&mut profile.game, game.id.clone(), game.label());
+                }
+                if game::find(&self.games, &profile.game).is_none() {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        format!("Game '{}' is not defined anymore", profile.game),
+                    );
+                }
+
                 ui.separator();
 
                 ui.label("Injection Options:");
-                if ui.checkbox(&mut self.hachimi_enabled, "Inject Hachimi").clicked() && self.hachimi_enabled {
-                    self.hachimi_edge_enabled = false;
+                let mut hachimi = profile.hachimi == Some(HachimiVersion::Original);
+                if ui.checkbox(&mut hachimi, "Inject Hachimi").changed() {
+                    profile.hachimi = hachimi.then_some(HachimiVersion::Original);
                 }
-                if ui.checkbox(&mut self.hachimi_edge_enabled, "Inject Hachimi-Edge").clicked() && self.hachimi_edge_enabled {
-                    self.hachimi_enabled = false;
+                let mut hachimi_edge = profile.hachimi == Some(HachimiVersion::Edge);
+                if ui
+                    .checkbox(&mut hachimi_edge, "Inject Hachimi-Edge")
+                    .changed()
+                {
+                    profile.hachimi = hachimi_edge.then_some(HachimiVersion::Edge);
                 }
 
                 ui.group(|ui| {
-                    ui.label("Custom DLLs:");
-                    let mut to_remove = None;
-                    for (i, dll) in self.custom_dlls.iter().enumerate() {
-                        ui.horizontal(|ui| {
-                            ui.label(dll.file_name().unwrap_or_default().to_string_lossy());
-                            if ui.button("❌").clicked() {
-                                to_remove = Some(i);
-                            }
-                        });
-                    }
-                    if let Some(i) = to_remove {
-                        self.custom_dlls.remove(i);
-                    }
+                    custom_dlls_ui(ui, &mut profile.custom_dlls);
+                    dll_order_ui(ui, profile, &mut self.import_order);
+                });
 
-                    if ui.button("Add Custom DLL...").clicked() {
-                        if let Some(path) = rfd::FileDialog::new()
-                            .add_filter("DLL Files", &["dll"])
-                            .pick_file()
-                        {
-                            self.custom_dlls.push(path);
-                        }
-                    }
+                ui.collapsing("Injection Methods", |ui| {
+                    injection_methods_ui(ui, &mut profile.injection_methods)
                 });
 
                 ui.separator();
 
                 ui.checkbox(
-                    &mut self.auto_restart,
+                    &mut profile.watch.auto_restart,
                     "Auto-restart watching when game stops",
                 );
 
+                ui.collapsing("Crash Protection", |ui| {
+                    let protection = &mut profile.watch.crash_protection;
+                    ui.checkbox(
+                        &mut protection.enabled,
+                        "Stop crash loops when auto-restarting",
+                    );
+                    ui.add_enabled_ui(protection.enabled, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Exits within");
+                            ui.add(
+                                egui::DragValue::new(&mut protection.window_secs)
+                                    .range(1..=3600)
+                                    .suffix(" s"),
+                            );
+                            ui.label("of injecting count as crashes");
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("After");
+                            ui.add(
+                                egui::DragValue::new(&mut protection.max_crashes).range(1..=100),
+                            );
+                            ui.label("crashes in a row:");
+                        });
+                        ui.radio_value(
+                            &mut protection.action,
+                            CrashAction::PauseAutoRestart,
+                            "Pause auto-restart",
+                        );
+                        ui.radio_value(
+                            &mut protection.action,
+                            CrashAction::DisableLastDll,
+                            "Disable the last custom DLL",
+                        );
+                    });
+                });
+
+                ui.collapsing("Launch Options", |ui| {
+                    let launch = &mut profile.launch;
+                    ui.checkbox(
+                        &mut launch.enabled,
+                        "Launch the game and inject before it starts",
+                    );
+                    ui.horizontal(|ui| {
+                        ui.label("Executable:");
+                        match &launch.executable {
+                            Some(path) => ui.label(path.display().to_string()),
+                            None => ui.weak("Not set"),
+                        };
+                        if ui.button("Browse...").clicked()
+                            && let Some(path) = rfd::FileDialog::new()
+                                .add_filter("Programs", &["exe"])
+                                .pick_file()
+                        {
+                            launch.executable = Some(path);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Working directory:");
+                        match launch.working_dir() {
+                            Some(dir) if launch.working_dir.is_some() => {
+                                ui.label(dir.display().to_string())
+                            }
+                            Some(dir) => ui.weak(dir.display().to_string()),
+                            None => ui.weak("Not set"),
+                        };
+                        if ui.button("Browse...").clicked()
+                            && let Some(dir) = rfd::FileDialog::new().pick_folder()
+                        {
+                            launch.working_dir = Some(dir);
+                        }
+                        if launch.working_dir.is_some() && ui.button("❌").clicked() {
+                            launch.working_dir = None;
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Arguments:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut launch.arguments)
+                                .hint_text("-screen-width 1920 -popupwindow"),
+                        );
+                    });
+                });
+
+                let launch = profile.launch.enabled;
                 ui.horizontal(|ui| {
-                    if self.is_watching {
+                    if self.watcher.is_some() {
                         if ui.button("Stop Watching").clicked() {
                             self.stop_watching();
                         }
                     } else {
-                        if ui.button("Start Watching").clicked() {
-                            self.start_watching();
+                        let label = if launch {
+                            "Launch Game"
+                        } else {
+                            "Start Watching"
+                        };
+                        if ui.button(label).clicked() {
+                            self.start_watching(
+                                ui.ctx(),
+                                self.settings.active_profile().clone(),
+                                false,
+                            );
                         }
                     }
+                    if ui
+                        .button("Restart Game")
+                        .on_hover_text("Close the game, then launch it again and inject")
+                        .clicked()
+                    {
+                        self.start_watching(ui.ctx(), self.settings.active_profile().clone(), true);
+                    }
                 });
 
                 ui.separator();
@@ -333,20 +1037,63 @@ impl eframe::App for TamamoApp {
                     ui.label("Status:");
                     ui.label(&self.status);
                 });
+                for crash in &self.crashes {
+                    ui.colored_label(ui.visuals().error_fg_color, format!("⚠ {}", crash));
+                }
+                let mut to_eject = None;
+                for instance in &self.instances {
+                    ui.group(|ui| {
+                        ui.label(format!(
+                            "{} (PID {})",
+                            instance.process.name, instance.process.pid
+                        ))
+                        .on_hover_text(instance.details());
+                        ui.label(&instance.status);
+                        for result in &instance.dll_results {
+                            ui.horizontal(|ui| {
+                                match &result.error {
+                                    None if result.skipped.is_some() => {
+                                        ui.weak(format!("↷ {}", result))
+                                    }
+                                    None => ui.label(format!("✔ {}", result)),
+                                    Some(_) => ui.colored_label(
+                                        ui.visuals().error_fg_color,
+                                        format!("❌ {}", result),
+                                    ),
+                                }
+                                .on_hover_text(result.dll.display().to_string());
+                                if result.module.is_some()
+                                    && ui
+                                        .small_button("Eject")
+                                        .on_hover_text("Unload this DLL from the game")
+                                        .clicked()
+                                {
+                                    to_eject = Some((instance.process.pid, result.dll.clone()));
+                                }
+                            });
+                        }
+                    });
+                }
+                if let Some((pid, dll)) = to_eject {
+                    self.eject(ctx, pid, dll);
+                }
             });
         });
 
-        // Request repaint to keep status updated
-        if self.is_watching {
-            ctx.request_repaint();
-        }
+        self.pending_start_ui(ctx);
+
+        self.sync_window_geometry(ctx);
+        self.save_settings_if_changed(ctx);
     }
 }
 
 fn main() -> eframe::Result {
-    // Initialize tracing
+    let cli = Cli::parse();
+
+    // Initialize tracing, logs go to stderr so they don't mix with CLI output
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into()))
+        .with_writer(std::io::stderr)
         .init();
 
     warn!(
@@ -357,16 +1104,41 @@ fn main() -> eframe::Result {
         "This is an experimental software, hence I will NOT be responsible for any damage. Use at your own risk."
     );
 
+    if let Some(command) = cli.command {
+        std::process::exit(cli::run(command, cli.json));
+    }
+
+    let (settings_store, settings, mut notice) = SettingsStore::load();
+    let (games, errors) = game::load_definitions(&settings::config_dir().join("games"));
+    if !errors.is_empty() {
+        notice = Some(format!(
+            "Failed to load game definitions: {}",
+            errors.join("; ")
+        ));
+    }
+
+    let mut viewport = egui::ViewportBuilder::default()
+        .with_inner_size(settings.window.size.unwrap_or([400.0, 600.0]))
+        .with_drag_and_drop(true);
+    if let Some(position) = settings.window.position {
+        viewport = viewport.with_position(position);
+    }
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_inner_size([400.0, 600.0])
-            .with_drag_and_drop(true),
+        viewport,
         ..Default::default()
     };
 
     eframe::run_native(
         "Tamamo-X",
         options,
-        Box::new(|cc| Ok(Box::new(TamamoApp::new(cc)))),
+        Box::new(|cc| {
+            Ok(Box::new(TamamoApp::new(
+                cc,
+                settings_store,
+                settings,
+                games,
+                notice,
+            )))
+        }),
     )
 }
@@ -0,0 +1,417 @@
+//! Rules deciding which running processes belong to a game.
+
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+#[cfg(any(windows, test))]
+use crate::backend::ProcessEntry;
+
+/// A rule matched against a process, e.g. in a game definition:
+///
+/// ```toml
+/// [matcher]
+/// all = [{ name = "game.exe" }, { path_glob = "*\\Steam\\*" }]
+/// ```
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessMatcher {
+    /// Executable file name, ignoring case.
+    Name(String),
+    /// Glob on the full executable path, ignoring case. `*` matches any run of
+    /// characters, `?` a single one, and `/` and `\` are interchangeable.
+    PathGlob(String),
+    /// Regex searched for in the full executable path.
+    PathRegex(PathRegex),
+    /// The process has a visible window whose title contains `title` and whose
+    /// class is exactly `class`.
+    Window {
+        #[serde(default)]
+        title: Option<String>,
+        #[serde(default)]
+        class: Option<String>,
+    },
+    /// Executable file name of the parent process, ignoring case.
+    ParentName(String),
+    All(Vec<ProcessMatcher>),
+    Any(Vec<ProcessMatcher>),
+}
+
+/// A compiled regex that keeps its source for serializing and comparing.
+#[derive(Clone)]
+pub struct PathRegex {
+    source: String,
+    #[cfg_attr(not(any(windows, test)), allow(dead_code))]
+    regex: Regex,
+}
+
+impl PathRegex {
+    pub fn new(source: &str) -> Result<Self, String> {
+        let regex = Regex::new(source).map_err(|e| format!("Invalid regex '{}': {}", source, e))?;
+        Ok(Self {
+            source: source.to_string(),
+            regex,
+        })
+    }
+}
+
+impl PartialEq for PathRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Debug for PathRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+impl Serialize for PathRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for PathRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        PathRegex::new(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A visible top-level window.
+#[cfg(any(windows, test))]
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct WindowInfo {
+    pub pid: u32,
+    pub title: String,
+    pub class: String,
+}
+
+#[cfg(any(windows, test))]
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// Matches `text` against a glob, ignoring case and treating `/` like `\`.
+#[cfg(any(windows, test))]
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let normalize = |s: &str| -> Vec<char> {
+        s.to_lowercase()
+            .chars()
+            .map(|c| if c == '/' { '\\' } else { c })
+            .collect()
+    };
+    let (pattern, text) = (normalize(pattern), normalize(text));
+
+    // Greedy matching that backtracks to the last `*` on a mismatch
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl ProcessMatcher {
+    /// Matches any of the given executable names, what a game definition
+    /// without a `matcher` uses.
+    pub fn names(names: &[String]) -> Self {
+        ProcessMatcher::Any(names.iter().cloned().map(ProcessMatcher::Name).collect())
+    }
+
+    /// Whether the rule looks at executable paths, which have to be read from
+    /// each process.
+    #[cfg(any(windows, test))]
+    pub fn needs_path(&self) -> bool {
+        match self {
+            ProcessMatcher::PathGlob(_) | ProcessMatcher::PathRegex(_) => true,
+            ProcessMatcher::All(matchers) | ProcessMatcher::Any(matchers) => {
+                matchers.iter().any(|m| m.needs_path())
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the rule looks at windows, which have to be enumerated.
+    #[cfg(any(windows, test))]
+    pub fn needs_windows(&self) -> bool {
+        match self {
+            ProcessMatcher::Window { .. } => true,
+            ProcessMatcher::All(matchers) | ProcessMatcher::Any(matchers) => {
+                matchers.iter().any(|m| m.needs_windows())
+            }
+            _ => false,
+        }
+    }
+
+    /// Matches `process`, looking up its parent in `processes` and its windows
+    /// in `windows`.
+    #[cfg(any(windows, test))]
+    pub fn matches(
+        &self,
+        process: &ProcessEntry,
+        processes: &[ProcessEntry],
+        windows: &[WindowInfo],
+    ) -> bool {
+        match self {
+            ProcessMatcher::Name(name) => same_name(name, &process.name),
+            ProcessMatcher::PathGlob(glob) => process
+                .path
+                .as_ref()
+                .is_some_and(|path| glob_matches(glob, &path.to_string_lossy())),
+            ProcessMatcher::PathRegex(regex) => process
+                .path
+                .as_ref()
+                .is_some_and(|path| regex.regex.is_match(path.to_string_lossy().as_ref())),
+            ProcessMatcher::Window { title, class } => windows.iter().any(|w| {
+                w.pid == process.pid
+                    && title.as_ref().is_none_or(|t| w.title.contains(t.as_str()))
+                    && class.as_ref().is_none_or(|c| w.class == *c)
+            }),
+            ProcessMatcher::ParentName(name) => {
+                parent(process, processes).is_some_and(|p| same_name(name, &p.name))
+            }
+            ProcessMatcher::All(matchers) => matchers
+                .iter()
+                .all(|m| m.matches(process, processes, windows)),
+            ProcessMatcher::Any(matchers) => matchers
+                .iter()
+                .any(|m| m.matches(process, processes, windows)),
+        }
+    }
+}
+
+/// Finds the parent of `process`, skipping a process that reused the parent's
+/// PID after it exited.
+#[cfg(any(windows, test))]
+fn parent<'a>(process: &ProcessEntry, processes: &'a [ProcessEntry]) -> Option<&'a ProcessEntry> {
+    processes.iter().find(|p| {
+        p.pid == process.parent_pid
+            && p.pid != process.pid
+            && match (p.start_time, process.start_time) {
+                (Some(parent), Some(child)) => parent <= child,
+                _ => true,
+            }
+    })
+}
+
+/// The processes in `processes` that `matcher` matches.
+#[cfg(any(windows, test))]
+pub fn select(
+    matcher: &ProcessMatcher,
+    processes: &[ProcessEntry],
+    windows: &[WindowInfo],
+) -> Vec<ProcessEntry> {
+    processes
+        .iter()
+        .filter(|p| matcher.matches(p, processes, windows))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn process(pid: u32, name: &str, parent_pid: u32, path: &str) -> ProcessEntry {
+        ProcessEntry {
+            pid,
+            name: name.to_string(),
+            parent_pid,
+            path: Some(PathBuf::from(path)),
+            start_time: Some(1000 + pid as u64),
+            ..Default::default()
+        }
+    }
+
+    fn processes() -> Vec<ProcessEntry> {
+        vec![
+            process(4, "explorer.exe", 0, "C:\\Windows\\explorer.exe"),
+            process(
+                10,
+                "steam.exe",
+                4,
+                "C:\\Program Files (x86)\\Steam\\steam.exe",
+            ),
+            process(
+                20,
+                "UmamusumePrettyDerby.exe",
+                10,
+                "D:\\SteamLibrary\\steamapps\\common\\UmamusumePrettyDerby\\UmamusumePrettyDerby.exe",
+            ),
+            process(
+                30,
+                "umamusume.exe",
+                4,
+                "C:\\Games\\Umamusume\\umamusume.exe",
+            ),
+            process(
+                40,
+                "UmamusumePrettyDerby_Launcher.exe",
+                4,
+                "C:\\Launcher\\launcher.exe",
+            ),
+        ]
+    }
+
+    fn pids(matcher: &ProcessMatcher, windows: &[WindowInfo]) -> Vec<u32> {
+        select(matcher, &processes(), windows)
+            .iter()
+            .map(|p| p.pid)
+            .collect()
+    }
+
+    fn name(name: &str) -> ProcessMatcher {
+        ProcessMatcher::Name(name.to_string())
+    }
+
+    #[test]
+    fn names_are_exact_and_ignore_case() {
+        assert_eq!(pids(&name("umamusumeprettyderby.exe"), &[]), [20]);
+        assert_eq!(pids(&name("UMAMUSUME.EXE"), &[]), [30]);
+        assert!(pids(&name("Umamusume"), &[]).is_empty());
+        assert_eq!(
+            pids(
+                &ProcessMatcher::names(&["umamusume.exe".to_string(), "steam.exe".to_string()]),
+                &[]
+            ),
+            [10, 30]
+        );
+    }
+
+    #[test]
+    fn globs_match_the_full_path() {
+        assert!(glob_matches(
+            "*\\steamapps\\*\\*.exe",
+            "D:\\SteamLibrary\\steamapps\\common\\x.exe"
+        ));
+        assert!(glob_matches(
+            "c:/games/*/UMAMUSUME.EXE",
+            "C:\\Games\\Umamusume\\umamusume.exe"
+        ));
+        assert!(glob_matches("C:\\Game?\\*", "C:\\Games\\a"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("*.dll", "C:\\game.exe"));
+        assert!(!glob_matches("C:\\Games", "C:\\Games\\a"));
+
+        let matcher = ProcessMatcher::PathGlob("*\\steamapps\\common\\*".to_string());
+        assert_eq!(pids(&matcher, &[]), [20]);
+
+        // Processes whose path could not be read never match a path rule
+        let mut unreadable = processes()[2].clone();
+        unreadable.path = None;
+        assert!(!matcher.matches(&unreadable, &[], &[]));
+    }
+
+    #[test]
+    fn regexes_search_the_full_path() {
+        let matcher =
+            ProcessMatcher::PathRegex(PathRegex::new(r"(?i)\\umamusume[^\\]*\\").unwrap());
+        assert_eq!(pids(&matcher, &[]), [20, 30]);
+        assert!(PathRegex::new("(unclosed").is_err());
+    }
+
+    #[test]
+    fn windows_match_title_and_class_together() {
+        let windows = [
+            WindowInfo {
+                pid: 20,
+                title: "Umamusume: Pretty Derby".to_string(),
+                class: "UnityWndClass".to_string(),
+            },
+            WindowInfo {
+                pid: 30,
+                title: "Loading".to_string(),
+                class: "UnityWndClass".to_string(),
+            },
+        ];
+        let window = |title: Option<&str>, class: Option<&str>| ProcessMatcher::Window {
+            title: title.map(str::to_string),
+            class: class.map(str::to_string),
+        };
+
+        assert_eq!(
+            pids(&window(None, Some("UnityWndClass")), &windows),
+            [20, 30]
+        );
+        assert_eq!(pids(&window(Some("Pretty Derby"), None), &windows), [20]);
+        assert!(pids(&window(Some("Loading"), Some("Other")), &windows).is_empty());
+        assert!(pids(&window(None, None), &[]).is_empty());
+    }
+
+    #[test]
+    fn parents_are_matched_by_name() {
+        assert_eq!(
+            pids(&ProcessMatcher::ParentName("STEAM.exe".to_string()), &[]),
+            [20]
+        );
+
+        // A process that reused the parent's PID after it exited
+        let mut list = processes();
+        list[1].start_time = Some(5000);
+        let matcher = ProcessMatcher::ParentName("steam.exe".to_string());
+        assert!(select(&matcher, &list, &[]).is_empty());
+    }
+
+    #[test]
+    fn rules_combine() {
+        let matcher = ProcessMatcher::All(vec![
+            ProcessMatcher::Any(vec![
+                name("umamusume.exe"),
+                name("UmamusumePrettyDerby.exe"),
+            ]),
+            ProcessMatcher::ParentName("explorer.exe".to_string()),
+        ]);
+        assert_eq!(pids(&matcher, &[]), [30]);
+        assert!(!matcher.needs_path());
+        assert!(!matcher.needs_windows());
+
+        assert!(pids(&ProcessMatcher::Any(vec![]), &[]).is_empty());
+        assert_eq!(pids(&ProcessMatcher::All(vec![]), &[]).len(), 5);
+    }
+
+    #[test]
+    fn parses_from_toml() {
+        #[derive(Deserialize)]
+        struct Definition {
+            matcher: ProcessMatcher,
+        }
+        let definition: Definition = toml::from_str(
+            r#"
+            [matcher]
+            all = [
+                { name = "umamusume.exe" },
+                { any = [{ path_glob = "C:\\Games\\*" }, { path_regex = "(?i)dmm" }] },
+                { window = { class = "UnityWndClass" } },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert!(definition.matcher.needs_path());
+        assert!(definition.matcher.needs_windows());
+        assert_eq!(pids(&definition.matcher, &[]), Vec::<u32>::new());
+
+        let error = toml::from_str::<Definition>("matcher = { path_regex = \"(\" }");
+        assert!(error.is_err());
+    }
+}
@@ -0,0 +1,677 @@
+use std::fmt;
+use std::path::Path;
+
+#[cfg(test)]
+pub mod fixtures;
+
+const IMAGE_FILE_DLL: u16 = 0x2000;
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10b;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20b;
+
+/// CPU architecture of a PE image or a process.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Machine {
+    X86,
+    X64,
+    Arm64,
+    Other(u16),
+}
+
+impl Machine {
+    pub fn from_raw(raw: u16) -> Self {
+        match raw {
+            0x014c => Machine::X86,
+            0x8664 => Machine::X64,
+            0xaa64 => Machine::Arm64,
+            other => Machine::Other(other),
+        }
+    }
+
+    /// The architecture Tamamo-X itself was built for.
+    pub fn current() -> Self {
+        if cfg!(target_arch = "x86") {
+            Machine::X86
+        } else if cfg!(target_arch = "aarch64") {
+            Machine::Arm64
+        } else {
+            Machine::X64
+        }
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Machine::X86 => write!(f, "32-bit (x86)"),
+            Machine::X64 => write!(f, "64-bit (x64)"),
+            Machine::Arm64 => write!(f, "ARM64"),
+            Machine::Other(raw) => write!(f, "unknown machine 0x{:04x}", raw),
+        }
+    }
+}
+
+/// What the DOS and NT headers of a PE file say about it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PeHeaders {
+    pub machine: Machine,
+    pub characteristics: u16,
+    pub subsystem: u16,
+    /// Whether the optional header is PE32+.
+    pub is_64_bit: bool,
+}
+
+impl PeHeaders {
+    pub fn is_dll(&self) -> bool {
+        self.characteristics & IMAGE_FILE_DLL != 0
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset.saturating_add(2))
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "File is truncated".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset.saturating_add(4))
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| "File is truncated".to_string())
+}
+
+pub fn parse_headers(data: &[u8]) -> Result<PeHeaders, String> {
+    if data.get(0..2) != Some(b"MZ") {
+        return Err("Not a PE file (missing MZ signature)".to_string());
+    }
+    let nt = read_u32(data, 0x3c)? as usize;
+    if data.get(nt..nt + 4) != Some(b"PE\0\0") {
+        return Err("Not a PE file (missing PE signature)".to_string());
+    }
+
+    // IMAGE_FILE_HEADER follows the signature, the optional header follows it.
+    let file_header = nt + 4;
+    let machine = Machine::from_raw(read_u16(data, file_header)?);
+    let characteristics = read_u16(data, file_header + 18)?;
+
+    let optional_header = file_header + 20;
+    let is_64_bit = match read_u16(data, optional_header)? {
+        IMAGE_NT_OPTIONAL_HDR32_MAGIC => false,
+        IMAGE_NT_OPTIONAL_HDR64_MAGIC => true,
+        magic => return Err(format!("Unknown optional header magic 0x{:04x}", magic)),
+    };
+    // Subsystem sits at the same offset in PE32 and PE32+ optional headers.
+    let subsystem = read_u16(data, optional_header + 68)?;
+
+    Ok(PeHeaders {
+        machine,
+        characteristics,
+        subsystem,
+        is_64_bit,
+    })
+}
+
+/// A function a DLL exports.
+#[cfg(any(windows, test))]
+#[derive(Clone, PartialEq, Debug)]
+pub struct Export {
+    /// Exports can be by ordinal only.
+    pub name: Option<String>,
+    pub ordinal: u16,
+    /// Where the function is, relative to the base the DLL is loaded at.
+    pub rva: u32,
+    /// Set when the export is another DLL's function, e.g. `NTDLL.RtlAllocateHeap`.
+    pub forwarder: Option<String>,
+}
+
+/// A DLL a PE file imports functions from.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Import {
+    /// File name of the DLL, as written in the importing file.
+    pub dll: String,
+    /// Delay-loaded DLLs are only loaded once one of their functions is called.
+    pub delayed: bool,
+}
+
+#[cfg(any(windows, test))]
+const DIRECTORY_EXPORT: usize = 0;
+const DIRECTORY_IMPORT: usize = 1;
+const DIRECTORY_DELAY_IMPORT: usize = 13;
+
+struct Section {
+    virtual_address: u32,
+    virtual_size: u32,
+    raw_offset: u32,
+    raw_size: u32,
+}
+
+fn sections(data: &[u8]) -> Result<Vec<Section>, String> {
+    let file_header = read_u32(data, 0x3c)? as usize + 4;
+    let count = read_u16(data, file_header + 2)? as usize;
+    let optional_header_size = read_u16(data, file_header + 16)? as usize;
+    let table = file_header + 20 + optional_header_size;
+    (0..count)
+        .map(|i| {
+            let section = table + i * 40;
+            Ok(Section {
+                virtual_size: read_u32(data, section + 8)?,
+                virtual_address: read_u32(data, section + 12)?,
+                raw_size: read_u32(data, section + 16)?,
+                raw_offset: read_u32(data, section + 20)?,
+            })
+        })
+        .collect()
+}
+
+/// The RVA and size of data directory `index`, `None` if the image has none.
+fn data_directory(
+    data: &[u8],
+    headers: &PeHeaders,
+    index: usize,
+) -> Result<Option<(u32, u32)>, String> {
+    let optional_header = read_u32(data, 0x3c)? as usize + 24;
+    // The directories follow the fields whose size differs between PE32 and PE32+
+    let (count, directories) = if headers.is_64_bit {
+        (108, 112)
+    } else {
+        (92, 96)
+    };
+    if index >= read_u32(data, optional_header + count)? as usize {
+        return Ok(None);
+    }
+    let directory = optional_header + directories + index * 8;
+    let rva = read_u32(data, directory)?;
+    let size = read_u32(data, directory + 4)?;
+    Ok((rva != 0).then_some((rva, size)))
+}
+
+/// Turns an RVA into an offset in the file, through the section it's in.
+fn rva_to_offset(sections: &[Section], rva: u32) -> Result<usize, String> {
+    let (section, offset) = sections
+        .iter()
+        .find_map(|s| {
+            let offset = rva.checked_sub(s.virtual_address)?;
+            (offset < s.virtual_size.max(s.raw_size)).then_some((s, offset))
+        })
+        .ok_or_else(|| format!("RVA 0x{:x} is not in any section", rva))?;
+    section
+        .raw_offset
+        .checked_add(offset)
+        .map(|offset| offset as usize)
+        .ok_or_else(|| format!("RVA 0x{:x} is past the end of the file", rva))
+}
+
+/// Checks that a table of `count` entries of `size` bytes at `offset` fits in
+/// `data`, before anything is allocated for it.
+#[cfg(any(windows, test))]
+fn check_table(data: &[u8], offset: usize, count: usize, size: usize) -> Result<(), String> {
+    let end = count
+        .checked_mul(size)
+        .and_then(|len| offset.checked_add(len))
+        .ok_or("Export table is too large")?;
+    if end > data.len() {
+        return Err("File is truncated".to_string());
+    }
+    Ok(())
+}
+
+fn read_c_string(data: &[u8], offset: usize) -> Result<String, String> {
+    let bytes = data.get(offset..).ok_or("File is truncated")?;
+    let end = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or("File is truncated")?;
+    Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+#[cfg(any(windows, test))]
+impl Export {
+    /// The DLL file name and function a forwarder points to. API sets are
+    /// mapped to KernelBase, which implements those kernel32 forwards to.
+    pub fn forwarded_to(&self) -> Option<(String, String)> {
+        let (dll, function) = self.forwarder.as_deref()?.rsplit_once('.')?;
+        // Forwarded by ordinal
+        if function.starts_with('#') {
+            return None;
+        }
+        let dll = dll.to_lowercase();
+        let dll = if dll.starts_with("api-ms-") || dll.starts_with("ext-ms-") {
+            "kernelbase".to_string()
+        } else {
+            dll
+        };
+        Some((format!("{}.dll", dll), function.to_string()))
+    }
+}
+
+/// Lists the functions exported by a PE file.
+#[cfg(any(windows, test))]
+pub fn parse_exports(data: &[u8]) -> Result<Vec<Export>, String> {
+    let sections = sections(data)?;
+    exports(data, |rva| rva_to_offset(&sections, rva))
+}
+
+/// Lists the functions exported by a PE image as it's laid out in memory once
+/// loaded, where RVAs are offsets.
+#[cfg(any(windows, test))]
+pub fn parse_image_exports(image: &[u8]) -> Result<Vec<Export>, String> {
+    exports(image, |rva| Ok(rva as usize))
+}
+
+#[cfg(any(windows, test))]
+fn exports(
+    data: &[u8],
+    offset: impl Fn(u32) -> Result<usize, String>,
+) -> Result<Vec<Export>, String> {
+    let headers = parse_headers(data)?;
+    let Some((directory_rva, directory_size)) = data_directory(data, &headers, DIRECTORY_EXPORT)?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let directory = offset(directory_rva)?;
+    let base = read_u32(data, directory + 16)?;
+    let function_count = read_u32(data, directory + 20)? as usize;
+    let name_count = read_u32(data, directory + 24)? as usize;
+    let functions = offset(read_u32(data, directory + 28)?)?;
+    let (names, ordinals) = if name_count > 0 {
+        (
+            offset(read_u32(data, directory + 32)?)?,
+            offset(read_u32(data, directory + 36)?)?,
+        )
+    } else {
+        (0, 0)
+    };
+    // The counts come from the file, so they can't be trusted to allocate
+    check_table(data, functions, function_count, 4)?;
+    check_table(data, names, name_count, 4)?;
+    check_table(data, ordinals, name_count, 2)?;
+
+    let mut function_names = vec![None; function_count];
+    for i in 0..name_count {
+        let index = read_u16(data, ordinals + i * 2)? as usize;
+        let name = read_c_string(data, offset(read_u32(data, names + i * 4)?)?)?;
+        if let Some(slot) = function_names.get_mut(index) {
+            *slot = Some(name);
+        }
+    }
+
+    let mut exports = Vec::new();
+    for (i, name) in function_names.into_iter().enumerate() {
+        let rva = read_u32(data, functions + i * 4)?;
+        // Unused slots in the function table
+        if rva == 0 {
+            continue;
+        }
+        // Forwarders point back into the export directory, at a string
+        let forwarder = if rva >= directory_rva && rva - directory_rva < directory_size {
+            Some(read_c_string(data, offset(rva)?)?)
+        } else {
+            None
+        };
+        exports.push(Export {
+            name,
+            ordinal: base.wrapping_add(i as u32) as u16,
+            rva,
+            forwarder,
+        });
+    }
+    Ok(exports)
+}
+
+/// Lists the DLLs a PE file imports from, then those it delay-loads.
+pub fn parse_imports(data: &[u8]) -> Result<Vec<Import>, String> {
+    let headers = parse_headers(data)?;
+    let sections = sections(data)?;
+    let mut imports = Vec::new();
+    // (directory, size of a descriptor, offset of the name in it)
+    for (index, size, name_field) in [(DIRECTORY_IMPORT, 20, 12), (DIRECTORY_DELAY_IMPORT, 32, 4)] {
+        let Some((directory_rva, _)) = data_directory(data, &headers, index)? else {
+            continue;
+        };
+        let delayed = index == DIRECTORY_DELAY_IMPORT;
+        // The descriptors end with one that is all zeroes
+        let mut descriptor = rva_to_offset(&sections, directory_rva)?;
+        loop {
+            let mut name = read_u32(data, descriptor + name_field)?;
+            if name == 0 {
+                break;
+            }
+            // Old delay-load descriptors hold addresses instead of RVAs, only
+            // ever in 32-bit files
+            if delayed && read_u32(data, descriptor)? & 1 == 0 && !headers.is_64_bit {
+                let image_base = read_u32(data, read_u32(data, 0x3c)? as usize + 24 + 28)?;
+                name = name.wrapping_sub(image_base);
+            }
+            imports.push(Import {
+                dll: read_c_string(data, rva_to_offset(&sections, name)?)?,
+                delayed,
+            });
+            descriptor += size;
+        }
+    }
+    Ok(imports)
+}
+
+#[cfg(windows)]
+pub fn read_exports(path: &Path) -> Result<Vec<Export>, String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse_exports(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn read_headers(path: &Path) -> Result<PeHeaders, String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse_headers(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Checks that the file at `path` is a DLL that can be loaded into a process
+/// running as `target`.
+pub fn check_dll(path: &Path, target: Machine) -> Result<PeHeaders, String> {
+    let headers = read_headers(path)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if !headers.is_dll() {
+        return Err(format!("{} is not a DLL", name));
+    }
+    if headers.machine != target {
+        return Err(format!(
+            "{} is a {} DLL, but the game is {}",
+            name, headers.machine, target
+        ));
+    }
+    Ok(headers)
+}
+
+/// Checks that the file at `path` is a DLL for an architecture Tamamo-X can
+/// inject into, its own or 32-bit x86 under WOW64, before the game is known.
+pub fn check_injectable_dll(path: &Path) -> Result<PeHeaders, String> {
+    let machine = read_headers(path)?.machine;
+    if machine != Machine::X86 && machine != Machine::current() {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        return Err(format!(
+            "{} is built for {}, only {} and 32-bit (x86) DLLs can be injected",
+            name,
+            machine,
+            Machine::current()
+        ));
+    }
+    check_dll(path, machine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const IMAGE_FILE_EXECUTABLE_IMAGE: u16 = 0x0002;
+    const SUBSYSTEM_WINDOWS_GUI: u16 = 2;
+
+    /// Builds the headers of a PE file, enough for `parse_headers`.
+    fn fixture(machine: u16, characteristics: u16, magic: u16) -> Vec<u8> {
+        let nt = 0x80;
+        let mut data = vec![0u8; 0x200];
+        data[0..2].copy_from_slice(b"MZ");
+        data[0x3c..0x40].copy_from_slice(&(nt as u32).to_le_bytes());
+        data[nt..nt + 4].copy_from_slice(b"PE\0\0");
+        data[nt + 4..nt + 6].copy_from_slice(&machine.to_le_bytes());
+        data[nt + 22..nt + 24].copy_from_slice(&characteristics.to_le_bytes());
+        data[nt + 24..nt + 26].copy_from_slice(&magic.to_le_bytes());
+        data[nt + 92..nt + 94].copy_from_slice(&SUBSYSTEM_WINDOWS_GUI.to_le_bytes());
+        data
+    }
+
+    fn dll64() -> Vec<u8> {
+        fixture(
+            0x8664,
+            IMAGE_FILE_EXECUTABLE_IMAGE | IMAGE_FILE_DLL,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC,
+        )
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tamamo-x-pe-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_fixture(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_64_bit_dll() {
+        let headers = parse_headers(&dll64()).unwrap();
+        assert_eq!(headers.machine, Machine::X64);
+        assert!(headers.is_dll());
+        assert!(headers.is_64_bit);
+        assert_eq!(headers.subsystem, SUBSYSTEM_WINDOWS_GUI);
+    }
+
+    #[test]
+    fn parses_32_bit_exe() {
+        let data = fixture(
+            0x014c,
+            IMAGE_FILE_EXECUTABLE_IMAGE,
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC,
+        );
+        let headers = parse_headers(&data).unwrap();
+        assert_eq!(headers.machine, Machine::X86);
+        assert!(!headers.is_dll());
+        assert!(!headers.is_64_bit);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(parse_headers(b"").is_err());
+        assert!(parse_headers(b"not a pe file at all").is_err());
+
+        let mut data = dll64();
+        data[0x80..0x84].copy_from_slice(b"NE\0\0");
+        assert!(parse_headers(&data).is_err());
+
+        // e_lfanew pointing past the end of the file
+        let mut data = dll64();
+        data[0x3c..0x40].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        assert!(parse_headers(&data).is_err());
+
+        assert!(parse_headers(&dll64()[..0x90]).is_err());
+    }
+
+    #[test]
+    fn check_dll_validates_architecture() {
+        let dir = temp_dir("check");
+        let path = write_fixture(&dir, "x64.dll", &dll64());
+        assert!(check_dll(&path, Machine::X64).is_ok());
+        let error = check_dll(&path, Machine::X86).unwrap_err();
+        assert_eq!(
+            error,
+            "x64.dll is a 64-bit (x64) DLL, but the game is 32-bit (x86)"
+        );
+
+        let exe = fixture(
+            0x8664,
+            IMAGE_FILE_EXECUTABLE_IMAGE,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC,
+        );
+        let path = write_fixture(&dir, "x64.exe", &exe);
+        assert_eq!(
+            check_dll(&path, Machine::X64).unwrap_err(),
+            "x64.exe is not a DLL"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn accepts_32_bit_dlls_before_the_game_is_known() {
+        let dir = temp_dir("injectable");
+        let path = write_fixture(&dir, "x86.dll", &fixtures::dll32_with_exports(&[]));
+        assert_eq!(check_injectable_dll(&path).unwrap().machine, Machine::X86);
+
+        let arm = fixture(0x01c4, IMAGE_FILE_DLL, IMAGE_NT_OPTIONAL_HDR32_MAGIC);
+        let path = write_fixture(&dir, "arm.dll", &arm);
+        assert_eq!(
+            check_injectable_dll(&path).unwrap_err(),
+            format!(
+                "arm.dll is built for unknown machine 0x01c4, only {} and 32-bit (x86) DLLs can be injected",
+                Machine::current()
+            )
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Builds a 64-bit DLL with one section holding its export directory. The
+    /// functions are at 0x2000 + 0x10 * i, followed by `Fwd`, forwarded to
+    /// `OTHER.Func`, and by one exported by ordinal only.
+    fn dll_with_exports(names: &[&str]) -> Vec<u8> {
+        let mut data = dll64();
+        data.resize(0x400, 0);
+        let put = |data: &mut Vec<u8>, offset: usize, value: u32| {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        // One section, a full PE32+ optional header and 16 data directories
+        data[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+        data[0x94..0x96].copy_from_slice(&0xf0u16.to_le_bytes());
+        put(&mut data, 0x98 + 108, 16);
+        put(&mut data, 0x98 + 112, 0x1000);
+        put(&mut data, 0x98 + 116, 0x200);
+        let section = 0x98 + 0xf0;
+        put(&mut data, section + 8, 0x200);
+        put(&mut data, section + 12, 0x1000);
+        put(&mut data, section + 16, 0x200);
+        put(&mut data, section + 20, 0x200);
+
+        // The section starts at offset 0x200 and RVA 0x1000
+        let rva = |offset: usize| (offset - 0x200 + 0x1000) as u32;
+        let (functions, names_table, ordinals) = (0x230, 0x260, 0x290);
+        let count = names.len() + 2;
+        put(&mut data, 0x200 + 16, 1);
+        put(&mut data, 0x200 + 20, count as u32);
+        put(&mut data, 0x200 + 24, names.len() as u32 + 1);
+        put(&mut data, 0x200 + 28, rva(functions));
+        put(&mut data, 0x200 + 32, rva(names_table));
+        put(&mut data, 0x200 + 36, rva(ordinals));
+
+        let mut strings = 0x2c0;
+        let mut add_string = |data: &mut Vec<u8>, s: &str| {
+            let offset = strings;
+            data[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+            strings += s.len() + 1;
+            offset
+        };
+        for (i, name) in names.iter().chain(&["Fwd"]).enumerate() {
+            let name = add_string(&mut data, name);
+            put(&mut data, names_table + i * 4, rva(name));
+            data[ordinals + i * 2..ordinals + i * 2 + 2].copy_from_slice(&(i as u16).to_le_bytes());
+        }
+        for i in 0..names.len() {
+            put(&mut data, functions + i * 4, 0x2000 + 0x10 * i as u32);
+        }
+        let forwarder = add_string(&mut data, "OTHER.Func");
+        put(&mut data, functions + names.len() * 4, rva(forwarder));
+        put(&mut data, functions + (names.len() + 1) * 4, 0x3000);
+        data
+    }
+
+    #[test]
+    fn parses_exports() {
+        let exports = parse_exports(&dll_with_exports(&["Init", "Shutdown"])).unwrap();
+        let summary: Vec<_> = exports
+            .iter()
+            .map(|e| (e.name.as_deref(), e.ordinal, e.rva, e.forwarder.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some("Init"), 1, 0x2000, None),
+                (Some("Shutdown"), 2, 0x2010, None),
+                (Some("Fwd"), 3, 0x10d2, Some("OTHER.Func")),
+                (None, 4, 0x3000, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_imports_and_delay_imports() {
+        let data =
+            fixtures::dll_with_imports(&["KERNEL32.dll", "helper.dll"], &["d3dcompiler_47.dll"]);
+        let imports: Vec<_> = parse_imports(&data)
+            .unwrap()
+            .into_iter()
+            .map(|i| (i.dll, i.delayed))
+            .collect();
+        assert_eq!(
+            imports,
+            [
+                ("KERNEL32.dll".to_string(), false),
+                ("helper.dll".to_string(), false),
+                ("d3dcompiler_47.dll".to_string(), true),
+            ]
+        );
+        assert_eq!(parse_imports(&dll64()).unwrap(), []);
+    }
+
+    #[test]
+    fn parses_exports_of_loaded_32_bit_images() {
+        let file = fixtures::dll32_with_exports(&[
+            ("LoadLibraryExW", None),
+            (
+                "AddDllDirectory",
+                Some("api-ms-win-core-libraryloader-l1-1-0.AddDllDirectory"),
+            ),
+            ("GetLastError", Some("NTDLL.RtlGetLastWin32Error")),
+        ]);
+        let image = fixtures::map_image(&file);
+        let exports = parse_image_exports(&image).unwrap();
+        assert_eq!(exports, parse_exports(&file).unwrap());
+
+        let summary: Vec<_> = exports
+            .iter()
+            .map(|e| (e.name.as_deref().unwrap(), e.rva, e.forwarded_to()))
+            .collect();
+        let forwarded = |dll: &str, function: &str| Some((dll.to_string(), function.to_string()));
+        assert_eq!(
+            summary,
+            [
+                ("LoadLibraryExW", 0x2000, None),
+                (
+                    "AddDllDirectory",
+                    0x10ec,
+                    forwarded("kernelbase.dll", "AddDllDirectory")
+                ),
+                (
+                    "GetLastError",
+                    0x1121,
+                    forwarded("ntdll.dll", "RtlGetLastWin32Error")
+                ),
+            ]
+        );
+        // In the file, the export directory is not at its RVA
+        assert!(parse_image_exports(&file).is_err());
+    }
+
+    #[test]
+    fn dlls_without_export_directory_export_nothing() {
+        assert_eq!(parse_exports(&dll64()).unwrap(), []);
+
+        // The directory points outside of every section
+        let mut data = dll_with_exports(&["Init"]);
+        data[0x98 + 112..0x98 + 116].copy_from_slice(&0x8000u32.to_le_bytes());
+        assert!(parse_exports(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_export_tables() {
+        // A function count far larger than the file
+        let mut data = dll_with_exports(&["Init"]);
+        data[0x200 + 20..0x200 + 24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_exports(&data).unwrap_err(), "File is truncated");
+
+        // A section whose file offset overflows once an RVA is added to it
+        let mut data = dll_with_exports(&["Init"]);
+        let section = 0x98 + 0xf0;
+        data[section + 20..section + 24].copy_from_slice(&0xffff_ff00u32.to_le_bytes());
+        assert!(parse_exports(&data).is_err());
+    }
+}
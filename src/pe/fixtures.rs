@@ -0,0 +1,144 @@
+//! PE files built in memory for tests.
+
+fn put(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Builds a 64-bit DLL with one section, at file offset 0x200 and RVA 0x1000,
+/// holding its import and delay-import directories.
+pub fn dll_with_imports(imports: &[&str], delay_imports: &[&str]) -> Vec<u8> {
+    let mut data = vec![0u8; 0x600];
+    data[0..2].copy_from_slice(b"MZ");
+    put(&mut data, 0x3c, 0x80);
+    data[0x80..0x84].copy_from_slice(b"PE\0\0");
+    // x64, one section, a full PE32+ optional header, a DLL
+    data[0x84..0x86].copy_from_slice(&0x8664u16.to_le_bytes());
+    data[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+    data[0x94..0x96].copy_from_slice(&0xf0u16.to_le_bytes());
+    data[0x96..0x98].copy_from_slice(&0x2002u16.to_le_bytes());
+    data[0x98..0x9a].copy_from_slice(&0x20bu16.to_le_bytes());
+    put(&mut data, 0x98 + 108, 16);
+    let section = 0x98 + 0xf0;
+    put(&mut data, section + 8, 0x400);
+    put(&mut data, section + 12, 0x1000);
+    put(&mut data, section + 16, 0x400);
+    put(&mut data, section + 20, 0x200);
+
+    let rva = |offset: usize| (offset - 0x200 + 0x1000) as u32;
+    let directory = |index: usize| 0x98 + 112 + index * 8;
+    let (import_table, delay_table) = (0x200, 0x300);
+    let mut strings = 0x400;
+    let mut add_string = |data: &mut Vec<u8>, s: &str| {
+        let offset = strings;
+        data[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        strings += s.len() + 1;
+        offset
+    };
+
+    if !imports.is_empty() {
+        put(&mut data, directory(1), rva(import_table));
+        put(&mut data, directory(1) + 4, (imports.len() as u32 + 1) * 20);
+    }
+    for (i, dll) in imports.iter().enumerate() {
+        let name = add_string(&mut data, dll);
+        let descriptor = import_table + i * 20;
+        put(&mut data, descriptor + 12, rva(name));
+        put(&mut data, descriptor + 16, 0x3000 + i as u32 * 0x10);
+    }
+
+    if !delay_imports.is_empty() {
+        put(&mut data, directory(13), rva(delay_table));
+        put(
+            &mut data,
+            directory(13) + 4,
+            (delay_imports.len() as u32 + 1) * 32,
+        );
+    }
+    for (i, dll) in delay_imports.iter().enumerate() {
+        let name = add_string(&mut data, dll);
+        let descriptor = delay_table + i * 32;
+        // Attributes: RVA based
+        put(&mut data, descriptor, 1);
+        put(&mut data, descriptor + 4, rva(name));
+    }
+    data
+}
+
+/// Builds a 32-bit DLL with one section, at file offset 0x200 and RVA 0x1000,
+/// holding its export directory. Exports without a forwarder are functions
+/// at 0x2000 + 0x10 * i.
+pub fn dll32_with_exports(exports: &[(&str, Option<&str>)]) -> Vec<u8> {
+    let mut data = vec![0u8; 0x400];
+    data[0..2].copy_from_slice(b"MZ");
+    put(&mut data, 0x3c, 0x80);
+    data[0x80..0x84].copy_from_slice(b"PE\0\0");
+    // x86, one section, a full PE32 optional header, a DLL
+    data[0x84..0x86].copy_from_slice(&0x014cu16.to_le_bytes());
+    data[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+    data[0x94..0x96].copy_from_slice(&0xe0u16.to_le_bytes());
+    data[0x96..0x98].copy_from_slice(&0x2102u16.to_le_bytes());
+    data[0x98..0x9a].copy_from_slice(&0x10bu16.to_le_bytes());
+    put(&mut data, 0x98 + 28, 0x1000_0000);
+    put(&mut data, 0x98 + 92, 16);
+    put(&mut data, 0x98 + 96, 0x1000);
+    put(&mut data, 0x98 + 100, 0x200);
+    let section = 0x98 + 0xe0;
+    put(&mut data, section + 8, 0x200);
+    put(&mut data, section + 12, 0x1000);
+    put(&mut data, section + 16, 0x200);
+    put(&mut data, section + 20, 0x200);
+
+    let rva = |offset: usize| (offset - 0x200 + 0x1000) as u32;
+    let (functions, names, ordinals) = (0x230, 0x260, 0x290);
+    put(&mut data, 0x200 + 16, 1);
+    put(&mut data, 0x200 + 20, exports.len() as u32);
+    put(&mut data, 0x200 + 24, exports.len() as u32);
+    put(&mut data, 0x200 + 28, rva(functions));
+    put(&mut data, 0x200 + 32, rva(names));
+    put(&mut data, 0x200 + 36, rva(ordinals));
+
+    let mut strings = 0x2c0;
+    let mut add_string = |data: &mut Vec<u8>, s: &str| {
+        let offset = strings;
+        data[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+        strings += s.len() + 1;
+        offset
+    };
+    for (i, (name, _)) in exports.iter().enumerate() {
+        let name = add_string(&mut data, name);
+        put(&mut data, names + i * 4, rva(name));
+        data[ordinals + i * 2..ordinals + i * 2 + 2].copy_from_slice(&(i as u16).to_le_bytes());
+    }
+    for (i, (_, forwarder)) in exports.iter().enumerate() {
+        let function = match forwarder {
+            Some(forwarder) => rva(add_string(&mut data, forwarder)),
+            None => 0x2000 + 0x10 * i as u32,
+        };
+        put(&mut data, functions + i * 4, function);
+    }
+    data
+}
+
+/// Lays a PE file out the way the loader maps it, with each section at its
+/// RVA.
+pub fn map_image(file: &[u8]) -> Vec<u8> {
+    let sections = super::sections(file).unwrap();
+    let headers = sections
+        .iter()
+        .map(|s| s.raw_offset as usize)
+        .min()
+        .unwrap_or(file.len());
+    let size = sections
+        .iter()
+        .map(|s| (s.virtual_address + s.virtual_size.max(s.raw_size)) as usize)
+        .max()
+        .unwrap_or(headers);
+    let mut image = vec![0u8; size];
+    image[..headers].copy_from_slice(&file[..headers]);
+    for section in &sections {
+        let (raw, size) = (section.raw_offset as usize, section.raw_size as usize);
+        let address = section.virtual_address as usize;
+        image[address..address + size].copy_from_slice(&file[raw..raw + size]);
+    }
+    image
+}
@@ -0,0 +1,374 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::HachimiVersion;
+use crate::backend::{InjectionMethod, LoadOptions};
+use crate::game;
+use crate::settings::{self, SETTINGS_VERSION};
+
+pub const DEFAULT_PROFILE_NAME: &str = "Default";
+
+const SHARE_STRING_PREFIX: &str = "tamamo-x:";
+
+/// Everything needed to start watching: which game to target, what to inject
+/// and how the watcher should behave.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Profile {
+    pub name: String,
+    /// Id of the targeted `GameDefinition`.
+    pub game: String,
+    pub hachimi: Option<HachimiVersion>,
+    pub custom_dlls: Vec<CustomDll>,
+    pub dll_order: DllOrder,
+    /// Tried in order until one works.
+    pub injection_methods: Vec<InjectionMethod>,
+    pub watch: WatchOptions,
+    pub launch: LaunchOptions,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE_NAME.to_string(),
+            game: game::DEFAULT_GAME_ID.to_string(),
+            hachimi: Some(HachimiVersion::Original),
+            custom_dlls: Vec::new(),
+            dll_order: DllOrder::default(),
+            injection_methods: InjectionMethod::ALL.to_vec(),
+            watch: WatchOptions::default(),
+            launch: LaunchOptions::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct WatchOptions {
+    pub auto_restart: bool,
+    pub crash_protection: CrashProtection,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            auto_restart: true,
+            crash_protection: CrashProtection::default(),
+        }
+    }
+}
+
+/// Stops a DLL that crashes the game on start from causing an endless
+/// crash/inject loop with `auto_restart`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct CrashProtection {
+    pub enabled: bool,
+    /// Exits within this many seconds after injecting count as quick crashes.
+    pub window_secs: u64,
+    /// Quick crashes in a row before `action` is taken.
+    pub max_crashes: u32,
+    pub action: CrashAction,
+}
+
+impl Default for CrashProtection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_secs: 30,
+            max_crashes: 3,
+            action: CrashAction::PauseAutoRestart,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CrashAction {
+    /// Stop watching for the game.
+    PauseAutoRestart,
+    /// Stop injecting the last enabled custom DLL, the most likely culprit,
+    /// and keep watching. Watching is paused once there's none left.
+    DisableLastDll,
+}
+
+/// The order custom DLLs are injected in.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DllOrder {
+    /// List order, except that DLLs come after the other custom DLLs they
+    /// import.
+    #[default]
+    Imports,
+    /// Exactly the list order.
+    List,
+}
+
+/// A DLL injected by a profile, in `DllOrder` after the game's default DLLs
+/// and Hachimi.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct CustomDll {
+    pub path: PathBuf,
+    /// Disabled DLLs are kept in the list but not injected.
+    pub enabled: bool,
+    /// Time to wait before injecting, after the previous DLL.
+    pub delay_ms: u64,
+    /// Only inject once a module with this file name is loaded in the game.
+    pub wait_for_module: Option<String>,
+    /// How many more times to try when injecting fails.
+    pub retries: u32,
+    /// Where the DLLs it depends on are searched for.
+    pub load: LoadOptions,
+    /// Export called once the DLL is injected.
+    pub init: Option<InitCall>,
+    /// Function the DLL exports to clean up, called before it's ejected.
+    pub shutdown_export: Option<String>,
+    pub note: String,
+}
+
+impl CustomDll {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for CustomDll {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            enabled: true,
+            delay_ms: 0,
+            wait_for_module: None,
+            retries: 0,
+            load: LoadOptions::default(),
+            init: None,
+            shutdown_export: None,
+            note: String::new(),
+        }
+    }
+}
+
+/// A function a DLL exports to be set up with, e.g. `Init(const char* config)`,
+/// for DLLs that don't do their work in `DllMain`.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct InitCall {
+    pub export: String,
+    pub argument: String,
+    pub format: ArgumentFormat,
+}
+
+/// How `InitCall::argument` is passed to the export.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgumentFormat {
+    /// A null pointer, the argument is ignored.
+    #[default]
+    None,
+    /// The argument as a NUL-terminated UTF-8 string.
+    Text,
+    /// Like `Text`, after checking the argument is JSON and minifying it.
+    Json,
+}
+
+impl InitCall {
+    /// The bytes written into the game for the export, `None` for a null
+    /// pointer.
+    pub fn argument_blob(&self) -> Result<Option<Vec<u8>>, String> {
+        let text = match self.format {
+            ArgumentFormat::None => return Ok(None),
+            ArgumentFormat::Text => self.argument.clone(),
+            ArgumentFormat::Json => serde_json::from_str::<serde_json::Value>(&self.argument)
+                .map_err(|e| format!("The argument for {} is not valid JSON: {}", self.export, e))?
+                .to_string(),
+        };
+        if text.contains('\0') {
+            return Err(format!(
+                "The argument for {} contains a NUL character",
+                self.export
+            ));
+        }
+        let mut blob = text.into_bytes();
+        blob.push(0);
+        Ok(Some(blob))
+    }
+}
+
+/// How to start the game when Tamamo-X launches it itself, so DLLs are
+/// injected before the game runs any of its own code.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct LaunchOptions {
+    /// Launch the game instead of waiting for it to be started.
+    pub enabled: bool,
+    pub executable: Option<PathBuf>,
+    /// Defaults to the folder of `executable`.
+    pub working_dir: Option<PathBuf>,
+    /// Appended to the command line as is, e.g. `-screen-width 1920 -popupwindow`.
+    pub arguments: String,
+}
+
+impl LaunchOptions {
+    pub fn working_dir(&self) -> Option<PathBuf> {
+        self.working_dir.clone().or_else(|| {
+            let parent = self.executable.as_ref()?.parent()?;
+            Some(parent.to_path_buf())
+        })
+    }
+}
+
+impl Profile {
+    /// The custom DLL at `path`, which is compared as an absolute path like
+    /// the watcher reports it.
+    pub fn custom_dll(&self, path: &Path) -> Option<&CustomDll> {
+        let absolute = |path: &Path| absolute_dll_path(path).ok();
+        self.custom_dlls
+            .iter()
+            .find(|dll| absolute(&dll.path) == absolute(path))
+    }
+
+    /// Encodes the profile as a single line that can be pasted into chat.
+    pub fn to_share_string(&self) -> Result<String, String> {
+        let json = serde_json::to_vec(&ExportedProfile::new(self))
+            .map_err(|e| format!("Failed to serialize profile: {}", e))?;
+        Ok(format!(
+            "{}{}",
+            SHARE_STRING_PREFIX,
+            URL_SAFE_NO_PAD.encode(json)
+        ))
+    }
+
+    pub fn from_share_string(share: &str) -> Result<Self, String> {
+        let encoded = share
+            .trim()
+            .strip_prefix(SHARE_STRING_PREFIX)
+            .ok_or("Not a Tamamo-X profile string")?;
+        let json = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|e| format!("Invalid profile string: {}", e))?;
+        parse_exported(&json).map_err(|e| format!("Invalid profile string: {}", e))
+    }
+
+    pub fn export_to_file(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&ExportedProfile::new(self))
+            .map_err(|e| format!("Failed to serialize profile: {}", e))?;
+        std::fs::write(path, json).map_err(|e| format!("Failed to write profile: {}", e))
+    }
+
+    pub fn import_from_file(path: &Path) -> Result<Self, String> {
+        let json =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read profile: {}", e))?;
+        parse_exported(json.as_bytes()).map_err(|e| format!("Invalid profile file: {}", e))
+    }
+}
+
+/// A profile as it's exported, with the settings schema it was written with
+/// so it can be migrated when imported.
+#[derive(Serialize)]
+struct ExportedProfile<'a> {
+    version: u32,
+    #[serde(flatten)]
+    profile: &'a Profile,
+}
+
+impl<'a> ExportedProfile<'a> {
+    fn new(profile: &'a Profile) -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            profile,
+        }
+    }
+}
+
+/// Reads an exported profile, migrating it from the schema it was written with.
+fn parse_exported(json: &[u8]) -> Result<Profile, String> {
+    let value: serde_json::Value = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+    if !value.is_object() {
+        return Err("expected a JSON object".to_string());
+    }
+    serde_json::from_value(settings::migrate_profile(value)).map_err(|e| e.to_string())
+}
+
+/// Makes a DLL path absolute the way the watcher does before injecting it,
+/// resolving `..` on Windows, so the paths it reports match the profile's.
+pub fn absolute_dll_path(path: &Path) -> std::io::Result<PathBuf> {
+    std::path::absolute(path)
+}
+
+/// Returns `base` if no profile uses it yet, otherwise `base (2)`, `base (3)`...
+pub fn unique_name(profiles: &[Profile], base: &str) -> String {
+    let taken = |name: &str| profiles.iter().any(|p| p.name == name);
+    let base = match base.trim() {
+        "" => "Profile",
+        trimmed => trimmed,
+    };
+    if !taken(base) {
+        return base.to_string();
+    }
+    (2..)
+        .map(|i| format!("{} ({})", base, i))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_profile() -> Profile {
+        Profile {
+            name: "Shared".to_string(),
+            game: "japanese".to_string(),
+            custom_dlls: vec![CustomDll::new(PathBuf::from("C:/mods/a.dll"))],
+            ..Default::default()
+        }
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tamamo-x-profile-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn round_trips_through_share_strings_and_files() {
+        let profile = test_profile();
+
+        let share = profile.to_share_string().unwrap();
+        assert!(share.starts_with(SHARE_STRING_PREFIX));
+        assert_eq!(Profile::from_share_string(&share).unwrap(), profile);
+
+        let path = temp_file("round-trip");
+        profile.export_to_file(&path).unwrap();
+        let exported: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(exported["version"], SETTINGS_VERSION);
+        assert_eq!(Profile::import_from_file(&path).unwrap(), profile);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrates_profiles_exported_before_versioning() {
+        let path = temp_file("legacy");
+        std::fs::write(
+            &path,
+            r#"{ "name": "Shared", "game_version": "Japanese", "custom_dlls": ["C:/mods/a.dll"] }"#,
+        )
+        .unwrap();
+        assert_eq!(Profile::import_from_file(&path).unwrap(), test_profile());
+        std::fs::remove_file(path).unwrap();
+
+        // Schema 3, where custom DLLs were still plain paths
+        let json = r#"{ "version": 3, "name": "Shared", "game": "japanese", "custom_dlls": ["C:/mods/a.dll"] }"#;
+        let share = format!("{}{}", SHARE_STRING_PREFIX, URL_SAFE_NO_PAD.encode(json));
+        assert_eq!(Profile::from_share_string(&share).unwrap(), test_profile());
+    }
+}
@@ -0,0 +1,456 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+use crate::profile::{self, Profile};
+
+/// Current schema version of the settings file, bump this and add a migration
+/// to `MIGRATIONS` whenever the layout of `Settings` changes incompatibly.
+pub const SETTINGS_VERSION: u32 = 4;
+
+const SETTINGS_FILE_NAME: &str = "settings.json";
+const PORTABLE_MARKER_FILE_NAME: &str = "portable.txt";
+
+/// A migration upgrades a settings document from version `n` to `n + 1`,
+/// where `n` is its index in `MIGRATIONS`. Keys that may be missing are
+/// looked up with `get_mut`, indexing would insert a null.
+type Migration = fn(&mut Value);
+
+const MIGRATIONS: &[Migration] = &[
+    // 0 -> 1: files written before the schema was versioned have the same
+    // layout as version 1, so only the version number has to be added.
+    |_| {},
+    // 1 -> 2: the loose injection options became the "Default" profile.
+    |value| {
+        let hachimi = if value["hachimi_enabled"].as_bool().unwrap_or(true) {
+            Value::from("Original")
+        } else if value["hachimi_edge_enabled"].as_bool().unwrap_or(false) {
+            Value::from("Edge")
+        } else {
+            Value::Null
+        };
+        let Some(object) = value.as_object_mut() else {
+            return;
+        };
+        let mut profile = serde_json::json!({
+            "name": profile::DEFAULT_PROFILE_NAME,
+            "hachimi": hachimi,
+        });
+        for key in ["game_version", "custom_dlls"] {
+            if let Some(old) = object.remove(key) {
+                profile[key] = old;
+            }
+        }
+        if let Some(auto_restart) = object.remove("auto_restart") {
+            profile["watch"] = serde_json::json!({ "auto_restart": auto_restart });
+        }
+        object.remove("hachimi_enabled");
+        object.remove("hachimi_edge_enabled");
+        value["profiles"] = Value::Array(vec![profile]);
+        value["active_profile"] = Value::from(profile::DEFAULT_PROFILE_NAME);
+    },
+    // 2 -> 3: profiles refer to game definitions by id instead of the
+    // hard-coded `GameVersion` enum.
+    |value| {
+        let Some(profiles) = value.get_mut("profiles").and_then(|p| p.as_array_mut()) else {
+            return;
+        };
+        for profile in profiles.iter_mut().filter_map(|p| p.as_object_mut()) {
+            if let Some(version) = profile.remove("game_version") {
+                let id = match version.as_str() {
+                    Some("Japanese") => "japanese",
+                    _ => "global",
+                };
+                profile.insert("game".to_string(), Value::from(id));
+            }
+        }
+    },
+    // 3 -> 4: custom DLLs became records with per-DLL settings.
+    |value| {
+        let Some(profiles) = value.get_mut("profiles").and_then(|p| p.as_array_mut()) else {
+            return;
+        };
+        for profile in profiles {
+            let Some(dlls) = profile
+                .get_mut("custom_dlls")
+                .and_then(|d| d.as_array_mut())
+            else {
+                continue;
+            };
+            for dll in dlls.iter_mut().filter(|dll| dll.is_string()) {
+                *dll = serde_json::json!({ "path": dll.take() });
+            }
+        }
+    },
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    pub profiles: Vec<Profile>,
+    pub active_profile: String,
+    pub window: WindowGeometry,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            profiles: vec![Profile::default()],
+            active_profile: profile::DEFAULT_PROFILE_NAME.to_string(),
+            window: WindowGeometry::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn active_profile_index(&self) -> usize {
+        self.profiles
+            .iter()
+            .position(|p| p.name == self.active_profile)
+            .unwrap_or(0)
+    }
+
+    pub fn active_profile(&self) -> &Profile {
+        &self.profiles[self.active_profile_index()]
+    }
+
+    pub fn active_profile_mut(&mut self) -> &mut Profile {
+        let index = self.active_profile_index();
+        &mut self.profiles[index]
+    }
+
+    /// Adds a profile under a name that isn't taken yet and makes it active.
+    pub fn add_profile(&mut self, mut profile: Profile) {
+        profile.name = profile::unique_name(&self.profiles, &profile.name);
+        self.active_profile = profile.name.clone();
+        self.profiles.push(profile);
+    }
+
+    pub fn rename_active_profile(&mut self, name: &str) {
+        if name == self.active_profile {
+            return;
+        }
+        let name = profile::unique_name(&self.profiles, name);
+        self.active_profile_mut().name = name.clone();
+        self.active_profile = name;
+    }
+
+    pub fn remove_active_profile(&mut self) {
+        let index = self.active_profile_index();
+        self.profiles.remove(index);
+        self.fix_up();
+        self.active_profile = self.profiles[index.saturating_sub(1)].name.clone();
+    }
+
+    /// Keeps the invariants the GUI relies on: there's always at least one
+    /// profile, names are unique and the active profile exists.
+    fn fix_up(&mut self) {
+        if self.profiles.is_empty() {
+            self.profiles.push(Profile::default());
+        }
+        for i in 1..self.profiles.len() {
+            let name = profile::unique_name(&self.profiles[..i], &self.profiles[i].name);
+            self.profiles[i].name = name;
+        }
+        if !self.profiles.iter().any(|p| p.name == self.active_profile) {
+            self.active_profile = self.profiles[0].name.clone();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct WindowGeometry {
+    pub size: Option<[f32; 2]>,
+    pub position: Option<[f32; 2]>,
+}
+
+pub struct SettingsStore {
+    path: PathBuf,
+    saved: Settings,
+    /// Schema of a file written by a newer version of Tamamo-X, it's backed
+    /// up before being overwritten with the older schema.
+    newer_version: Option<u32>,
+}
+
+impl SettingsStore {
+    /// Loads the settings from disk, falling back to the defaults if the file
+    /// doesn't exist or can't be read.
+    ///
+    /// The last value is a message meant for the user when something went
+    /// wrong, e.g. a corrupt file that has been moved aside.
+    pub fn load() -> (Self, Settings, Option<String>) {
+        Self::load_from(settings_path())
+    }
+
+    fn load_from(path: PathBuf) -> (Self, Settings, Option<String>) {
+        info!("Using settings file: {}", path.display());
+
+        let mut newer_version = None;
+        let (settings, notice) = match fs::read_to_string(&path) {
+            Ok(contents) => match parse_settings(&contents) {
+                Ok((settings, version)) if version > SETTINGS_VERSION => {
+                    newer_version = Some(version);
+                    let notice = format!(
+                        "Settings file was written by a newer version of Tamamo-X, it will be backed up to {} before saving",
+                        backup_path(&path, version).display()
+                    );
+                    (settings, Some(notice))
+                }
+                Ok((settings, _)) => (settings, None),
+                Err(e) => {
+                    error!("Failed to parse settings file: {}", e);
+                    let backup = path.with_extension("json.corrupt");
+                    let notice = match fs::rename(&path, &backup) {
+                        Ok(_) => format!(
+                            "Settings file was corrupt and has been reset (old file kept as {})",
+                            backup.display()
+                        ),
+                        Err(e) => format!("Settings file was corrupt and has been reset: {}", e),
+                    };
+                    (Settings::default(), Some(notice))
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Settings::default(), None),
+            Err(e) => {
+                error!("Failed to read settings file: {}", e);
+                (
+                    Settings::default(),
+                    Some(format!("Failed to read settings file: {}", e)),
+                )
+            }
+        };
+
+        let store = Self {
+            path,
+            saved: settings.clone(),
+            newer_version,
+        };
+        (store, settings, notice)
+    }
+
+    pub fn is_saved(&self, settings: &Settings) -> bool {
+        self.saved == *settings
+    }
+
+    /// Writes the settings to disk if they differ from the last saved copy.
+    /// A file from a newer version of Tamamo-X is backed up first, and left
+    /// alone if that fails.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), String> {
+        if self.is_saved(settings) {
+            return Ok(());
+        }
+        if let Some(version) = self.newer_version {
+            let backup = backup_path(&self.path, version);
+            match fs::copy(&self.path, &backup) {
+                Ok(_) => info!(
+                    "Backed up settings from schema {} to {}",
+                    version,
+                    backup.display()
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(format!(
+                        "Not saving over settings from a newer version of Tamamo-X, failed to back them up: {}",
+                        e
+                    ));
+                }
+            }
+            self.newer_version = None;
+        }
+        let json = serde_json::to_string_pretty(settings)
+            .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+        write_atomic(&self.path, json.as_bytes())
+            .map_err(|e| format!("Failed to write settings file: {}", e))?;
+        self.saved = settings.clone();
+        Ok(())
+    }
+}
+
+/// Parses and migrates a settings file, also returning the schema it was
+/// written with.
+fn parse_settings(contents: &str) -> Result<(Settings, u32), String> {
+    let mut value: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    if !value.is_object() {
+        return Err("expected a JSON object".to_string());
+    }
+
+    let version = value["version"].as_u64().unwrap_or(0) as u32;
+    if version > SETTINGS_VERSION {
+        warn!(
+            "Settings file was written by a newer version of Tamamo-X (schema {}), it will be backed up before saving",
+            version
+        );
+    }
+    migrate(&mut value, version);
+
+    let mut settings: Settings = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    settings.version = SETTINGS_VERSION;
+    settings.fix_up();
+    Ok((settings, version))
+}
+
+/// Where a settings file of a newer schema is kept before it's overwritten.
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    path.with_extension(format!("json.v{}.bak", version))
+}
+
+/// Runs the migrations from schema `version` to the current one.
+fn migrate(value: &mut Value, version: u32) {
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating settings from schema {} to {}", from, from + 1);
+        migration(value);
+        value["version"] = Value::from(from as u32 + 1);
+    }
+}
+
+/// Brings an exported profile up to date by migrating it as part of a
+/// settings file of the schema it was exported with. Exports without a
+/// version predate it, but profiles only exist since schema 2.
+pub fn migrate_profile(mut profile: Value) -> Value {
+    let version = profile
+        .as_object_mut()
+        .and_then(|p| p.remove("version"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(2) as u32;
+    let mut value = serde_json::json!({ "version": version, "profiles": [profile] });
+    migrate(&mut value, version);
+    value["profiles"][0].take()
+}
+
+/// Writes to a temporary file next to `path` first and then renames it over
+/// the target, so a crash mid-write never leaves a truncated settings file.
+fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn settings_path() -> PathBuf {
+    config_dir().join(SETTINGS_FILE_NAME)
+}
+
+/// Settings and user data live next to the executable in portable mode (when a
+/// `portable.txt` or a settings file is already present there), or in the user
+/// config directory otherwise.
+pub fn config_dir() -> PathBuf {
+    let exe_dir = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|p| p.to_path_buf()));
+
+    if let Some(exe_dir) = &exe_dir
+        && (exe_dir.join(PORTABLE_MARKER_FILE_NAME).exists()
+            || exe_dir.join(SETTINGS_FILE_NAME).exists())
+    {
+        return exe_dir.clone();
+    }
+
+    match dirs::config_dir() {
+        Some(dir) => dir.join("tamamo-x"),
+        None => exe_dir.unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::CustomDll;
+
+    #[test]
+    fn migrates_custom_dlls_to_records() {
+        let (settings, version) = parse_settings(
+            r#"{
+                "version": 3,
+                "profiles": [
+                    { "name": "Default", "custom_dlls": ["C:\\mods\\a.dll", "b.dll"] },
+                    { "name": "Empty" }
+                ],
+                "active_profile": "Default"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(version, 3);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(
+            settings.profiles[0].custom_dlls,
+            [
+                CustomDll::new(PathBuf::from("C:\\mods\\a.dll")),
+                CustomDll::new(PathBuf::from("b.dll")),
+            ]
+        );
+        assert!(settings.profiles[0].custom_dlls[0].enabled);
+        assert!(settings.profiles[1].custom_dlls.is_empty());
+    }
+
+    #[test]
+    fn migrates_unversioned_settings() {
+        let (settings, version) = parse_settings(
+            r#"{ "hachimi_enabled": false, "custom_dlls": ["a.dll"], "auto_restart": false }"#,
+        )
+        .unwrap();
+
+        assert_eq!(version, 0);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        let profile = settings.active_profile();
+        assert_eq!(profile.hachimi, None);
+        assert_eq!(
+            profile.custom_dlls,
+            [CustomDll::new(PathBuf::from("a.dll"))]
+        );
+        assert!(!profile.watch.auto_restart);
+    }
+
+    #[test]
+    fn migrates_game_versions_to_ids() {
+        let (settings, _) = parse_settings(
+            r#"{
+                "version": 2,
+                "profiles": [
+                    { "name": "JP", "game_version": "Japanese" },
+                    { "name": "Global", "game_version": "Global" }
+                ],
+                "active_profile": "JP"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.profiles[0].game, "japanese");
+        assert_eq!(settings.profiles[1].game, "global");
+    }
+
+    #[test]
+    fn backs_up_settings_of_newer_versions_before_saving() {
+        let dir = std::env::temp_dir().join(format!("tamamo-x-settings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SETTINGS_FILE_NAME);
+        let newer = r#"{ "version": 99, "profiles": [{ "name": "Default" }], "future": true }"#;
+        fs::write(&path, newer).unwrap();
+
+        let (mut store, mut settings, notice) = SettingsStore::load_from(path.clone());
+        assert!(notice.is_some());
+        settings.profiles.push(Profile {
+            name: "Other".to_string(),
+            ..Default::default()
+        });
+        store.save(&settings).unwrap();
+
+        assert_eq!(fs::read_to_string(backup_path(&path, 99)).unwrap(), newer);
+        let (_, saved, notice) = SettingsStore::load_from(path);
+        assert_eq!(notice, None);
+        assert_eq!(saved, settings);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
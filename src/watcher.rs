@@ -0,0 +1,2180 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::backend::{self, InjectionMethod, LoadedModule, ProcessBackend, ProcessEntry};
+use crate::dependencies::{self, RealFileSystem};
+use crate::duplicates::{self, Duplicate};
+use crate::error::{ErrorCode, InjectError};
+use crate::game::{DllSource, GameDefinition, INPUT_IDLE_TIMEOUT_MS, Readiness};
+use crate::github;
+use crate::profile::{
+    self, CrashAction, CustomDll, DllOrder, InitCall, LaunchOptions, Profile, WatchOptions,
+};
+
+/// How long a DLL waits for the module it depends on to be loaded.
+const MODULE_WAIT_TIMEOUT_MS: u32 = 60000;
+
+#[cfg(not(test))]
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+#[cfg(test)]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum WatcherState {
+    Downloading {
+        name: String,
+    },
+    /// Closing the running game before launching it again.
+    Restarting,
+    Launching,
+    WaitingForProcess,
+    WaitingForWindow {
+        pid: u32,
+    },
+    WaitingForModule {
+        pid: u32,
+        name: String,
+    },
+    WaitingForFile {
+        pid: u32,
+        path: PathBuf,
+    },
+    /// Waiting for the process to become idle, or for a fixed delay to pass.
+    WaitingForIdle {
+        pid: u32,
+    },
+    Injecting {
+        pid: u32,
+    },
+    Injected {
+        pid: u32,
+        succeeded: usize,
+        total: usize,
+    },
+    WaitingForExit {
+        pid: u32,
+    },
+    Exited {
+        pid: u32,
+    },
+    Stopped,
+    /// Auto-restart was paused because the game kept crashing after injecting.
+    CrashLoop {
+        crashes: u32,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl WatcherState {
+    /// The game instance this state is about, if any.
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            WatcherState::WaitingForWindow { pid }
+            | WatcherState::WaitingForModule { pid, .. }
+            | WatcherState::WaitingForFile { pid, .. }
+            | WatcherState::WaitingForIdle { pid }
+            | WatcherState::Injecting { pid }
+            | WatcherState::Injected { pid, .. }
+            | WatcherState::WaitingForExit { pid }
+            | WatcherState::Exited { pid } => Some(*pid),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WatcherState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatcherState::Downloading { name } => write!(f, "Downloading latest {}...", name),
+            WatcherState::Restarting => write!(f, "Closing the game to restart it..."),
+            WatcherState::Launching => write!(f, "Launching the game..."),
+            WatcherState::WaitingForProcess => write!(f, "Waiting for the game to start..."),
+            WatcherState::WaitingForWindow { pid } => {
+                write!(f, "Process found (PID {})! Waiting for window...", pid)
+            }
+            WatcherState::WaitingForModule { pid, name } => {
+                write!(f, "Waiting for process {} to load {}...", pid, name)
+            }
+            WatcherState::WaitingForFile { pid, path } => {
+                write!(
+                    f,
+                    "Waiting for {} to exist (PID {})...",
+                    path.display(),
+                    pid
+                )
+            }
+            WatcherState::WaitingForIdle { pid } => {
+                write!(f, "Waiting for process {} to become idle...", pid)
+            }
+            WatcherState::Injecting { pid } => write!(f, "Injecting into process {}...", pid),
+            WatcherState::Injected {
+                pid,
+                succeeded,
+                total,
+            } => write!(
+                f,
+                "Injected {}/{} DLLs into process {}",
+                succeeded, total, pid
+            ),
+            WatcherState::WaitingForExit { pid } => {
+                write!(f, "Injected. Waiting for process {} to exit...", pid)
+            }
+            WatcherState::Exited { pid } => write!(f, "Process {} exited", pid),
+            WatcherState::Stopped => write!(f, "Stopped"),
+            WatcherState::CrashLoop { crashes } => write!(
+                f,
+                "The game crashed {} times in a row right after injecting, auto-restart paused",
+                crashes
+            ),
+            WatcherState::Failed { reason } => write!(f, "Failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum WatcherEvent {
+    State(WatcherState),
+    /// A new instance of the game is running, its states follow.
+    ProcessFound(ProcessEntry),
+    /// One DLL was injected, or failed to be.
+    InjectionResult(DllResult),
+    /// Something went wrong, but the watcher keeps going.
+    Warning(String),
+    /// The game exited with an error or soon after being injected into.
+    Crashed(CrashReport),
+    /// Crash protection stopped injecting this DLL.
+    DllDisabled(PathBuf),
+    /// The watcher thread has returned, no more events will follow.
+    Finished(WatchOutcome),
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct DllResult {
+    pub pid: u32,
+    pub dll: PathBuf,
+    /// Where the DLL was loaded, set when the injection succeeded.
+    pub module: Option<LoadedModule>,
+    /// How the DLL was injected.
+    pub method: Option<InjectionMethod>,
+    /// Set when the DLL wasn't injected because it's already in the game.
+    pub skipped: Option<Duplicate>,
+    /// What the DLL's init export returned.
+    pub init_returned: Option<u32>,
+    pub error: Option<InjectError>,
+}
+
+impl fmt::Display for DllResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.dll.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(duplicate) = &self.skipped {
+            return write!(f, "{}: skipped, {}", name, duplicate);
+        }
+        match (&self.module, &self.error) {
+            (_, Some(e)) => write!(f, "{}: {}", name, e),
+            (Some(module), None) => {
+                write!(
+                    f,
+                    "{} at 0x{:016X} ({} KiB)",
+                    name,
+                    module.base,
+                    module.size / 1024
+                )?;
+                if let Some(method) = self.method {
+                    write!(f, " with {}", method)?;
+                }
+                match self.init_returned {
+                    Some(value) => write!(f, ", init returned {}", value),
+                    None => Ok(()),
+                }
+            }
+            (None, None) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct CrashReport {
+    pub pid: u32,
+    pub exit_code: Option<u32>,
+    /// Seconds between injecting and exiting.
+    pub uptime_secs: u64,
+    /// Whether the exit was within the crash protection window.
+    pub quick: bool,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Process {} exited", self.pid)?;
+        match self.exit_code {
+            // Exceptions such as access violations are NTSTATUS codes
+            Some(code) if code >= 0xC000_0000 => write!(f, " with {}", ErrorCode::NtStatus(code))?,
+            Some(code) => write!(f, " with code {}", code)?,
+            None => {}
+        }
+        write!(f, " {} s after injecting", self.uptime_secs)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum WatchOutcome {
+    Stopped,
+    CrashLoop { crashes: u32 },
+    Injected { succeeded: usize, total: usize },
+    Failed { reason: String },
+}
+
+/// Sends watcher events and wakes up whoever is listening, e.g. asks the GUI
+/// to repaint.
+pub struct EventSender {
+    tx: Sender<WatcherEvent>,
+    waker: Box<dyn Fn() + Send + Sync>,
+}
+
+impl EventSender {
+    fn send(&self, event: WatcherEvent) {
+        match &event {
+            WatcherEvent::State(state) => info!("{}", state),
+            WatcherEvent::ProcessFound(process) => {
+                info!("Found {} (PID {})", process.name, process.pid)
+            }
+            WatcherEvent::InjectionResult(result) if result.error.is_none() => {
+                info!("Injected {} from {}", result, result.dll.display())
+            }
+            WatcherEvent::InjectionResult(result) => warn!("Failed to inject {}", result),
+            WatcherEvent::Warning(warning) => warn!("{}", warning),
+            WatcherEvent::Crashed(report) => warn!("{}", report),
+            WatcherEvent::DllDisabled(dll) => {
+                warn!("Crash protection disabled {}", dll.display())
+            }
+            WatcherEvent::Finished(_) => {}
+        }
+        let _ = self.tx.send(event);
+        (self.waker)();
+    }
+
+    fn state(&self, state: WatcherState) {
+        self.send(WatcherEvent::State(state));
+    }
+}
+
+pub fn channel(waker: impl Fn() + Send + Sync + 'static) -> (EventSender, Receiver<WatcherEvent>) {
+    let (tx, rx) = mpsc::channel();
+    let sender = EventSender {
+        tx,
+        waker: Box::new(waker),
+    };
+    (sender, rx)
+}
+
+/// A watcher running on its own thread, it's stopped when this is dropped.
+pub struct WatcherHandle {
+    pub events: Receiver<WatcherEvent>,
+    stop: Sender<()>,
+}
+
+impl WatcherHandle {
+    pub fn stop(&self) {
+        let _ = self.stop.send(());
+    }
+}
+
+pub fn spawn<B: ProcessBackend + Send + Sync + 'static>(
+    backend: B,
+    profile: Profile,
+    game: GameDefinition,
+    restart: bool,
+    waker: impl Fn() + Send + Sync + 'static,
+) -> WatcherHandle {
+    let (events, rx) = channel(waker);
+    let (stop_tx, stop_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let outcome = run(&backend, &profile, &game, restart, &events, &stop_rx);
+        events.send(WatcherEvent::Finished(outcome));
+    });
+    WatcherHandle {
+        events: rx,
+        stop: stop_tx,
+    }
+}
+
+/// Waits for `timeout` or until the watcher is asked to stop, returns whether
+/// it should stop.
+fn should_stop(stop: &Receiver<()>, timeout: Duration) -> bool {
+    !matches!(stop.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+}
+
+/// Like `should_stop`, for the instance threads that only see a flag.
+fn sleep_or_stop(stopping: &AtomicBool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if stopping.load(Ordering::Relaxed) {
+            return true;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        thread::sleep(remaining.min(POLL_INTERVAL));
+    }
+}
+
+/// Polls `ready` until it returns true, `timeout` passes or the watcher stops.
+/// Returns `None` when stopping, otherwise whether `ready` returned true.
+fn poll_until(
+    stopping: &AtomicBool,
+    timeout: Option<Duration>,
+    mut ready: impl FnMut() -> bool,
+) -> Option<bool> {
+    let start = Instant::now();
+    loop {
+        if ready() {
+            return Some(true);
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return Some(false);
+        }
+        if sleep_or_stop(stopping, POLL_INTERVAL) {
+            return None;
+        }
+    }
+}
+
+/// Downloads the DLLs the profile needs, then waits for the game and injects
+/// into every instance of it, each handled on its own thread. New instances
+/// keep being picked up if `auto_restart` is set.
+///
+/// If launching is enabled in the profile, or `restart` is set, the game is
+/// first started suspended by the watcher, injected into and resumed. `restart`
+/// closes the running instances before that.
+///
+/// Returns once the instances found first are injected when `auto_restart` is
+/// off, or once something is sent on `stop` (or its sender is dropped).
+pub fn run<B: ProcessBackend + Sync>(
+    backend: &B,
+    profile: &Profile,
+    game: &GameDefinition,
+    restart: bool,
+    events: &EventSender,
+    stop: &Receiver<()>,
+) -> WatchOutcome {
+    let mut dlls = Vec::new();
+
+    let mut sources = game.default_dlls.clone();
+    if let Some(version) = profile.hachimi
+        && !sources.contains(&DllSource::Hachimi(version))
+    {
+        sources.push(DllSource::Hachimi(version));
+    }
+
+    for source in sources {
+        let version = match source {
+            DllSource::Hachimi(version) => version,
+            DllSource::Path(path) => {
+                dlls.push(CustomDll::new(path));
+                continue;
+            }
+        };
+        events.state(WatcherState::Downloading {
+            name: version.display_name().to_string(),
+        });
+        match github::hachimi_download_latest(version) {
+            Ok(path) => dlls.push(CustomDll::new(path)),
+            Err(e) => events.send(WatcherEvent::Warning(format!(
+                "Failed to download {}: {}\n\nInjection will continue without this DLL.",
+                version.display_name(),
+                e
+            ))),
+        }
+    }
+    // Only these can be disabled by crash protection
+    let first_custom_dll = dlls.len();
+    dlls.extend(
+        profile
+            .custom_dlls
+            .iter()
+            .filter(|dll| dll.enabled)
+            .cloned(),
+    );
+
+    if dlls.is_empty() {
+        let reason = "There are no DLLs to inject".to_string();
+        events.state(WatcherState::Failed {
+            reason: reason.clone(),
+        });
+        return WatchOutcome::Failed { reason };
+    }
+
+    // The game's loader names modules by full path, `..` is resolved too
+    for dll in &mut dlls {
+        match profile::absolute_dll_path(&dll.path) {
+            Ok(path) => dll.path = path,
+            Err(e) => {
+                let reason = format!("Failed to make {} absolute: {}", dll.path.display(), e);
+                events.state(WatcherState::Failed {
+                    reason: reason.clone(),
+                });
+                return WatchOutcome::Failed { reason };
+            }
+        }
+    }
+    // Crash protection disables the last DLL of the list, whatever the order
+    let mut list_order: Vec<_> = dlls[first_custom_dll..]
+        .iter()
+        .map(|d| d.path.clone())
+        .collect();
+    if profile.dll_order == DllOrder::Imports {
+        order_by_imports(&mut dlls[first_custom_dll..], events);
+    }
+    let mut plan = InjectionPlan {
+        dlls,
+        methods: profile.injection_methods.clone(),
+    };
+    let auto_restart = profile.watch.auto_restart;
+    let matcher = game.process_matcher();
+    let stopping = AtomicBool::new(false);
+
+    // Set when the game is launched instead of waited for
+    let executable = match &profile.launch.executable {
+        _ if !profile.launch.enabled && !restart => None,
+        Some(executable) => Some(executable.as_path()),
+        None => {
+            let reason = "No game executable is set to launch".to_string();
+            events.state(WatcherState::Failed {
+                reason: reason.clone(),
+            });
+            return WatchOutcome::Failed { reason };
+        }
+    };
+
+    // Instances are told apart by start time too, in case a PID is reused
+    let mut seen = HashSet::new();
+    if restart {
+        events.state(WatcherState::Restarting);
+        for process in backend.find_processes(&matcher) {
+            match backend.terminate(process.pid) {
+                // It may still be listed while exiting, it mustn't be injected
+                Ok(()) => {
+                    seen.insert((process.pid, process.start_time));
+                }
+                Err(e) => events.send(WatcherEvent::Warning(format!(
+                    "Failed to close process {}: {}",
+                    process.pid, e
+                ))),
+            }
+        }
+    }
+
+    thread::scope(|scope| {
+        let mut instances = Vec::new();
+
+        if let Some(executable) = executable {
+            events.state(WatcherState::Launching);
+            let (process, injected) = match launch_instance(
+                backend,
+                executable,
+                &profile.launch,
+                &plan,
+                events,
+                &stopping,
+            ) {
+                Ok(launched) => launched,
+                Err(reason) => {
+                    events.state(WatcherState::Failed {
+                        reason: reason.clone(),
+                    });
+                    return WatchOutcome::Failed { reason };
+                }
+            };
+            seen.insert((process.pid, process.start_time));
+            let (options, stopping) = (&profile.watch, &stopping);
+            instances.push(scope.spawn(move || {
+                let mut quick_crash = false;
+                if auto_restart && let Some(ph) = backend.open_process(process.pid) {
+                    quick_crash =
+                        wait_until_exit(backend, &ph, process.pid, events, stopping, options);
+                }
+                Some(InstanceOutcome {
+                    injected,
+                    quick_crash,
+                })
+            }));
+        } else {
+            events.state(WatcherState::WaitingForProcess);
+        }
+        let mut waiting = instances.is_empty();
+        let (mut succeeded, mut total) = (0, 0);
+        let mut crashes = 0;
+        let protection = &profile.watch.crash_protection;
+
+        let result = loop {
+            if auto_restart || seen.is_empty() {
+                for process in backend.find_processes(&matcher) {
+                    if !seen.insert((process.pid, process.start_time)) {
+                        continue;
+                    }
+                    events.send(WatcherEvent::ProcessFound(process.clone()));
+                    // Each instance gets its own list, crash protection may change it
+                    let (plan, stopping) = (plan.clone(), &stopping);
+                    instances.push(scope.spawn(move || {
+                        watch_instance(
+                            backend,
+                            game,
+                            &plan,
+                            &process,
+                            events,
+                            stopping,
+                            &profile.watch,
+                        )
+                    }));
+                }
+            }
+
+            for instance in instances.extract_if(.., |i| i.is_finished()) {
+                let Some(outcome) = instance.join().unwrap() else {
+                    continue;
+                };
+                succeeded += outcome.injected.0;
+                total += outcome.injected.1;
+                crashes = if outcome.quick_crash { crashes + 1 } else { 0 };
+            }
+            if protection.enabled && crashes >= protection.max_crashes.max(1) {
+                let disabled = match protection.action {
+                    CrashAction::DisableLastDll => list_order.pop().inspect(|path| {
+                        plan.dlls.retain(|dll| dll.path != *path);
+                    }),
+                    _ => None,
+                };
+                let Some(dll) = disabled else {
+                    events.state(WatcherState::CrashLoop { crashes });
+                    break Some(WatchOutcome::CrashLoop { crashes });
+                };
+                events.send(WatcherEvent::DllDisabled(dll));
+                crashes = 0;
+            }
+
+            let running = !instances.is_empty();
+            if !auto_restart && !seen.is_empty() && !running {
+                break None;
+            }
+            if !running && !waiting {
+                events.state(WatcherState::WaitingForProcess);
+            }
+            waiting = !running;
+
+            if should_stop(stop, POLL_INTERVAL) {
+                events.state(WatcherState::Stopped);
+                break Some(WatchOutcome::Stopped);
+            }
+        };
+
+        stopping.store(true, Ordering::Relaxed);
+        for instance in instances {
+            if let Some(outcome) = instance.join().unwrap() {
+                succeeded += outcome.injected.0;
+                total += outcome.injected.1;
+            }
+        }
+        result.unwrap_or(WatchOutcome::Injected { succeeded, total })
+    })
+}
+
+/// Moves DLLs after the others they import, or warns and leaves them in list
+/// order when they import each other.
+fn order_by_imports(dlls: &mut [CustomDll], events: &EventSender) {
+    let paths: Vec<_> = dlls.iter().map(|dll| dll.path.as_path()).collect();
+    match dependencies::import_order(&RealFileSystem, &paths) {
+        Ok(order) => {
+            let ordered: Vec<_> = order.into_iter().map(|i| dlls[i].clone()).collect();
+            dlls.clone_from_slice(&ordered);
+        }
+        Err(cycle) => events.send(WatcherEvent::Warning(format!(
+            "{}, they are injected in list order",
+            cycle
+        ))),
+    }
+}
+
+/// What to inject into each instance of the game, and how.
+#[derive(Clone)]
+struct InjectionPlan {
+    dlls: Vec<CustomDll>,
+    /// Tried in order until one works.
+    methods: Vec<InjectionMethod>,
+}
+
+/// What happened to an instance that was injected into.
+struct InstanceOutcome {
+    /// How many DLLs were injected out of how many.
+    injected: (usize, usize),
+    /// Whether it exited within the crash protection window.
+    quick_crash: bool,
+}
+
+/// Waits for one instance of the game to be ready and injects into it, then
+/// waits for it to exit if `auto_restart` is set.
+///
+/// Returns `None` if the instance couldn't be injected into at all.
+fn watch_instance<B: ProcessBackend>(
+    backend: &B,
+    game: &GameDefinition,
+    plan: &InjectionPlan,
+    process: &ProcessEntry,
+    events: &EventSender,
+    stopping: &AtomicBool,
+    options: &WatchOptions,
+) -> Option<InstanceOutcome> {
+    let pid = process.pid;
+    let Some(ph) = backend.open_process(pid) else {
+        events.send(WatcherEvent::Warning(format!(
+            "Failed to open process {}",
+            pid
+        )));
+        return None;
+    };
+
+    // 1. Wait until the game is ready
+    if !wait_until_ready(backend, game, &ph, process, events, stopping) {
+        return None;
+    }
+
+    // 2. Inject
+    let injected = inject_all(backend, &ph, pid, plan, None, events, stopping).ok()?;
+
+    // 3. Wait for process to exit
+    let mut quick_crash = false;
+    if options.auto_restart {
+        quick_crash = wait_until_exit(backend, &ph, pid, events, stopping, options);
+    }
+
+    Some(InstanceOutcome {
+        injected,
+        quick_crash,
+    })
+}
+
+/// Goes through the game's readiness conditions in order, returns false if
+/// one timed out or the watcher is stopping.
+fn wait_until_ready<B: ProcessBackend>(
+    backend: &B,
+    game: &GameDefinition,
+    ph: &B::Process,
+    process: &ProcessEntry,
+    events: &EventSender,
+    stopping: &AtomicBool,
+) -> bool {
+    let pid = process.pid;
+    let millis = |ms: &Option<u64>| ms.map(Duration::from_millis);
+    for condition in &game.readiness {
+        let (ready, what, timeout_ms) = match condition {
+            Readiness::Window { timeout_ms } => {
+                events.state(WatcherState::WaitingForWindow { pid });
+                let ready = poll_until(stopping, millis(timeout_ms), || {
+                    backend.has_window(
+                        ph,
+                        game.window_title.as_deref(),
+                        game.window_class.as_deref(),
+                    )
+                });
+                (ready, "show a window".to_string(), timeout_ms)
+            }
+            Readiness::ModuleLoaded { name, timeout_ms } => {
+                events.state(WatcherState::WaitingForModule {
+                    pid,
+                    name: name.clone(),
+                });
+                let ready = poll_until(stopping, millis(timeout_ms), || {
+                    backend.has_module(ph, name)
+                });
+                (ready, format!("load {}", name), timeout_ms)
+            }
+            Readiness::FileExists { path, timeout_ms } => {
+                let game_dir = process.path.as_ref().and_then(|p| p.parent());
+                let path = match game_dir {
+                    Some(dir) if path.is_relative() => dir.join(path),
+                    _ => path.clone(),
+                };
+                events.state(WatcherState::WaitingForFile {
+                    pid,
+                    path: path.clone(),
+                });
+                let ready = poll_until(stopping, millis(timeout_ms), || path.exists());
+                (ready, format!("create {}", path.display()), timeout_ms)
+            }
+            Readiness::InputIdle { timeout_ms } => {
+                events.state(WatcherState::WaitingForIdle { pid });
+                // Waited for in slices so stopping isn't held up, a busy game
+                // is injected into anyway
+                let slice = POLL_INTERVAL.as_millis() as u32;
+                let timeout_ms = timeout_ms.unwrap_or(INPUT_IDLE_TIMEOUT_MS);
+                let timeout = Some(Duration::from_millis(timeout_ms));
+                if poll_until(stopping, timeout, || backend.wait_for_input_idle(ph, slice))
+                    .is_none()
+                {
+                    return false;
+                }
+                continue;
+            }
+            Readiness::Delay { ms } => {
+                events.state(WatcherState::WaitingForIdle { pid });
+                if sleep_or_stop(stopping, Duration::from_millis(*ms)) {
+                    return false;
+                }
+                continue;
+            }
+        };
+        match ready {
+            Some(true) => {}
+            Some(false) => {
+                events.send(WatcherEvent::Warning(format!(
+                    "Process {} did not {} within {} ms, it won't be injected into",
+                    pid,
+                    what,
+                    timeout_ms.unwrap_or_default()
+                )));
+                return false;
+            }
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Starts the game suspended, injects into it and lets it run.
+///
+/// Returns the process and how many DLLs were injected out of how many.
+fn launch_instance<B: ProcessBackend>(
+    backend: &B,
+    executable: &Path,
+    options: &LaunchOptions,
+    plan: &InjectionPlan,
+    events: &EventSender,
+    stopping: &AtomicBool,
+) -> Result<(ProcessEntry, (usize, usize)), String> {
+    let (process, ph, main_thread) = backend.launch(executable, options)?;
+    events.send(WatcherEvent::ProcessFound(process.clone()));
+
+    // Nothing of the game has run yet, so there is nothing to wait for
+    let injected = inject_all(
+        backend,
+        &ph,
+        process.pid,
+        plan,
+        Some(&main_thread),
+        events,
+        stopping,
+    )?;
+    Ok((process, injected))
+}
+
+fn resume<B: ProcessBackend>(backend: &B, pid: u32, main_thread: &B::Thread) -> Result<(), String> {
+    backend.resume(main_thread).map_err(|e| {
+        // A game stuck suspended is of no use to anyone
+        let _ = backend.terminate(pid);
+        format!("Failed to resume process {}: {}", pid, e)
+    })
+}
+
+/// Injects every DLL into the process, returns how many were injected out of
+/// how many.
+///
+/// A process launched suspended is resumed through `main_thread` before a DLL
+/// waits for a module, which would never be loaded otherwise, before anything
+/// if it can't be injected into yet, or after the last DLL. Failing to resume
+/// it is the only error.
+fn inject_all<B: ProcessBackend>(
+    backend: &B,
+    ph: &B::Process,
+    pid: u32,
+    plan: &InjectionPlan,
+    mut main_thread: Option<&B::Thread>,
+    events: &EventSender,
+    stopping: &AtomicBool,
+) -> Result<(usize, usize), String> {
+    events.state(WatcherState::Injecting { pid });
+
+    // A 32-bit game has to start running to load the kernel32 used to inject
+    let mut dlls = &plan.dlls[..];
+    if !backend.can_inject(ph) {
+        if let Some(main_thread) = main_thread.take() {
+            resume(backend, pid, main_thread)?;
+        }
+        let timeout = Duration::from_millis(MODULE_WAIT_TIMEOUT_MS as u64);
+        if poll_until(stopping, Some(timeout), || backend.can_inject(ph)).is_none() {
+            dlls = &[];
+        }
+    }
+
+    let game_dir = backend.process_directory(ph);
+    let mut success_count = 0;
+    for dll in dlls {
+        if dll.delay_ms > 0 && sleep_or_stop(stopping, Duration::from_millis(dll.delay_ms)) {
+            break;
+        }
+
+        let mut loaded = true;
+        if let Some(name) = &dll.wait_for_module {
+            if let Some(main_thread) = main_thread.take() {
+                resume(backend, pid, main_thread)?;
+            }
+            let timeout = Duration::from_millis(MODULE_WAIT_TIMEOUT_MS as u64);
+            match poll_until(stopping, Some(timeout), || backend.has_module(ph, name)) {
+                Some(ready) => loaded = ready,
+                None => break,
+            }
+        }
+
+        let duplicate = duplicates::find(&backend.list_modules(ph), game_dir.as_deref(), &dll.path);
+        if let Some(duplicate) = duplicate {
+            if duplicate.skips() {
+                success_count += 1;
+                events.send(WatcherEvent::InjectionResult(DllResult {
+                    pid,
+                    dll: dll.path.clone(),
+                    module: None,
+                    method: None,
+                    skipped: Some(duplicate),
+                    init_returned: None,
+                    error: None,
+                }));
+                continue;
+            }
+            events.send(WatcherEvent::Warning(format!(
+                "Injecting {} into process {} anyway, {}",
+                dll.path.display(),
+                pid,
+                duplicate
+            )));
+        }
+
+        let result = if loaded {
+            inject_with_retries(backend, ph, dll, &plan.methods, stopping)
+        } else {
+            Err(InjectError::ModuleWaitTimeout {
+                name: dll.wait_for_module.clone().unwrap_or_default(),
+                timeout_ms: MODULE_WAIT_TIMEOUT_MS,
+            })
+        };
+        let (module, method, init_returned, error) = match result {
+            Ok((module, method)) => match &dll.init {
+                Some(init) => match call_init(backend, ph, &module, init, &plan.methods) {
+                    Ok(value) => (Some(module), Some(method), Some(value), None),
+                    Err(e) => (Some(module), Some(method), None, Some(e)),
+                },
+                None => (Some(module), Some(method), None, None),
+            },
+            Err(e) => (None, None, None, Some(e)),
+        };
+        if error.is_none() {
+            success_count += 1;
+        }
+        events.send(WatcherEvent::InjectionResult(DllResult {
+            pid,
+            dll: dll.path.clone(),
+            module,
+            method,
+            skipped: None,
+            init_returned,
+            error,
+        }));
+    }
+
+    if let Some(main_thread) = main_thread {
+        resume(backend, pid, main_thread)?;
+    }
+
+    events.state(WatcherState::Injected {
+        pid,
+        succeeded: success_count,
+        total: plan.dlls.len(),
+    });
+    Ok((success_count, plan.dlls.len()))
+}
+
+/// Calls the init export of a DLL that was just injected, with the same
+/// methods as injecting it.
+fn call_init<B: ProcessBackend>(
+    backend: &B,
+    ph: &B::Process,
+    module: &LoadedModule,
+    init: &InitCall,
+    methods: &[InjectionMethod],
+) -> Result<u32, InjectError> {
+    let argument = init.argument_blob().map_err(InjectError::InvalidDll)?;
+    let value = backend.call_export(ph, module, &init.export, argument.as_deref(), methods)?;
+    info!("{} returned {}", init.export, value);
+    Ok(value)
+}
+
+fn inject_with_retries<B: ProcessBackend>(
+    backend: &B,
+    ph: &B::Process,
+    dll: &CustomDll,
+    methods: &[InjectionMethod],
+    stopping: &AtomicBool,
+) -> Result<(LoadedModule, InjectionMethod), InjectError> {
+    let inject = || {
+        backend::inject_with_fallback(methods, |method| {
+            backend.inject(ph, &dll.path, method, &dll.load)
+        })
+    };
+    let mut result = inject();
+    for attempt in 1..=dll.retries {
+        match &result {
+            // Trying again won't make an invalid DLL valid
+            Ok(_) | Err(InjectError::InvalidDll(_)) => break,
+            Err(e) => info!(
+                "Retrying {} ({}/{}) after: {}",
+                dll.path.display(),
+                attempt,
+                dll.retries,
+                e
+            ),
+        }
+        if sleep_or_stop(stopping, POLL_INTERVAL) {
+            break;
+        }
+        result = inject();
+    }
+    result
+}
+
+/// Waits for the injected process to exit and reports it if it crashed.
+/// Returns whether it crashed within the crash protection window, a clean
+/// exit such as the player quitting never counts.
+fn wait_until_exit<B: ProcessBackend>(
+    backend: &B,
+    ph: &B::Process,
+    pid: u32,
+    events: &EventSender,
+    stopping: &AtomicBool,
+    options: &WatchOptions,
+) -> bool {
+    let injected_at = Instant::now();
+    events.state(WatcherState::WaitingForExit { pid });
+    while !backend.wait_for_exit(ph, POLL_INTERVAL.as_millis() as u32) {
+        if stopping.load(Ordering::Relaxed) {
+            return false;
+        }
+    }
+    events.state(WatcherState::Exited { pid });
+
+    let uptime = injected_at.elapsed();
+    let exit_code = backend.exit_code(ph);
+    let quick = uptime < Duration::from_secs(options.crash_protection.window_secs);
+    let crashed = exit_code.is_some_and(|code| code != 0);
+    if crashed {
+        events.send(WatcherEvent::Crashed(CrashReport {
+            pid,
+            exit_code,
+            uptime_secs: uptime.as_secs(),
+            quick,
+        }));
+    }
+    crashed && quick
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SearchPath;
+    use crate::backend::mock::{MockBackend, MockEvent, MockProcess};
+    use crate::error::ErrorCode;
+    use crate::pe::fixtures::dll_with_imports;
+    use crate::profile::{ArgumentFormat, CrashProtection, LaunchOptions, WatchOptions};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn test_game() -> GameDefinition {
+        GameDefinition {
+            id: "test".to_string(),
+            name: "Test".to_string(),
+            process_names: vec!["game.exe".to_string()],
+            matcher: None,
+            window_title: None,
+            window_class: None,
+            default_dlls: Vec::new(),
+            readiness: vec![
+                Readiness::Window { timeout_ms: None },
+                Readiness::InputIdle {
+                    timeout_ms: Some(0),
+                },
+            ],
+        }
+    }
+
+    fn test_profile(dlls: &[PathBuf], auto_restart: bool) -> Profile {
+        Profile {
+            game: "test".to_string(),
+            hachimi: None,
+            custom_dlls: dlls.iter().cloned().map(CustomDll::new).collect(),
+            watch: WatchOptions {
+                auto_restart,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn dll(name: &str) -> PathBuf {
+        std::env::temp_dir().join(name)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tamamo-x-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn states(events: &Receiver<WatcherEvent>) -> Vec<WatcherState> {
+        events
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::State(state) => Some(state),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Runs the watcher on another thread, returns the stop sender, the event
+    /// receiver and the thread.
+    fn start(
+        backend: Arc<MockBackend>,
+        profile: Profile,
+    ) -> (
+        Sender<()>,
+        Receiver<WatcherEvent>,
+        thread::JoinHandle<WatchOutcome>,
+    ) {
+        let (events, rx) = channel(|| {});
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let handle =
+            thread::spawn(move || run(&*backend, &profile, &test_game(), false, &events, &stop_rx));
+        (stop_tx, rx, handle)
+    }
+
+    fn events_of(backend: &MockBackend, pid: u32) -> Vec<MockEvent> {
+        backend
+            .events()
+            .into_iter()
+            .filter(|event| match event {
+                MockEvent::Found(p)
+                | MockEvent::WindowShown(p)
+                | MockEvent::InputIdle(p)
+                | MockEvent::Injected(p, _)
+                | MockEvent::InjectionFailed(p, _)
+                | MockEvent::Called(p, _, _)
+                | MockEvent::Ejected(p, _)
+                | MockEvent::Exited(p)
+                | MockEvent::Launched(p)
+                | MockEvent::Resumed(p)
+                | MockEvent::Terminated(p) => *p == pid,
+            })
+            .collect()
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn injects_and_rewatches_after_exit() {
+        let (a, b) = (dll("a.dll"), dll("b.dll"));
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe")
+                .starts_after(3)
+                .window_after(2)
+                .exits_after(2),
+            MockProcess::new(2, "game.exe").starts_after(5),
+        ]));
+        let profile = test_profile(&[a.clone(), b.clone()], true);
+
+        let (stop, _events, handle) = start(backend.clone(), profile);
+        wait_until(|| backend.is_finished());
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        for pid in [1, 2] {
+            assert_eq!(
+                events_of(&backend, pid),
+                [
+                    MockEvent::Found(pid),
+                    MockEvent::WindowShown(pid),
+                    MockEvent::InputIdle(pid),
+                    MockEvent::Injected(pid, a.clone()),
+                    MockEvent::Injected(pid, b.clone()),
+                    MockEvent::Exited(pid),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn injects_into_every_instance() {
+        let a = dll("a.dll");
+        let backend = MockBackend::new([
+            MockProcess::new(1, "game.exe"),
+            MockProcess::new(2, "launcher.exe"),
+            MockProcess::new(3, "game.exe").window_after(5),
+        ]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(
+            &backend,
+            &test_profile(std::slice::from_ref(&a), false),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 2,
+                total: 2
+            }
+        );
+        let found: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::ProcessFound(process) => Some(process.pid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(found, [1, 3]);
+        assert!(events_of(&backend, 1).contains(&MockEvent::Injected(1, a.clone())));
+        assert!(events_of(&backend, 3).contains(&MockEvent::Injected(3, a)));
+        assert!(events_of(&backend, 2).is_empty());
+    }
+
+    #[test]
+    fn picks_up_instances_started_later() {
+        let a = dll("a.dll");
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe").exits_after(u32::MAX),
+            MockProcess::new(2, "game.exe").starts_after(10),
+        ]));
+
+        let (stop, events, handle) = start(
+            backend.clone(),
+            test_profile(std::slice::from_ref(&a), true),
+        );
+        wait_until(|| {
+            backend
+                .events()
+                .contains(&MockEvent::Injected(2, a.clone()))
+        });
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert!(backend.events().contains(&MockEvent::Injected(1, a)));
+        assert!(!backend.events().contains(&MockEvent::Exited(1)));
+        let states = states(&events);
+        assert!(states.contains(&WatcherState::WaitingForExit { pid: 1 }));
+        assert_eq!(states.last(), Some(&WatcherState::Stopped));
+    }
+
+    #[test]
+    fn returns_after_first_injection_without_auto_restart() {
+        let (a, b) = (dll("a.dll"), dll("b.dll"));
+        let backend = MockBackend::new([MockProcess::new(7, "game.exe").failing_dll(&b)]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(
+            &backend,
+            &test_profile(&[a.clone(), b.clone()], false),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 2
+            }
+        );
+        assert_eq!(
+            states(&rx),
+            [
+                WatcherState::WaitingForProcess,
+                WatcherState::WaitingForWindow { pid: 7 },
+                WatcherState::WaitingForIdle { pid: 7 },
+                WatcherState::Injecting { pid: 7 },
+                WatcherState::Injected {
+                    pid: 7,
+                    succeeded: 1,
+                    total: 2
+                },
+            ]
+        );
+        assert_eq!(
+            backend.events(),
+            [
+                MockEvent::Found(7),
+                MockEvent::WindowShown(7),
+                MockEvent::InputIdle(7),
+                MockEvent::Injected(7, a),
+                MockEvent::InjectionFailed(7, b),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_result_of_each_dll() {
+        let (a, b) = (dll("a.dll"), dll("b.dll"));
+        let backend = MockBackend::new([MockProcess::new(9, "game.exe").failing_dll(&b)]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        run(
+            &backend,
+            &test_profile(&[a.clone(), b.clone()], false),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        let results: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => Some(result),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results.len(), 2);
+        let module = results[0].module.as_ref().unwrap();
+        assert_eq!(module.path, a);
+        assert!(module.base > u32::MAX as u64);
+        assert_eq!(results[0].error, None);
+        assert_eq!(
+            results[1],
+            DllResult {
+                pid: 9,
+                dll: b,
+                module: None,
+                method: None,
+                skipped: None,
+                init_returned: None,
+                error: Some(InjectError::LoadLibraryFailed(ErrorCode::Win32(1114)))
+            }
+        );
+        assert_eq!(
+            results[1].to_string(),
+            "b.dll: LoadLibraryExW failed in the game (1114 ERROR_DLL_INIT_FAILED: A DLL failed to initialize)"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_paths_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let a = std::env::temp_dir().join(OsStr::from_bytes(b"\xff\xfe.dll"));
+        let backend = MockBackend::new([MockProcess::new(8, "game.exe")]);
+        let (events, _rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        run(
+            &backend,
+            &test_profile(std::slice::from_ref(&a), false),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        assert!(backend.events().contains(&MockEvent::Injected(8, a)));
+    }
+
+    #[test]
+    fn only_matches_the_game_process() {
+        let backend = Arc::new(MockBackend::new([MockProcess::new(3, "other.exe")]));
+
+        let (stop, events, handle) = start(backend.clone(), test_profile(&[dll("a.dll")], true));
+        thread::sleep(Duration::from_millis(50));
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert!(backend.events().is_empty());
+        assert_eq!(
+            states(&events),
+            [WatcherState::WaitingForProcess, WatcherState::Stopped]
+        );
+    }
+
+    #[test]
+    fn stops_while_waiting_for_window() {
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(4, "game.exe").window_after(u32::MAX)
+        ]));
+
+        let (stop, events, handle) = start(backend.clone(), test_profile(&[dll("a.dll")], true));
+        wait_until(|| backend.events() == [MockEvent::Found(4)]);
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert_eq!(backend.events(), [MockEvent::Found(4)]);
+        assert_eq!(states(&events).last(), Some(&WatcherState::Stopped));
+    }
+
+    #[test]
+    fn fails_without_dlls() {
+        let backend = MockBackend::new([MockProcess::new(5, "game.exe")]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(
+            &backend,
+            &test_profile(&[], true),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        let reason = "There are no DLLs to inject".to_string();
+        assert_eq!(
+            outcome,
+            WatchOutcome::Failed {
+                reason: reason.clone()
+            }
+        );
+        assert_eq!(states(&rx), [WatcherState::Failed { reason }]);
+        assert!(backend.events().is_empty());
+    }
+
+    #[test]
+    fn waits_for_modules_and_files() {
+        let a = dll("a.dll");
+        let dir = temp_dir("ready");
+        let marker = dir.join("marker");
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe").module_after("GameAssembly.dll", 3)
+        ]));
+        let mut game = test_game();
+        game.readiness = vec![
+            Readiness::ModuleLoaded {
+                name: "gameassembly.dll".to_string(),
+                timeout_ms: Some(5000),
+            },
+            Readiness::FileExists {
+                path: marker.clone(),
+                timeout_ms: None,
+            },
+        ];
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+        let profile = test_profile(std::slice::from_ref(&a), false);
+
+        let watcher = {
+            let backend = backend.clone();
+            thread::spawn(move || run(&*backend, &profile, &game, false, &events, &stop_rx))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(
+            backend
+                .events()
+                .iter()
+                .all(|e| !matches!(e, MockEvent::Injected(..)))
+        );
+        std::fs::write(&marker, b"").unwrap();
+
+        assert_eq!(
+            watcher.join().unwrap(),
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 1
+            }
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(
+            states(&rx)[..3],
+            [
+                WatcherState::WaitingForProcess,
+                WatcherState::WaitingForModule {
+                    pid: 1,
+                    name: "gameassembly.dll".to_string()
+                },
+                WatcherState::WaitingForFile {
+                    pid: 1,
+                    path: marker
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_instances_whose_conditions_time_out() {
+        let backend = MockBackend::new([MockProcess::new(1, "game.exe")]);
+        let mut game = test_game();
+        game.readiness = vec![Readiness::ModuleLoaded {
+            name: "UnityPlayer.dll".to_string(),
+            timeout_ms: Some(20),
+        }];
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(
+            &backend,
+            &test_profile(&[dll("a.dll")], false),
+            &game,
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 0,
+                total: 0
+            }
+        );
+        assert_eq!(backend.events(), [MockEvent::Found(1)]);
+        let warnings: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::Warning(warning) => Some(warning),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            warnings,
+            ["Process 1 did not load UnityPlayer.dll within 20 ms, it won't be injected into"]
+        );
+    }
+
+    #[test]
+    fn applies_per_dll_settings() {
+        let (a, b, c) = (dll("a.dll"), dll("b.dll"), dll("c.dll"));
+        let backend = MockBackend::new([MockProcess::new(1, "game.exe")
+            .flaky_dll(&a, 2)
+            .flaky_dll(&c, 5)
+            .module_after("GameAssembly.dll", 2)]);
+        let mut profile = test_profile(&[], false);
+        profile.custom_dlls = vec![
+            CustomDll {
+                retries: 2,
+                ..CustomDll::new(a.clone())
+            },
+            CustomDll {
+                enabled: false,
+                ..CustomDll::new(b.clone())
+            },
+            CustomDll {
+                retries: 1,
+                delay_ms: 5,
+                wait_for_module: Some("GameAssembly.dll".to_string()),
+                ..CustomDll::new(c.clone())
+            },
+        ];
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        // Disabled DLLs don't count
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 2
+            }
+        );
+        assert_eq!(
+            backend.events()[3..],
+            [
+                MockEvent::InjectionFailed(1, a.clone()),
+                MockEvent::InjectionFailed(1, a.clone()),
+                MockEvent::Injected(1, a.clone()),
+                MockEvent::InjectionFailed(1, c.clone()),
+                MockEvent::InjectionFailed(1, c.clone()),
+            ]
+        );
+        let results: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => Some((result.dll, result.error.is_none())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results, [(a, true), (c, false)]);
+    }
+
+    #[test]
+    fn reports_modules_that_never_load() {
+        let a = dll("a.dll");
+        let backend = MockBackend::new([MockProcess::new(1, "game.exe")]);
+        let mut profile = test_profile(&[], false);
+        profile.custom_dlls = vec![CustomDll {
+            wait_for_module: Some("missing.dll".to_string()),
+            ..CustomDll::new(a.clone())
+        }];
+        let (events, rx) = channel(|| {});
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let watcher =
+            thread::spawn(move || run(&backend, &profile, &test_game(), false, &events, &stop_rx));
+        thread::sleep(Duration::from_millis(50));
+        stop_tx.send(()).unwrap();
+
+        // Stopping while waiting doesn't report the DLL as failed
+        assert_eq!(watcher.join().unwrap(), WatchOutcome::Stopped);
+        assert!(
+            rx.try_iter()
+                .all(|e| !matches!(e, WatcherEvent::InjectionResult(_)))
+        );
+    }
+
+    #[test]
+    fn resumes_launched_game_before_waiting_for_a_module() {
+        let (a, b) = (dll("a.dll"), dll("b.dll"));
+        let backend = MockBackend::new([])
+            .launching([MockProcess::new(1, "game.exe").module_after("UnityPlayer.dll", 1)]);
+        let mut profile = launch_profile(std::slice::from_ref(&a), false);
+        profile.custom_dlls.push(CustomDll {
+            wait_for_module: Some("UnityPlayer.dll".to_string()),
+            ..CustomDll::new(b.clone())
+        });
+        let (events, _rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 2,
+                total: 2
+            }
+        );
+        assert_eq!(
+            backend.events(),
+            [
+                MockEvent::Launched(1),
+                MockEvent::Injected(1, a),
+                MockEvent::Resumed(1),
+                MockEvent::Injected(1, b),
+            ]
+        );
+    }
+
+    #[test]
+    fn resumes_launched_game_that_cant_be_injected_yet() {
+        let a = dll("a.dll");
+        let backend =
+            MockBackend::new([]).launching([MockProcess::new(1, "game.exe").injectable_after(2)]);
+        let profile = launch_profile(std::slice::from_ref(&a), false);
+        let (events, _rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 1
+            }
+        );
+        assert_eq!(
+            backend.events(),
+            [
+                MockEvent::Launched(1),
+                MockEvent::Resumed(1),
+                MockEvent::Injected(1, a)
+            ]
+        );
+    }
+
+    fn launch_profile(dlls: &[PathBuf], auto_restart: bool) -> Profile {
+        let mut profile = test_profile(dlls, auto_restart);
+        profile.launch = LaunchOptions {
+            enabled: true,
+            executable: Some(PathBuf::from("C:/Game/game.exe")),
+            ..Default::default()
+        };
+        profile
+    }
+
+    #[test]
+    fn launches_injects_then_resumes() {
+        let a = dll("a.dll");
+        let backend = Arc::new(
+            MockBackend::new([MockProcess::new(2, "game.exe").starts_after(5)])
+                .launching([MockProcess::new(1, "game.exe").exits_after(2)]),
+        );
+
+        let (stop, rx, handle) = start(
+            backend.clone(),
+            launch_profile(std::slice::from_ref(&a), true),
+        );
+        wait_until(|| backend.is_finished());
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        // Readiness is skipped, the game can't get anywhere while suspended
+        assert_eq!(
+            events_of(&backend, 1),
+            [
+                MockEvent::Launched(1),
+                MockEvent::Injected(1, a.clone()),
+                MockEvent::Resumed(1),
+                MockEvent::Exited(1),
+            ]
+        );
+        // Instances started later are still watched for
+        assert_eq!(events_of(&backend, 2)[0], MockEvent::Found(2));
+        assert_eq!(
+            states(&rx)[..2],
+            [WatcherState::Launching, WatcherState::Injecting { pid: 1 }]
+        );
+    }
+
+    #[test]
+    fn restart_closes_the_game_before_launching() {
+        let a = dll("a.dll");
+        let backend = MockBackend::new([MockProcess::new(1, "game.exe")])
+            .launching([MockProcess::new(2, "game.exe")]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        // Launching doesn't have to be enabled in the profile to restart
+        let mut profile = launch_profile(std::slice::from_ref(&a), false);
+        profile.launch.enabled = false;
+        let outcome = run(&backend, &profile, &test_game(), true, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 1
+            }
+        );
+        assert_eq!(
+            backend.events(),
+            [
+                MockEvent::Found(1),
+                MockEvent::Terminated(1),
+                MockEvent::Launched(2),
+                MockEvent::Injected(2, a),
+                MockEvent::Resumed(2),
+            ]
+        );
+        assert_eq!(states(&rx)[0], WatcherState::Restarting);
+    }
+
+    #[test]
+    fn restart_doesnt_inject_closed_instances_still_exiting() {
+        let a = dll("a.dll");
+        let backend = Arc::new(
+            MockBackend::new([
+                MockProcess::new(1, "game.exe").lingers(),
+                MockProcess::new(3, "game.exe")
+                    .starts_after(2)
+                    .exits_after(u32::MAX),
+            ])
+            .launching([MockProcess::new(2, "game.exe")]),
+        );
+        let (events, rx) = channel(|| {});
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let profile = launch_profile(std::slice::from_ref(&a), true);
+
+        let handle = {
+            let backend = backend.clone();
+            thread::spawn(move || run(&*backend, &profile, &test_game(), true, &events, &stop_rx))
+        };
+        wait_until(|| {
+            backend
+                .events()
+                .contains(&MockEvent::Injected(3, a.clone()))
+        });
+        stop_tx.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        let found: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::ProcessFound(process) => Some(process.pid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(found, [2, 3]);
+        assert!(!backend.events().contains(&MockEvent::Injected(1, a)));
+    }
+
+    #[test]
+    fn launch_fails_without_executable() {
+        let backend = MockBackend::new([]).launching([MockProcess::new(1, "game.exe")]);
+        let (events, _rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+        let mut profile = launch_profile(&[dll("a.dll")], true);
+        profile.launch.executable = None;
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert!(matches!(outcome, WatchOutcome::Failed { .. }));
+        assert!(backend.events().is_empty());
+    }
+
+    #[test]
+    fn spawned_watcher_stops_when_handle_is_dropped() {
+        let (woken_tx, woken_rx) = mpsc::channel();
+        let handle = spawn(
+            MockBackend::new([]),
+            test_profile(&[dll("a.dll")], true),
+            test_game(),
+            false,
+            move || {
+                let _ = woken_tx.send(());
+            },
+        );
+        woken_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let WatcherHandle { events, stop } = handle;
+        drop(stop);
+
+        let finished = events.iter().find_map(|event| match event {
+            WatcherEvent::Finished(outcome) => Some(outcome),
+            _ => None,
+        });
+        assert_eq!(finished, Some(WatchOutcome::Stopped));
+    }
+
+    fn crashes(events: &Receiver<WatcherEvent>) -> Vec<WatcherEvent> {
+        events
+            .try_iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    WatcherEvent::Crashed(_) | WatcherEvent::DllDisabled(_)
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pauses_auto_restart_after_quick_crashes() {
+        let a = dll("a.dll");
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe").exit_code(0xC000_0005),
+            MockProcess::new(2, "game.exe")
+                .starts_after(50)
+                .exit_code(1),
+            MockProcess::new(3, "game.exe")
+                .starts_after(50)
+                .exit_code(1),
+            MockProcess::new(4, "game.exe").starts_after(50),
+        ]));
+        let mut profile = test_profile(std::slice::from_ref(&a), true);
+        profile.watch.crash_protection.max_crashes = 3;
+
+        let (_stop, events, handle) = start(backend.clone(), profile);
+
+        assert_eq!(
+            handle.join().unwrap(),
+            WatchOutcome::CrashLoop { crashes: 3 }
+        );
+        assert!(!backend.events().contains(&MockEvent::Found(4)));
+        let crashes: Vec<_> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::Crashed(report) => Some(report),
+                WatcherEvent::State(WatcherState::CrashLoop { crashes }) => {
+                    assert_eq!(crashes, 3);
+                    None
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(crashes.len(), 3);
+        assert_eq!(crashes[0].exit_code, Some(0xC000_0005));
+        assert!(crashes.iter().all(|c| c.quick));
+    }
+
+    #[test]
+    fn clean_exits_after_the_window_are_not_crashes() {
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe"),
+            MockProcess::new(2, "game.exe").starts_after(50),
+        ]));
+        let mut profile = test_profile(&[dll("a.dll")], true);
+        profile.watch.crash_protection = CrashProtection {
+            window_secs: 0,
+            max_crashes: 1,
+            ..Default::default()
+        };
+
+        let (stop, events, handle) = start(backend.clone(), profile);
+        wait_until(|| backend.is_finished());
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert!(crashes(&events).is_empty());
+    }
+
+    #[test]
+    fn quick_clean_exits_are_not_crashes() {
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe"),
+            MockProcess::new(2, "game.exe").starts_after(50),
+        ]));
+        let mut profile = test_profile(&[dll("a.dll")], true);
+        profile.watch.crash_protection.max_crashes = 1;
+
+        let (stop, events, handle) = start(backend.clone(), profile);
+        wait_until(|| backend.is_finished());
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert!(crashes(&events).is_empty());
+    }
+
+    #[test]
+    fn disables_the_last_dll_after_quick_crashes() {
+        // The helper is injected before the plugin importing it, but it's
+        // last in the list
+        let dir = temp_dir("crash");
+        let (a, b) = (dir.join("helper.dll"), dir.join("plugin.dll"));
+        std::fs::write(&a, dll_with_imports(&["KERNEL32.dll"], &[])).unwrap();
+        std::fs::write(&b, dll_with_imports(&["helper.dll"], &[])).unwrap();
+        let backend = Arc::new(MockBackend::new([
+            MockProcess::new(1, "game.exe").exit_code(0xC000_0005),
+            MockProcess::new(2, "game.exe")
+                .starts_after(50)
+                .exit_code(0xC000_0005),
+            MockProcess::new(3, "game.exe")
+                .starts_after(50)
+                .exits_after(u32::MAX),
+        ]));
+        let mut profile = test_profile(&[b.clone(), a.clone()], true);
+        profile.watch.crash_protection = CrashProtection {
+            max_crashes: 2,
+            action: CrashAction::DisableLastDll,
+            ..Default::default()
+        };
+
+        let (stop, events, handle) = start(backend.clone(), profile);
+        wait_until(|| {
+            backend
+                .events()
+                .contains(&MockEvent::Injected(3, b.clone()))
+        });
+        stop.send(()).unwrap();
+
+        assert_eq!(handle.join().unwrap(), WatchOutcome::Stopped);
+        assert!(
+            !backend
+                .events()
+                .contains(&MockEvent::Injected(3, a.clone()))
+        );
+        assert_eq!(
+            crashes(&events).last(),
+            Some(&WatcherEvent::DllDisabled(a.clone()))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_dlls_already_in_the_game() {
+        let dir = std::env::temp_dir().join(format!("tamamo-x-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("winhttp.dll"), b"MZ hachimi").unwrap();
+        let (a, b, hachimi) = (dll("a.dll"), dll("b.dll"), dll("hachimi.dll"));
+        let backend = MockBackend::new([MockProcess::new(4, "game.exe")
+            .in_directory(&dir)
+            .loaded_dll(&a)]);
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(
+            &backend,
+            &test_profile(&[hachimi.clone(), a.clone(), b.clone()], false),
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 3,
+                total: 3
+            }
+        );
+        let skipped: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => Some((result.dll, result.skipped)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            skipped[0],
+            (
+                hachimi,
+                Some(Duplicate::HachimiProxy {
+                    path: dir.join("winhttp.dll")
+                })
+            )
+        );
+        assert!(matches!(&skipped[1], (dll, Some(Duplicate::Loaded { .. })) if *dll == a));
+        assert_eq!(skipped[2], (b.clone(), None));
+        let injected: Vec<_> = backend
+            .events()
+            .into_iter()
+            .filter(|e| matches!(e, MockEvent::Injected(..)))
+            .collect();
+        assert_eq!(injected, [MockEvent::Injected(4, b)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn calls_init_exports_after_injecting() {
+        let (a, b) = (dll("a.dll"), dll("b.dll"));
+        let backend = MockBackend::new([MockProcess::new(5, "game.exe").export("Init", 7)]);
+        let mut profile = test_profile(&[a.clone(), b.clone()], false);
+        profile.custom_dlls[0].init = Some(InitCall {
+            export: "Init".to_string(),
+            argument: "{ \"lang\": \"en\" }".to_string(),
+            format: ArgumentFormat::Json,
+        });
+        profile.custom_dlls[1].init = Some(InitCall {
+            export: "Setup".to_string(),
+            ..Default::default()
+        });
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 2
+            }
+        );
+        let results: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => Some((result.init_returned, result.error)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (Some(7), None),
+                (
+                    None,
+                    Some(InjectError::ExportNotFound {
+                        name: "Setup".to_string()
+                    })
+                ),
+            ]
+        );
+        let calls: Vec<_> = backend
+            .events()
+            .into_iter()
+            .filter(|e| matches!(e, MockEvent::Called(..)))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                MockEvent::Called(5, "Init".to_string(), Some(b"{\"lang\":\"en\"}\0".to_vec())),
+                MockEvent::Called(5, "Setup".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_init_exports_with_the_next_method() {
+        let a = dll("a.dll");
+        let backend = MockBackend::new([MockProcess::new(5, "game.exe")
+            .blocked_method(InjectionMethod::NtCreateThread)
+            .export("Init", 7)]);
+        let mut profile = test_profile(std::slice::from_ref(&a), false);
+        profile.custom_dlls[0].init = Some(InitCall {
+            export: "Init".to_string(),
+            ..Default::default()
+        });
+        // A queued call can load the DLL but not return what Init returned
+        profile.injection_methods = vec![
+            InjectionMethod::NtCreateThread,
+            InjectionMethod::QueueUserApc,
+            InjectionMethod::CreateRemoteThread,
+        ];
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 1
+            }
+        );
+        let results: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => {
+                    Some((result.method, result.init_returned, result.error))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [(Some(InjectionMethod::QueueUserApc), Some(7), None)]
+        );
+        assert!(
+            backend
+                .events()
+                .contains(&MockEvent::Called(5, "Init".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_next_injection_method() {
+        let a = dll("a.dll");
+        let backend = MockBackend::new([
+            MockProcess::new(5, "game.exe").blocked_method(InjectionMethod::NtCreateThread)
+        ]);
+        let mut profile = test_profile(std::slice::from_ref(&a), false);
+        profile.injection_methods = vec![
+            InjectionMethod::NtCreateThread,
+            InjectionMethod::CreateRemoteThread,
+        ];
+        let (events, rx) = channel(|| {});
+        let (_stop_tx, stop_rx) = mpsc::channel();
+
+        let outcome = run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+
+        assert_eq!(
+            outcome,
+            WatchOutcome::Injected {
+                succeeded: 1,
+                total: 1
+            }
+        );
+        let methods: Vec<_> = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                WatcherEvent::InjectionResult(result) => Some(result.method),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(methods, [Some(InjectionMethod::CreateRemoteThread)]);
+
+        // Nothing is left to fall back to
+        profile.injection_methods = vec![InjectionMethod::NtCreateThread];
+        let backend = MockBackend::new([
+            MockProcess::new(5, "game.exe").blocked_method(InjectionMethod::NtCreateThread)
+        ]);
+        let (events, rx) = channel(|| {});
+        run(&backend, &profile, &test_game(), false, &events, &stop_rx);
+        let failed = rx.try_iter().any(|event| {
+            matches!(event, WatcherEvent::InjectionResult(result) if result.method.is_none() && result.error.is_some())
+        });
+        assert!(failed);
+    }
+
+    #[test]
+    fn reports_missing_dependencies() {
+        let a = dll("a.dll");
+        let process = || MockProcess::new(5, "game.exe").dll_with_dependencies(&a);
+        let mut profile = test_profile(std::slice::from_ref(&a), false);
+        let (_stop_tx, stop_rx) = mpsc::channel();
+        let errors = |rx: Receiver<WatcherEvent>| -> Vec<_> {
+            rx.try_iter()
+                .filter_map(|event| match event {
+                    WatcherEvent::InjectionResult(result) => Some(result.error),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let (events, rx) = channel(|| {});
+        run(
+            &MockBackend::new([process()]),
+            &profile,
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+        assert_eq!(errors(rx), [Some(InjectError::DependencyNotFound)]);
+
+        profile.custom_dlls[0].load.search_path = SearchPath::DllLoadDir;
+        let (events, rx) = channel(|| {});
+        run(
+            &MockBackend::new([process()]),
+            &profile,
+            &test_game(),
+            false,
+            &events,
+            &stop_rx,
+        );
+        assert_eq!(errors(rx), [None]);
+    }
+
+    #[test]
+    fn injects_dlls_after_the_dlls_they_import() {
+        let dir = temp_dir("order");
+        let (plugin, helper) = (dir.join("plugin.dll"), dir.join("helper.dll"));
+        std::fs::write(
+            &plugin,
+            dll_with_imports(&["KERNEL32.dll", "helper.dll"], &[]),
+        )
+        .unwrap();
+        std::fs::write(&helper, dll_with_imports(&["KERNEL32.dll"], &[])).unwrap();
+        let mut profile = test_profile(&[plugin.clone(), helper.clone()], false);
+        let (_stop_tx, stop_rx) = mpsc::channel();
+        let injected = |profile: &Profile| {
+            let backend = MockBackend::new([MockProcess::new(5, "game.exe")]);
+            let (events, _rx) = channel(|| {});
+            run(&backend, profile, &test_game(), false, &events, &stop_rx);
+            backend
+                .events()
+                .into_iter()
+                .filter_map(|e| match e {
+                    MockEvent::Injected(_, path) => Some(path),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(injected(&profile), [helper.clone(), plugin.clone()]);
+        profile.dll_order = DllOrder::List;
+        assert_eq!(injected(&profile), [plugin, helper]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
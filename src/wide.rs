@@ -0,0 +1,95 @@
+//! Conversions between Rust strings and the UTF-16 strings Win32 APIs use.
+
+use std::ffi::{OsStr, OsString};
+
+#[cfg(windows)]
+fn encode_wide(s: &OsStr) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().collect()
+}
+
+// Only used by tests off Windows, where paths are practically always UTF-8.
+#[cfg(not(windows))]
+fn encode_wide(s: &OsStr) -> Vec<u16> {
+    s.to_string_lossy().encode_utf16().collect()
+}
+
+#[cfg(windows)]
+fn decode_wide(wide: &[u16]) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    OsString::from_wide(wide)
+}
+
+#[cfg(not(windows))]
+fn decode_wide(wide: &[u16]) -> OsString {
+    String::from_utf16_lossy(wide).into()
+}
+
+/// Encodes `s` as a NUL-terminated UTF-16 string, e.g. for `LoadLibraryW`.
+///
+/// Returns `None` if `s` contains a NUL, which would cut the string short.
+pub fn to_wide_nul(s: &OsStr) -> Option<Vec<u16>> {
+    let mut wide = encode_wide(s);
+    if wide.contains(&0) {
+        return None;
+    }
+    wide.push(0);
+    Some(wide)
+}
+
+/// Decodes a NUL-terminated UTF-16 buffer, ignoring whatever follows the NUL.
+pub fn os_from_wide_nul(buffer: &[u16]) -> OsString {
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    decode_wide(&buffer[..len])
+}
+
+/// Like `os_from_wide_nul`, but replaces invalid UTF-16 for display.
+pub fn from_wide_nul(buffer: &[u16]) -> String {
+    os_from_wide_nul(buffer).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn encodes_non_ascii_paths() {
+        let path = Path::new("C:\\Users\\ユーザー\\ウマ娘\\hachimi.dll");
+        let wide = to_wide_nul(path.as_os_str()).unwrap();
+
+        assert_eq!(wide.last(), Some(&0));
+        assert_eq!(wide.len(), path.to_str().unwrap().chars().count() + 1);
+        assert_eq!(
+            String::from_utf16(&wide[..wide.len() - 1]).unwrap(),
+            path.to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn encodes_characters_outside_the_bmp_as_surrogate_pairs() {
+        let wide = to_wide_nul(OsStr::new("🐎.dll")).unwrap();
+        assert_eq!(
+            wide,
+            [
+                0xd83d, 0xdc0e, '.' as u16, 'd' as u16, 'l' as u16, 'l' as u16, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_interior_nul() {
+        assert_eq!(to_wide_nul(OsStr::new("a\0b.dll")), None);
+    }
+
+    #[test]
+    fn round_trips_through_a_fixed_buffer() {
+        let mut buffer = [0xffffu16; 32];
+        let wide = to_wide_nul(OsStr::new("ゲーム.exe")).unwrap();
+        buffer[..wide.len()].copy_from_slice(&wide);
+
+        assert_eq!(from_wide_nul(&buffer), "ゲーム.exe");
+        assert_eq!(os_from_wide_nul(&buffer), OsString::from("ゲーム.exe"));
+        assert_eq!(from_wide_nul(&[b'a' as u16, b'b' as u16]), "ab");
+    }
+}
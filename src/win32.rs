@@ -1,23 +1,49 @@
-use std::ffi::CString;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{mem, ptr, thread};
-use tracing::{debug, error, info};
-use windows::Win32::Foundation::{HANDLE, HWND, LPARAM};
+use tracing::{info, warn};
+use windows::Win32::Foundation::{
+    CloseHandle, FILETIME, GetLastError, HANDLE, HMODULE, HWND, LPARAM, PAPCFUNC, WAIT_OBJECT_0,
+    WAIT_TIMEOUT,
+};
+#[cfg(target_arch = "x86_64")]
+use windows::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+use windows::Win32::System::Diagnostics::Debug::{ReadProcessMemory, WriteProcessMemory};
 use windows::Win32::System::Diagnostics::ToolHelp::{
     CREATE_TOOLHELP_SNAPSHOT_FLAGS, CreateToolhelp32Snapshot, MODULEENTRY32W, Module32FirstW,
-    Module32NextW, PROCESSENTRY32W, Process32FirstW, Process32NextW,
+    Module32NextW, PROCESSENTRY32W, Process32FirstW, Process32NextW, THREADENTRY32, Thread32First,
+    Thread32Next,
 };
 use windows::Win32::System::LibraryLoader::{GetModuleHandleA, GetProcAddress};
-use windows::Win32::System::Memory::{MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE};
+use windows::Win32::System::Memory::{
+    MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_READWRITE, VirtualAllocEx, VirtualFreeEx,
+};
+#[cfg(target_arch = "x86_64")]
+use windows::Win32::System::Memory::{PAGE_EXECUTE_READ, PAGE_PROTECTION_FLAGS, VirtualProtectEx};
+use windows::Win32::System::RemoteDesktop::ProcessIdToSessionId;
+use windows::Win32::System::SystemInformation::{IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_UNKNOWN};
 use windows::Win32::System::Threading::{
-    GetExitCodeThread, GetProcessId, OpenProcess, PROCESS_NAME_FORMAT, QueryFullProcessImageNameW,
-    WaitForInputIdle, WaitForSingleObject,
+    CREATE_SUSPENDED, CreateProcessW, CreateRemoteThread, GetCurrentProcessId, GetExitCodeProcess,
+    GetExitCodeThread, GetProcessId, GetProcessTimes, IsWow64Process2, LPTHREAD_START_ROUTINE,
+    OpenProcess, OpenThread, PROCESS_INFORMATION, PROCESS_NAME_FORMAT,
+    PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE, PROCESS_TERMINATE,
+    QueryFullProcessImageNameW, QueueUserAPC, ResumeThread, STARTUPINFOW, THREAD_SET_CONTEXT,
+    TerminateProcess, WaitForInputIdle, WaitForSingleObject,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
-    EnumWindows, GetWindowThreadProcessId, IsWindowVisible,
+    EnumWindows, GetClassNameW, GetWindowTextW, GetWindowThreadProcessId, IsWindowVisible,
+};
+use windows::core::{BOOL, PCSTR, PCWSTR, PWSTR, s};
+
+use crate::backend::{
+    self, InjectionMethod, LoadOptions, LoadedModule, ProcessBackend, ProcessEntry,
 };
-use windows::core::{BOOL, s};
+use crate::error::{ERROR_MOD_NOT_FOUND, ERROR_PROC_NOT_FOUND, ErrorCode, InjectError};
+use crate::matcher::{self, ProcessMatcher, WindowInfo};
+use crate::pe::{self, Machine};
+use crate::profile::LaunchOptions;
+use crate::wide::{self, from_wide_nul};
 
 // Define function pointer types for the dynamically resolved NT functions
 type ZwAllocateVirtualMemoryFn = unsafe extern "system" fn(
@@ -37,6 +63,15 @@ type ZwWriteVirtualMemoryFn = unsafe extern "system" fn(
     *mut usize,
 ) -> i32;
 
+#[cfg(target_arch = "x86_64")]
+type ZwProtectVirtualMemoryFn = unsafe extern "system" fn(
+    HANDLE,
+    *mut *mut core::ffi::c_void,
+    *mut usize,
+    u32,
+    *mut u32,
+) -> i32;
+
 type ZwCreateThreadExFn = unsafe extern "system" fn(
     *mut HANDLE,
     u32,
@@ -51,70 +86,479 @@ type ZwCreateThreadExFn = unsafe extern "system" fn(
     *const core::ffi::c_void,
 ) -> i32;
 
-pub unsafe fn inject_dll_to_handle(ph: HANDLE, dll_path: &str) -> bool {
+/// A handle that is closed when dropped.
+pub struct OwnedHandle(HANDLE);
+
+impl OwnedHandle {
+    pub fn as_raw(&self) -> HANDLE {
+        self.0
+    }
+}
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        if !self.0.is_invalid() {
+            unsafe {
+                let _ = CloseHandle(self.0);
+            }
+        }
+    }
+}
+
+/// Memory allocated in another process, released when dropped.
+struct RemoteAllocation {
+    process: HANDLE,
+    address: *mut core::ffi::c_void,
+}
+
+impl RemoteAllocation {
+    /// Keeps the memory allocated, for when a remote thread may still use it.
+    fn leak(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for RemoteAllocation {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = VirtualFreeEx(self.process, self.address, 0, MEM_RELEASE);
+        }
+    }
+}
+
+fn snapshot(flags: u32, pid: u32) -> Option<OwnedHandle> {
+    unsafe { CreateToolhelp32Snapshot(CREATE_TOOLHELP_SNAPSHOT_FLAGS(flags), pid) }
+        .ok()
+        .map(OwnedHandle)
+}
+
+/// How long to wait for `LoadLibraryExW` or `FreeLibrary` to return in the
+/// remote thread.
+const LOAD_LIBRARY_TIMEOUT_MS: u32 = 5000;
+/// How long an export called in the game, such as an initialization or
+/// shutdown function, gets to return.
+const EXPORT_CALL_TIMEOUT_MS: u32 = 10000;
+const TERMINATE_TIMEOUT_MS: u32 = 10000;
+/// How often the module list is checked for a DLL loaded by a queued call.
+const QUEUED_CALL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn last_error() -> ErrorCode {
+    ErrorCode::Win32(unsafe { GetLastError() }.0)
+}
+
+fn resolve(
+    module: HMODULE,
+    name: PCSTR,
+) -> Result<unsafe extern "system" fn() -> isize, InjectError> {
+    unsafe { GetProcAddress(module, name) }.ok_or_else(|| InjectError::ModuleResolution {
+        name: unsafe { name.to_string() }.unwrap_or_default(),
+        code: last_error(),
+    })
+}
+
+fn module_handle(name: PCSTR) -> Result<HMODULE, InjectError> {
+    unsafe { GetModuleHandleA(name) }.map_err(|e| InjectError::ModuleResolution {
+        name: unsafe { name.to_string() }.unwrap_or_default(),
+        code: ErrorCode::Win32(e.code().0 as u32 & 0xFFFF),
+    })
+}
+
+/// Whether the process is a 32-bit one running under WOW64, so the code and
+/// pointers it is given have to be 32-bit.
+fn is_wow64(ph: HANDLE) -> bool {
+    cfg!(target_pointer_width = "64") && process_machine(ph) == Some(Machine::X86)
+}
+
+/// How many forwarders are followed to find a function, Windows forwards
+/// kernel32 functions at most twice.
+const MAX_FORWARDS: u32 = 4;
+
+/// Where kernel32 functions are in the process.
+enum Kernel32 {
+    /// kernel32 is at the same address in every process of the same
+    /// architecture, so its functions are where they are in Tamamo-X.
+    Native(HMODULE),
+    /// A WOW64 process has its own 32-bit kernel32, whose export table is read
+    /// from the process. Holds the 32-bit modules of the process.
+    Wow64(HANDLE, Vec<LoadedModule>),
+}
+
+impl Kernel32 {
+    fn new(ph: HANDLE) -> Result<Self, InjectError> {
+        if !is_wow64(ph) {
+            return module_handle(s!("kernel32.dll")).map(Kernel32::Native);
+        }
+        Ok(Kernel32::Wow64(ph, wow64_modules(ph)?))
+    }
+
+    fn function(&self, name: PCSTR) -> Result<usize, InjectError> {
+        match self {
+            Kernel32::Native(module) => resolve(*module, name).map(|f| f as usize),
+            Kernel32::Wow64(ph, modules) => {
+                let name = unsafe { name.to_string() }.unwrap_or_default();
+                unsafe { remote_export(*ph, modules, "kernel32.dll", &name, 0) }
+            }
+        }
+    }
+}
+
+/// The 32-bit modules of a WOW64 process.
+fn wow64_modules(ph: HANDLE) -> Result<Vec<LoadedModule>, InjectError> {
+    let modules = module_snapshot(unsafe { GetProcessId(ph) }).map_err(|code| {
+        InjectError::ModuleResolution {
+            name: "kernel32.dll".to_string(),
+            code,
+        }
+    })?;
+    // The 64-bit modules of WOW64 itself are listed too, above 4 GiB
+    Ok(modules
+        .into_iter()
+        .filter(|m| m.base + m.size as u64 <= 1 << 32)
+        .collect())
+}
+
+/// Whether DLLs can be injected into the process, which for a WOW64 one means
+/// it has loaded its 32-bit kernel32.
+fn can_inject(ph: HANDLE) -> bool {
+    let is_kernel32 = |m: &LoadedModule| {
+        m.path
+            .file_name()
+            .is_some_and(|f| f.eq_ignore_ascii_case("kernel32.dll"))
+    };
+    !is_wow64(ph) || wow64_modules(ph).is_ok_and(|modules| modules.iter().any(is_kernel32))
+}
+
+/// Finds the function the loaded module called `module` exports as `name` by
+/// reading its export table from the process, following forwarders into the
+/// other `modules`.
+unsafe fn remote_export(
+    ph: HANDLE,
+    modules: &[LoadedModule],
+    module: &str,
+    name: &str,
+    forwards: u32,
+) -> Result<usize, InjectError> {
+    let not_found = |code| InjectError::ModuleResolution {
+        name: format!("{}!{}", module, name),
+        code: ErrorCode::Win32(code),
+    };
+    let loaded = modules
+        .iter()
+        .find(|m| {
+            m.path
+                .file_name()
+                .is_some_and(|f| f.to_string_lossy().eq_ignore_ascii_case(module))
+        })
+        .ok_or_else(|| not_found(ERROR_MOD_NOT_FOUND))?;
+
+    let mut image = vec![0u8; loaded.size as usize];
     unsafe {
-        // 1. Get Handle to Kernel32
-        let h_kernel32 = match GetModuleHandleA(s!("Kernel32")) {
-            Ok(handle) => handle,
-            Err(_) => {
-                // Assuming GetLastError is available (check your imports if this fails)
-                // Note: In 0.62, GetLastError is in Foundation.
-                error!("Failed to get Kernel32 handle");
-                return false;
+        ReadProcessMemory(
+            ph,
+            loaded.base as *const core::ffi::c_void,
+            image.as_mut_ptr() as *mut core::ffi::c_void,
+            image.len(),
+            None,
+        )
+    }
+    .map_err(|e| InjectError::ModuleResolution {
+        name: format!("{}!{}", module, name),
+        code: ErrorCode::Win32(e.code().0 as u32 & 0xFFFF),
+    })?;
+    // ERROR_BAD_EXE_FORMAT
+    let exports = pe::parse_image_exports(&image).map_err(|_| not_found(193))?;
+    let export = exports
+        .iter()
+        .find(|e| e.name.as_deref() == Some(name))
+        .ok_or_else(|| not_found(ERROR_PROC_NOT_FOUND))?;
+    if export.forwarder.is_none() {
+        return Ok(loaded.base as usize + export.rva as usize);
+    }
+    match export.forwarded_to() {
+        Some((dll, function)) if forwards < MAX_FORWARDS => unsafe {
+            remote_export(ph, modules, &dll, &function, forwards + 1)
+        },
+        _ => Err(not_found(ERROR_PROC_NOT_FOUND)),
+    }
+}
+
+unsafe fn inject_dll_to_handle(
+    ph: HANDLE,
+    dll_path: &Path,
+    options: &LoadOptions,
+    injector: &dyn Injector,
+) -> Result<LoadedModule, InjectError> {
+    unsafe {
+        if is_wow64(ph) && !injector.supports_wow64() {
+            return Err(InjectError::Wow64Unsupported);
+        }
+        let kernel32 = Kernel32::new(ph)?;
+
+        // 1. Run LoadLibraryExW through CALL_STUB, which needs the game to
+        // allow executable memory, or else start a thread at LoadLibraryW
+        #[cfg(target_arch = "x86_64")]
+        let exit_code = match load_library_ex(ph, dll_path, options, injector, &kernel32) {
+            Err(InjectError::NotExecutable(code)) => {
+                warn!(
+                    "The game doesn't allow running code written to it ({}), loading the DLL with LoadLibraryW instead",
+                    code
+                );
+                load_library(ph, dll_path, options, injector, &kernel32)?
             }
+            result => result?,
         };
+        #[cfg(not(target_arch = "x86_64"))]
+        let exit_code = load_library(ph, dll_path, options, injector, &kernel32)?;
 
-        // 2. Get Handle to ntdll
-        let h_ntdll = match GetModuleHandleA(s!("ntdll.dll")) {
-            Ok(handle) => handle,
-            Err(_) => {
-                error!("Failed to get ntdll handle");
-                return false;
+        // 2. Confirm the DLL is loaded, a queued call may run any time
+        let queued_at = Instant::now();
+        loop {
+            let modules = list_modules(GetProcessId(ph)).unwrap_or_default();
+            if let Some(module) = backend::find_loaded(&modules, dll_path) {
+                info!(
+                    "LoadLibraryExW succeeded. DLL base address: 0x{:016X}, size: 0x{:X}",
+                    module.base, module.size
+                );
+                return Ok(module.clone());
             }
-        };
+            match exit_code {
+                Some(_) => return Err(InjectError::NotLoaded),
+                None if queued_at.elapsed()
+                    < Duration::from_millis(LOAD_LIBRARY_TIMEOUT_MS as u64) =>
+                {
+                    thread::sleep(QUEUED_CALL_POLL_INTERVAL)
+                }
+                None => {
+                    return Err(InjectError::QueuedCallNotRun {
+                        timeout_ms: LOAD_LIBRARY_TIMEOUT_MS,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Adds the DLL directories and runs `LoadLibraryExW` in the game with
+/// `CALL_STUB`, returns 0 once it's loaded or `None` if the call was only
+/// queued.
+#[cfg(target_arch = "x86_64")]
+unsafe fn load_library_ex(
+    ph: HANDLE,
+    dll_path: &Path,
+    options: &LoadOptions,
+    injector: &dyn Injector,
+    kernel32: &Kernel32,
+) -> Result<Option<u32>, InjectError> {
+    unsafe {
+        let wow64 = is_wow64(ph);
+        let load_library = kernel32.function(s!("LoadLibraryExW"))?;
+        let get_last_error = kernel32.function(s!("GetLastError"))?;
+
+        // The DLL directories are only searched with the flags
+        // `LoadOptions::flags` sets for them
+        if !options.dll_directories.is_empty() {
+            let add_dll_directory = kernel32.function(s!("AddDllDirectory"))?;
+            for dir in &options.dll_directories {
+                let call = remote_call(
+                    wow64,
+                    add_dll_directory,
+                    get_last_error,
+                    0,
+                    &wide_path(dir)?,
+                );
+                if let Some(code) = run_remote_call(ph, injector, &call)?
+                    && code != 0
+                {
+                    return Err(InjectError::AddDllDirectoryFailed {
+                        path: dir.clone(),
+                        code: ErrorCode::Win32(code),
+                    });
+                }
+            }
+        }
 
-        // 3. Get LoadLibraryA address
-        let lb = GetProcAddress(h_kernel32, s!("LoadLibraryA"));
-        if lb.is_none() {
-            error!("Failed to get LoadLibraryA address");
-            return false;
+        let path = wide_path(dll_path)?;
+        let call = remote_call(wow64, load_library, get_last_error, options.flags(), &path);
+        match run_remote_call(ph, injector, &call)? {
+            Some(ERROR_MOD_NOT_FOUND) => Err(InjectError::DependencyNotFound),
+            Some(code) if code != 0 => Err(InjectError::LoadLibraryFailed(ErrorCode::Win32(code))),
+            exit_code => Ok(exit_code),
         }
+    }
+}
 
-        // 4. Resolve NT function addresses
-        let zw_alloc_virtual_mem_addr = GetProcAddress(h_ntdll, s!("ZwAllocateVirtualMemory"));
-        let zw_write_virtual_mem_addr = GetProcAddress(h_ntdll, s!("ZwWriteVirtualMemory"));
-        let zw_create_thread_ex_addr = GetProcAddress(h_ntdll, s!("ZwCreateThreadEx"));
+/// Starts a thread in the game at `LoadLibraryW` with the path as its
+/// parameter, which runs no code of Tamamo-X's but can't pass the flags of
+/// `options` or tell why loading failed. Returns 0 once it has returned or
+/// `None` if the call was only queued.
+unsafe fn load_library(
+    ph: HANDLE,
+    dll_path: &Path,
+    options: &LoadOptions,
+    injector: &dyn Injector,
+    kernel32: &Kernel32,
+) -> Result<Option<u32>, InjectError> {
+    unsafe {
+        if *options != LoadOptions::default() {
+            warn!("LoadLibraryW searches for the DLL's dependencies from the game's folder only");
+        }
+        let load_library = kernel32.function(s!("LoadLibraryW"))?;
+        let path: Vec<u8> = wide_path(dll_path)?
+            .iter()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        let remote = injector.write(ph, &path)?;
+        let start = load_library as *mut core::ffi::c_void;
+        // The low bits of the module handle, the module list tells if it worked
+        run_keeping(ph, injector, remote, start, 0).map(|exit| exit.map(|_| 0))
+    }
+}
 
-        if zw_write_virtual_mem_addr.is_none()
-            || zw_create_thread_ex_addr.is_none()
-            || zw_alloc_virtual_mem_addr.is_none()
-        {
-            error!("Failed to get Windows function address");
-            return false;
+fn wide_path(path: &Path) -> Result<Vec<u16>, InjectError> {
+    wide::to_wide_nul(path.as_os_str()).ok_or_else(|| {
+        InjectError::InvalidDll(format!("Path contains a NUL character: {}", path.display()))
+    })
+}
+
+/// Code run in the game with a pointer to the block `remote_call` puts after
+/// it. It calls `function(path, NULL, flags)` and returns 0 if that returned
+/// non-null, or else what `GetLastError` returns, so the exit code of the
+/// thread says why `LoadLibraryExW` or `AddDllDirectory` failed.
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const CALL_STUB: [u8; 40] = [
+    0x53,                   // push rbx
+    0x48, 0x83, 0xEC, 0x20, // sub rsp, 0x20
+    0x48, 0x89, 0xCB,       // mov rbx, rcx
+    0x48, 0x8D, 0x4B, 0x18, // lea rcx, [rbx + 0x18] (path)
+    0x31, 0xD2,             // xor edx, edx
+    0x4C, 0x8B, 0x43, 0x10, // mov r8, [rbx + 0x10] (flags)
+    0xFF, 0x13,             // call [rbx] (function)
+    0x48, 0x85, 0xC0,       // test rax, rax
+    0x75, 0x05,             // jnz ok
+    0xFF, 0x53, 0x08,       // call [rbx + 0x08] (GetLastError)
+    0xEB, 0x02,             // jmp done
+    0x31, 0xC0,             // ok: xor eax, eax
+    0x48, 0x83, 0xC4, 0x20, // done: add rsp, 0x20
+    0x5B,                   // pop rbx
+    0xC3,                   // ret
+    0xCC, 0xCC,             // int3, so the block is aligned
+];
+
+/// `CALL_STUB` for WOW64 processes, a stdcall function whose block has 32-bit
+/// fields. `esp` is restored after the call, as `AddDllDirectory` only pops
+/// one of the three arguments.
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const CALL_STUB_32: [u8; CALL_STUB.len()] = [
+    0x53,                   // push ebx
+    0x56,                   // push esi
+    0x8B, 0x5C, 0x24, 0x0C, // mov ebx, [esp + 0x0C] (block)
+    0x89, 0xE6,             // mov esi, esp
+    0xFF, 0x73, 0x08,       // push dword [ebx + 0x08] (flags)
+    0x6A, 0x00,             // push 0
+    0x8D, 0x43, 0x0C,       // lea eax, [ebx + 0x0C] (path)
+    0x50,                   // push eax
+    0xFF, 0x13,             // call [ebx] (function)
+    0x89, 0xF4,             // mov esp, esi
+    0x85, 0xC0,             // test eax, eax
+    0x75, 0x05,             // jnz ok
+    0xFF, 0x53, 0x04,       // call [ebx + 0x04] (GetLastError)
+    0xEB, 0x02,             // jmp done
+    0x31, 0xC0,             // ok: xor eax, eax
+    0x5E,                   // done: pop esi
+    0x5B,                   // pop ebx
+    0xC2, 0x04, 0x00,       // ret 4
+    0xCC, 0xCC, 0xCC,       // int3, so the block is aligned
+];
+
+/// `CALL_STUB` followed by its block: the function, `GetLastError`, the flags
+/// and the NUL-terminated path. With `wow64`, `CALL_STUB_32` and 32-bit
+/// fields instead.
+#[cfg(target_arch = "x86_64")]
+fn remote_call(
+    wow64: bool,
+    function: usize,
+    get_last_error: usize,
+    flags: u32,
+    path: &[u16],
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    if wow64 {
+        data.extend_from_slice(&CALL_STUB_32);
+        data.extend_from_slice(&(function as u32).to_le_bytes());
+        data.extend_from_slice(&(get_last_error as u32).to_le_bytes());
+        data.extend_from_slice(&flags.to_le_bytes());
+    } else {
+        data.extend_from_slice(&CALL_STUB);
+        data.extend_from_slice(&(function as u64).to_le_bytes());
+        data.extend_from_slice(&(get_last_error as u64).to_le_bytes());
+        data.extend_from_slice(&(flags as u64).to_le_bytes());
+    }
+    data.extend(path.iter().flat_map(|c| c.to_le_bytes()));
+    data
+}
+
+/// Makes the process run a `remote_call` with `injector` and returns its
+/// exit code, or `None` if it was only queued.
+#[cfg(target_arch = "x86_64")]
+unsafe fn run_remote_call(
+    ph: HANDLE,
+    injector: &dyn Injector,
+    call: &[u8],
+) -> Result<Option<u32>, InjectError> {
+    unsafe {
+        let remote = injector.write(ph, call)?;
+        injector.make_executable(ph, &remote, call.len())?;
+        let _ = FlushInstructionCache(ph, Some(remote.address), call.len());
+
+        let start = remote.address;
+        run_keeping(ph, injector, remote, start, CALL_STUB.len())
+    }
+}
+
+/// Makes the process call `start` with `injector`, passing it the address
+/// `parameter` bytes into `remote`, and returns its exit code, or `None` if
+/// it was only queued. `remote` stays allocated while the call may use it.
+unsafe fn run_keeping(
+    ph: HANDLE,
+    injector: &dyn Injector,
+    remote: RemoteAllocation,
+    start: *mut core::ffi::c_void,
+    parameter: usize,
+) -> Result<Option<u32>, InjectError> {
+    unsafe {
+        let parameter = remote.address.add(parameter);
+        match injector.run(ph, start, parameter, LOAD_LIBRARY_TIMEOUT_MS) {
+            Ok(Some(exit_code)) => Ok(Some(exit_code)),
+            // A queued call may run any time, so the memory has to stay
+            Ok(None) => {
+                remote.leak();
+                Ok(None)
+            }
+            Err(e) => {
+                if let InjectError::WaitTimeout { .. } = e {
+                    // The thread may still be running the call
+                    remote.leak();
+                }
+                Err(e)
+            }
         }
+    }
+}
 
-        // Cast function pointers to the types defined above
+/// Copies `data` into memory allocated for it in the process.
+unsafe fn write_remote(ph: HANDLE, data: &[u8]) -> Result<RemoteAllocation, InjectError> {
+    unsafe {
+        // Resolve NT function addresses and cast them to the types defined above
+        let h_ntdll = module_handle(s!("ntdll.dll"))?;
         let zw_allocate_virtual_memory: ZwAllocateVirtualMemoryFn =
-            mem::transmute(zw_alloc_virtual_mem_addr.unwrap());
+            mem::transmute(resolve(h_ntdll, s!("ZwAllocateVirtualMemory"))?);
         let zw_write_virtual_memory: ZwWriteVirtualMemoryFn =
-            mem::transmute(zw_write_virtual_mem_addr.unwrap());
-        let zw_create_thread_ex: ZwCreateThreadExFn =
-            mem::transmute(zw_create_thread_ex_addr.unwrap());
-
-        // 5. Prepare DLL Path
-        let dll_path_c = match CString::new(dll_path) {
-            Ok(s) => s,
-            Err(_) => {
-                error!("Failed to convert DLL path to CString");
-                return false;
-            }
-        };
-        let dll_path_ptr = dll_path_c.as_ptr() as *const core::ffi::c_void;
-        let dll_length = dll_path_c.as_bytes_with_nul().len();
+            mem::transmute(resolve(h_ntdll, s!("ZwWriteVirtualMemory"))?);
 
-        // 6. Allocate Memory
         let mut base_address: *mut core::ffi::c_void = ptr::null_mut();
-        let mut region_size = dll_length;
+        let mut region_size = data.len();
 
         let status = zw_allocate_virtual_memory(
             ph,
@@ -124,122 +568,572 @@ pub unsafe fn inject_dll_to_handle(ph: HANDLE, dll_path: &str) -> bool {
             MEM_RESERVE.0 | MEM_COMMIT.0,
             PAGE_READWRITE.0,
         );
-
         if status != 0 {
-            error!(
-                "Failed to allocate memory in the target process: 0x{:X}",
-                status
-            );
-            return false;
+            return Err(InjectError::from_code(
+                ErrorCode::NtStatus(status as u32),
+                InjectError::Allocation,
+            ));
         }
 
-        let rb = base_address;
-
-        // 7. Write DLL Path
-        let write_status =
-            zw_write_virtual_memory(ph, rb, dll_path_ptr, dll_length, ptr::null_mut());
+        let remote = RemoteAllocation {
+            process: ph,
+            address: base_address,
+        };
 
+        let write_status = zw_write_virtual_memory(
+            ph,
+            remote.address,
+            data.as_ptr() as *const core::ffi::c_void,
+            data.len(),
+            ptr::null_mut(),
+        );
         if write_status != 0 {
-            error!(
-                "Failed to write memory in the target process: 0x{:X}",
-                write_status
-            );
-            return false;
+            return Err(InjectError::from_code(
+                ErrorCode::NtStatus(write_status as u32),
+                InjectError::Write,
+            ));
         }
+        Ok(remote)
+    }
+}
 
-        // 8. Create Remote Thread
-        let mut h_thread: HANDLE = HANDLE::default();
+/// Runs `start(parameter)` on a new thread in the process, waits up to
+/// `timeout_ms` for it and returns its exit code.
+unsafe fn run_remote_thread(
+    ph: HANDLE,
+    start: *mut core::ffi::c_void,
+    parameter: *mut core::ffi::c_void,
+    timeout_ms: u32,
+) -> Result<u32, InjectError> {
+    unsafe {
+        let h_ntdll = module_handle(s!("ntdll.dll"))?;
+        let zw_create_thread_ex: ZwCreateThreadExFn =
+            mem::transmute(resolve(h_ntdll, s!("ZwCreateThreadEx"))?);
 
+        let mut h_thread: HANDLE = HANDLE::default();
         let create_status = zw_create_thread_ex(
             &mut h_thread,
             windows::Win32::System::Threading::THREAD_ALL_ACCESS.0,
             ptr::null(),
             ph,
-            lb.unwrap() as *mut core::ffi::c_void,
-            rb,
+            start,
+            parameter,
             0,
             0,
             0,
             0,
             ptr::null(),
         );
+        if create_status != 0 || h_thread.is_invalid() {
+            return Err(InjectError::from_code(
+                ErrorCode::NtStatus(create_status as u32),
+                InjectError::ThreadCreation,
+            ));
+        }
 
-        if create_status == 0 && !h_thread.is_invalid() {
-            info!("Remote thread created. Waiting for completion...");
-            WaitForSingleObject(h_thread, 5000);
+        wait_for_thread(OwnedHandle(h_thread), timeout_ms)
+    }
+}
 
-            let mut exit_code: u32 = 0;
-            let _ = GetExitCodeThread(h_thread, &mut exit_code);
+/// Waits up to `timeout_ms` for a thread created in the game and returns its
+/// exit code.
+fn wait_for_thread(h_thread: OwnedHandle, timeout_ms: u32) -> Result<u32, InjectError> {
+    unsafe {
+        info!("Remote thread created. Waiting for completion...");
+        let wait = WaitForSingleObject(h_thread.as_raw(), timeout_ms);
+        if wait == WAIT_TIMEOUT {
+            return Err(InjectError::WaitTimeout { timeout_ms });
+        } else if wait != WAIT_OBJECT_0 {
+            return Err(InjectError::Wait(last_error()));
+        }
 
-            if exit_code == 0 {
-                error!(
-                    "LoadLibraryA failed in target process (Exit code 0). Check if DLL path is correct and architecture matches."
-                );
-                false
-            } else {
-                info!(
-                    "LoadLibraryA succeeded. DLL base address: 0x{:X}",
-                    exit_code
-                );
-                true
+        let mut exit_code: u32 = 0;
+        let _ = GetExitCodeThread(h_thread.as_raw(), &mut exit_code);
+        Ok(exit_code)
+    }
+}
+
+/// One way of making the game run `LoadLibraryExW`, see `InjectionMethod`.
+trait Injector {
+    /// Copies `data` into memory allocated for it in the process.
+    unsafe fn write(&self, ph: HANDLE, data: &[u8]) -> Result<RemoteAllocation, InjectError> {
+        unsafe {
+            let address = VirtualAllocEx(
+                ph,
+                None,
+                data.len(),
+                MEM_RESERVE | MEM_COMMIT,
+                PAGE_READWRITE,
+            );
+            if address.is_null() {
+                return Err(InjectError::from_code(
+                    last_error(),
+                    InjectError::Allocation,
+                ));
             }
-        } else {
-            error!(
-                "Failed to create thread in the target process: 0x{:X}",
-                create_status
+            let remote = RemoteAllocation {
+                process: ph,
+                address,
+            };
+            WriteProcessMemory(
+                ph,
+                address,
+                data.as_ptr() as *const core::ffi::c_void,
+                data.len(),
+                None,
+            )
+            .map_err(|e| {
+                InjectError::from_code(
+                    ErrorCode::Win32(e.code().0 as u32 & 0xFFFF),
+                    InjectError::Write,
+                )
+            })?;
+            Ok(remote)
+        }
+    }
+
+    /// Makes the first `len` bytes of `remote` executable and read-only, which
+    /// fails when the game blocks dynamic code, e.g. with ACG.
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn make_executable(
+        &self,
+        ph: HANDLE,
+        remote: &RemoteAllocation,
+        len: usize,
+    ) -> Result<(), InjectError> {
+        unsafe {
+            let mut old = PAGE_PROTECTION_FLAGS::default();
+            VirtualProtectEx(ph, remote.address, len, PAGE_EXECUTE_READ, &mut old).map_err(|e| {
+                InjectError::NotExecutable(ErrorCode::Win32(e.code().0 as u32 & 0xFFFF))
+            })
+        }
+    }
+
+    /// Whether it can run 32-bit code in a WOW64 process.
+    fn supports_wow64(&self) -> bool {
+        true
+    }
+
+    /// Whether `run` only queues the call, which may then run on several
+    /// threads and never returns a value.
+    fn only_queues(&self) -> bool {
+        false
+    }
+
+    /// Makes the process call `start(parameter)` and waits up to `timeout_ms`
+    /// for it to return. Returns its exit code, or `None` if the call was
+    /// only queued and may still run, so `parameter` has to stay valid.
+    unsafe fn run(
+        &self,
+        ph: HANDLE,
+        start: *mut core::ffi::c_void,
+        parameter: *mut core::ffi::c_void,
+        timeout_ms: u32,
+    ) -> Result<Option<u32>, InjectError>;
+}
+
+struct NtThreadInjector;
+
+impl Injector for NtThreadInjector {
+    unsafe fn write(&self, ph: HANDLE, data: &[u8]) -> Result<RemoteAllocation, InjectError> {
+        unsafe { write_remote(ph, data) }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn make_executable(
+        &self,
+        ph: HANDLE,
+        remote: &RemoteAllocation,
+        len: usize,
+    ) -> Result<(), InjectError> {
+        unsafe {
+            let h_ntdll = module_handle(s!("ntdll.dll"))?;
+            let zw_protect_virtual_memory: ZwProtectVirtualMemoryFn =
+                mem::transmute(resolve(h_ntdll, s!("ZwProtectVirtualMemory"))?);
+            let (mut address, mut size, mut old) = (remote.address, len, 0);
+            let status = zw_protect_virtual_memory(
+                ph,
+                &mut address,
+                &mut size,
+                PAGE_EXECUTE_READ.0,
+                &mut old,
             );
-            false
+            if status != 0 {
+                return Err(InjectError::NotExecutable(ErrorCode::NtStatus(
+                    status as u32,
+                )));
+            }
+            Ok(())
         }
     }
+
+    unsafe fn run(
+        &self,
+        ph: HANDLE,
+        start: *mut core::ffi::c_void,
+        parameter: *mut core::ffi::c_void,
+        timeout_ms: u32,
+    ) -> Result<Option<u32>, InjectError> {
+        unsafe { run_remote_thread(ph, start, parameter, timeout_ms).map(Some) }
+    }
 }
 
-pub fn find_process(target_process_name: &str) -> Option<HANDLE> {
-    let target_process_name = target_process_name.trim_end_matches('\0');
-    let mut target_name_wide: Vec<u16> = target_process_name.encode_utf16().collect();
-    target_name_wide.push(0);
+struct RemoteThreadInjector;
+
+impl Injector for RemoteThreadInjector {
+    unsafe fn run(
+        &self,
+        ph: HANDLE,
+        start: *mut core::ffi::c_void,
+        parameter: *mut core::ffi::c_void,
+        timeout_ms: u32,
+    ) -> Result<Option<u32>, InjectError> {
+        unsafe {
+            let h_thread = CreateRemoteThread(
+                ph,
+                None,
+                0,
+                mem::transmute::<*mut core::ffi::c_void, LPTHREAD_START_ROUTINE>(start),
+                Some(parameter),
+                0,
+                None,
+            )
+            .map_err(|e| {
+                InjectError::from_code(
+                    ErrorCode::Win32(e.code().0 as u32 & 0xFFFF),
+                    InjectError::ThreadCreation,
+                )
+            })?;
+            wait_for_thread(OwnedHandle(h_thread), timeout_ms).map(Some)
+        }
+    }
+}
+
+struct ApcInjector;
+
+impl Injector for ApcInjector {
+    // An APC queued from a 64-bit process runs as 64-bit code
+    fn supports_wow64(&self) -> bool {
+        false
+    }
+
+    fn only_queues(&self) -> bool {
+        true
+    }
 
+    unsafe fn run(
+        &self,
+        ph: HANDLE,
+        start: *mut core::ffi::c_void,
+        parameter: *mut core::ffi::c_void,
+        _timeout_ms: u32,
+    ) -> Result<Option<u32>, InjectError> {
+        unsafe {
+            let pid = GetProcessId(ph);
+            // TH32CS_SNAPTHREAD, which lists the threads of every process
+            let snapshot = snapshot(0x00000004, 0)
+                .ok_or_else(|| InjectError::from_code(last_error(), InjectError::ThreadCreation))?;
+            let routine: PAPCFUNC = mem::transmute(start);
+
+            // Which threads wait alertably isn't known, so every one gets the call
+            let mut queued = 0;
+            let mut error = None;
+            let mut entry = THREADENTRY32 {
+                dwSize: mem::size_of::<THREADENTRY32>() as u32,
+                ..Default::default()
+            };
+            if Thread32First(snapshot.as_raw(), &mut entry).is_ok() {
+                loop {
+                    if entry.th32OwnerProcessID == pid {
+                        match OpenThread(THREAD_SET_CONTEXT, false, entry.th32ThreadID) {
+                            Ok(thread) => {
+                                let thread = OwnedHandle(thread);
+                                if QueueUserAPC(routine, thread.as_raw(), parameter as usize) != 0 {
+                                    queued += 1;
+                                } else {
+                                    error = Some(last_error());
+                                }
+                            }
+                            Err(e) => error = Some(ErrorCode::Win32(e.code().0 as u32 & 0xFFFF)),
+                        }
+                    }
+                    if Thread32Next(snapshot.as_raw(), &mut entry).is_err() {
+                        break;
+                    }
+                }
+            }
+            if queued == 0 {
+                let code = error.unwrap_or(ErrorCode::Win32(0));
+                return Err(InjectError::from_code(code, InjectError::ThreadCreation));
+            }
+            info!("Queued the call on {} threads of process {}", queued, pid);
+            Ok(None)
+        }
+    }
+}
+
+fn injector(method: InjectionMethod) -> &'static dyn Injector {
+    match method {
+        InjectionMethod::NtCreateThread => &NtThreadInjector,
+        InjectionMethod::CreateRemoteThread => &RemoteThreadInjector,
+        InjectionMethod::QueueUserApc => &ApcInjector,
+    }
+}
+
+/// Where the function the DLL at `path` exports as `name` is, relative to the
+/// DLL's base.
+fn export_rva(path: &Path, name: &str) -> Result<u32, InjectError> {
+    let exports = pe::read_exports(path).map_err(InjectError::InvalidDll)?;
+    // A forwarded export is code in another DLL, not this one
+    exports
+        .into_iter()
+        .find(|e| e.name.as_deref() == Some(name) && e.forwarder.is_none())
+        .map(|e| e.rva)
+        .ok_or_else(|| InjectError::ExportNotFound {
+            name: name.to_string(),
+        })
+}
+
+/// Calls the function `module` exports as `export` in the process, passing it
+/// a copy of `argument`, and returns what it returned. The call is made with
+/// each of `methods` in turn, skipping those that only queue it.
+pub unsafe fn call_export(
+    ph: HANDLE,
+    module: &LoadedModule,
+    export: &str,
+    argument: Option<&[u8]>,
+    methods: &[InjectionMethod],
+) -> Result<u32, InjectError> {
     unsafe {
-        let snapshot = match CreateToolhelp32Snapshot(CREATE_TOOLHELP_SNAPSHOT_FLAGS(0x00000002), 0)
-        {
-            Ok(h) => h,
-            Err(_) => return None,
+        let rva = export_rva(&module.path, export)?;
+        let remote_argument = argument.map(|data| write_remote(ph, data)).transpose()?;
+        let parameter = remote_argument
+            .as_ref()
+            .map_or(ptr::null_mut(), |argument| argument.address);
+        let start = (module.base + rva as u64) as *mut core::ffi::c_void;
+
+        info!("Calling {} in {}", export, module.path.display());
+        let result = backend::inject_with_fallback(methods, |method| {
+            let injector = injector(method);
+            if injector.only_queues() {
+                return Err(InjectError::QueuedExportCall);
+            }
+            injector
+                .run(ph, start, parameter, EXPORT_CALL_TIMEOUT_MS)?
+                .ok_or(InjectError::QueuedExportCall)
+        });
+        if let (Err(InjectError::WaitTimeout { .. }), Some(argument)) = (&result, remote_argument) {
+            // The export may still read its argument, so it can't be freed
+            argument.leak();
+        }
+        let (value, method) = result?;
+        info!("Called {} with {}", export, method);
+        Ok(value)
+    }
+}
+
+/// Unloads `dll_path` from the process with `FreeLibrary`, after calling its
+/// `shutdown_export` if set so it can clean up first. `FreeLibrary` is run
+/// with each of `methods` in turn, like `LoadLibraryExW` when injecting.
+pub unsafe fn eject_dll_from_handle(
+    ph: HANDLE,
+    dll_path: &Path,
+    methods: &[InjectionMethod],
+    shutdown_export: Option<&str>,
+) -> Result<(), InjectError> {
+    unsafe {
+        let pid = GetProcessId(ph);
+        let modules = list_modules(pid).unwrap_or_default();
+        let module = backend::find_loaded(&modules, dll_path)
+            .cloned()
+            .ok_or(InjectError::NotInjected)?;
+
+        if let Some(name) = shutdown_export {
+            call_export(ph, &module, name, None, methods)?;
+        }
+
+        let (_, method) = backend::inject_with_fallback(methods, |method| {
+            free_library(ph, &module, injector(method))
+        })?;
+        info!(
+            "Ejected {} from process {} with {}",
+            dll_path.display(),
+            pid,
+            method
+        );
+        Ok(())
+    }
+}
+
+/// Makes the process run `FreeLibrary` on `module` with `injector` and checks
+/// that it's gone from the module list.
+unsafe fn free_library(
+    ph: HANDLE,
+    module: &LoadedModule,
+    injector: &dyn Injector,
+) -> Result<(), InjectError> {
+    unsafe {
+        if is_wow64(ph) && !injector.supports_wow64() {
+            return Err(InjectError::Wow64Unsupported);
+        }
+        let free_library = Kernel32::new(ph)?.function(s!("FreeLibrary"))?;
+        let freed = injector.run(
+            ph,
+            free_library as *mut core::ffi::c_void,
+            module.base as *mut core::ffi::c_void,
+            LOAD_LIBRARY_TIMEOUT_MS,
+        )?;
+        if freed == Some(0) {
+            return Err(InjectError::FreeLibraryFailed);
+        }
+
+        // FreeLibrary only unloads the DLL once nothing else references it,
+        // and a queued call may run any time
+        let pid = GetProcessId(ph);
+        let queued_at = Instant::now();
+        loop {
+            let modules = list_modules(pid).unwrap_or_default();
+            if !modules.iter().any(|m| m.base == module.base) {
+                return Ok(());
+            }
+            match freed {
+                Some(_) => return Err(InjectError::StillLoaded),
+                None if queued_at.elapsed()
+                    < Duration::from_millis(LOAD_LIBRARY_TIMEOUT_MS as u64) =>
+                {
+                    thread::sleep(QUEUED_CALL_POLL_INTERVAL)
+                }
+                None => {
+                    return Err(InjectError::QueuedCallNotRun {
+                        timeout_ms: LOAD_LIBRARY_TIMEOUT_MS,
+                    });
+                }
+            }
+        }
+    }
+}
+
+pub fn find_processes(matcher: &ProcessMatcher) -> Vec<ProcessEntry> {
+    let mut processes = list_processes();
+    // Opening every process is only worth it when the rule needs their paths
+    let needs_path = matcher.needs_path();
+    if needs_path {
+        for process in &mut processes {
+            read_process_details(process);
+        }
+    }
+    let windows = if matcher.needs_windows() {
+        list_windows()
+    } else {
+        Vec::new()
+    };
+
+    let mut processes = matcher::select(matcher, &processes, &windows);
+    if !needs_path {
+        for process in &mut processes {
+            read_process_details(process);
+        }
+    }
+    processes
+}
+
+/// Fills in the path, start time and session of a process, as far as it can be
+/// opened.
+fn read_process_details(process: &mut ProcessEntry) {
+    unsafe {
+        let mut session_id = 0;
+        if ProcessIdToSessionId(process.pid, &mut session_id).is_ok() {
+            process.session_id = Some(session_id);
+        }
+
+        let Ok(ph) = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process.pid) else {
+            return;
         };
+        let ph = OwnedHandle(ph);
+        process.path = get_process_path(ph.as_raw());
 
+        let (mut creation, mut exit, mut kernel, mut user) = Default::default();
+        if GetProcessTimes(
+            ph.as_raw(),
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        )
+        .is_ok()
+        {
+            process.start_time = Some(filetime_to_unix(creation));
+        }
+    }
+}
+
+/// Converts a FILETIME (100 ns intervals since 1601) to seconds since 1970.
+fn filetime_to_unix(time: FILETIME) -> u64 {
+    let intervals = ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+    (intervals / 10_000_000).saturating_sub(11_644_473_600)
+}
+
+pub fn list_processes() -> Vec<ProcessEntry> {
+    let mut processes = Vec::new();
+    let Some(snapshot) = snapshot(0x00000002, 0) else {
+        return processes;
+    };
+
+    unsafe {
         let mut entry = PROCESSENTRY32W::default();
         entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
 
-        if Process32FirstW(snapshot, &mut entry).is_ok() {
+        if Process32FirstW(snapshot.as_raw(), &mut entry).is_ok() {
             loop {
-                if entry.szExeFile.starts_with(&target_name_wide) {
-                    let ph = match OpenProcess(
-                        windows::Win32::System::Threading::PROCESS_ALL_ACCESS,
-                        false,
-                        entry.th32ProcessID,
-                    ) {
-                        Ok(handle) => handle,
-                        Err(_) => {
-                            let _ = windows::Win32::Foundation::CloseHandle(snapshot);
-                            return None;
-                        }
-                    };
-                    let _ = windows::Win32::Foundation::CloseHandle(snapshot);
-                    return Some(ph);
-                }
+                processes.push(ProcessEntry {
+                    pid: entry.th32ProcessID,
+                    name: from_wide_nul(&entry.szExeFile),
+                    parent_pid: entry.th32ParentProcessID,
+                    ..Default::default()
+                });
 
-                if Process32NextW(snapshot, &mut entry).is_err() {
+                if Process32NextW(snapshot.as_raw(), &mut entry).is_err() {
                     break;
                 }
             }
         }
-        let _ = windows::Win32::Foundation::CloseHandle(snapshot);
     }
-    None
+    processes
 }
 
-pub fn has_window(ph: HANDLE) -> bool {
+/// The architecture the process runs as, e.g. x86 for a WOW64 process.
+pub fn process_machine(ph: HANDLE) -> Option<Machine> {
+    let mut process = IMAGE_FILE_MACHINE::default();
+    let mut native = IMAGE_FILE_MACHINE::default();
+    unsafe { IsWow64Process2(ph, &mut process, Some(&mut native)) }.ok()?;
+    let machine = if process == IMAGE_FILE_MACHINE_UNKNOWN {
+        native
+    } else {
+        process
+    };
+    Some(Machine::from_raw(machine.0))
+}
+
+pub fn open_process(pid: u32) -> Option<OwnedHandle> {
+    unsafe {
+        OpenProcess(
+            windows::Win32::System::Threading::PROCESS_ALL_ACCESS,
+            false,
+            pid,
+        )
+        .ok()
+        .map(OwnedHandle)
+    }
+}
+
+/// Checks whether the process has a visible window, optionally only counting
+/// windows whose title contains `title` and whose class is exactly `class`.
+pub fn has_window(ph: HANDLE, title: Option<&str>, class: Option<&str>) -> bool {
     let target_pid = unsafe { GetProcessId(ph) };
     let mut data = EnumData {
         target_pid,
+        title: title.map(|t| t.to_string()),
+        class: class.map(|c| c.to_string()),
         found: false,
     };
 
@@ -253,9 +1147,45 @@ pub fn has_window(ph: HANDLE) -> bool {
     data.found
 }
 
-// May be useful for future features
-#[allow(dead_code)]
+fn window_text(hwnd: HWND) -> (String, String) {
+    let mut title = [0u16; 512];
+    let len = unsafe { GetWindowTextW(hwnd, &mut title) }.max(0) as usize;
+    let mut class = [0u16; 256];
+    let class_len = unsafe { GetClassNameW(hwnd, &mut class) }.max(0) as usize;
+    (
+        String::from_utf16_lossy(&title[..len]),
+        String::from_utf16_lossy(&class[..class_len]),
+    )
+}
+
+unsafe extern "system" fn list_windows_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let windows = unsafe { &mut *(lparam.0 as *mut Vec<WindowInfo>) };
+    if unsafe { IsWindowVisible(hwnd).as_bool() } {
+        let mut pid = 0u32;
+        unsafe { GetWindowThreadProcessId(hwnd, Some(&mut pid)) };
+        let (title, class) = window_text(hwnd);
+        windows.push(WindowInfo { pid, title, class });
+    }
+    BOOL::from(true)
+}
+
+/// Lists the visible top-level windows of every process.
+pub fn list_windows() -> Vec<WindowInfo> {
+    let mut windows: Vec<WindowInfo> = Vec::new();
+    unsafe {
+        let _ = EnumWindows(
+            Some(list_windows_callback),
+            LPARAM(&mut windows as *mut Vec<WindowInfo> as isize),
+        );
+    }
+    windows
+}
+
 pub fn get_process_directory(ph: HANDLE) -> Option<PathBuf> {
+    get_process_path(ph)?.parent().map(|p| p.to_path_buf())
+}
+
+pub fn get_process_path(ph: HANDLE) -> Option<PathBuf> {
     let mut buffer = [0u16; 1024];
     let mut size = buffer.len() as u32;
 
@@ -268,70 +1198,83 @@ pub fn get_process_directory(ph: HANDLE) -> Option<PathBuf> {
         )
         .is_ok()
         {
-            let path_str = String::from_utf16_lossy(&buffer[..size as usize]);
-            let path = PathBuf::from(path_str);
-            return path.parent().map(|p| p.to_path_buf());
+            return Some(PathBuf::from(wide::os_from_wide_nul(
+                &buffer[..size as usize],
+            )));
         }
     }
     None
 }
 
-#[allow(dead_code)]
-pub fn wait_for_module(ph: HANDLE, module_name: &str) -> bool {
+/// Checks whether a module called `module_name` (ignoring case) is loaded.
+pub fn has_module(ph: HANDLE, module_name: &str) -> bool {
     let pid = unsafe { GetProcessId(ph) };
-    let module_name_lower = module_name.to_lowercase();
-    debug!("Waiting for module '{}' in PID {}...", module_name, pid);
+    let module_name = module_name.to_lowercase();
+    list_modules(pid).unwrap_or_default().iter().any(|m| {
+        m.path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().to_lowercase() == module_name)
+    })
+}
 
-    loop {
-        unsafe {
-            // TH32CS_SNAPMODULE (0x8) | TH32CS_SNAPMODULE32 (0x10)
-            let snapshot = match CreateToolhelp32Snapshot(
-                CREATE_TOOLHELP_SNAPSHOT_FLAGS(0x00000008 | 0x00000010),
-                pid,
-            ) {
-                Ok(h) => h,
-                Err(_) => {
-                    thread::sleep(Duration::from_millis(100));
-                    continue;
-                }
-            };
+/// Lists the modules loaded in the process, `None` if it can't be inspected.
+pub fn list_modules(pid: u32) -> Option<Vec<LoadedModule>> {
+    module_snapshot(pid).ok()
+}
 
-            let mut entry = MODULEENTRY32W::default();
-            entry.dwSize = std::mem::size_of::<MODULEENTRY32W>() as u32;
+/// Lists the modules loaded in the process, or why it can't be inspected.
+fn module_snapshot(pid: u32) -> Result<Vec<LoadedModule>, ErrorCode> {
+    // TH32CS_SNAPMODULE (0x8) | TH32CS_SNAPMODULE32 (0x10)
+    let snapshot = snapshot(0x00000008 | 0x00000010, pid).ok_or_else(last_error)?;
+    let mut modules = Vec::new();
 
-            if Module32FirstW(snapshot, &mut entry).is_ok() {
-                loop {
-                    let current_module = String::from_utf16_lossy(&entry.szModule);
-                    let current_module = current_module.trim_matches('\0').to_lowercase();
+    unsafe {
+        let mut entry = MODULEENTRY32W::default();
+        entry.dwSize = std::mem::size_of::<MODULEENTRY32W>() as u32;
 
-                    if current_module == module_name_lower {
-                        info!("Module '{}' found and initialized!", module_name);
-                        let _ = windows::Win32::Foundation::CloseHandle(snapshot);
-                        return true;
-                    }
+        if Module32FirstW(snapshot.as_raw(), &mut entry).is_ok() {
+            loop {
+                modules.push(LoadedModule {
+                    base: entry.modBaseAddr as u64,
+                    size: entry.modBaseSize,
+                    path: PathBuf::from(wide::os_from_wide_nul(&entry.szExePath)),
+                });
 
-                    if Module32NextW(snapshot, &mut entry).is_err() {
-                        break;
-                    }
+                if Module32NextW(snapshot.as_raw(), &mut entry).is_err() {
+                    break;
                 }
             }
-            let _ = windows::Win32::Foundation::CloseHandle(snapshot);
         }
-        thread::sleep(Duration::from_millis(100));
     }
+    Ok(modules)
 }
 
 struct EnumData {
     target_pid: u32,
+    title: Option<String>,
+    class: Option<String>,
     found: bool,
 }
 
+impl EnumData {
+    fn matches(&self, hwnd: HWND) -> bool {
+        let (title, class) = window_text(hwnd);
+        self.title
+            .as_ref()
+            .is_none_or(|t| title.contains(t.as_str()))
+            && self.class.as_ref().is_none_or(|c| class == *c)
+    }
+}
+
 unsafe extern "system" fn enum_windows_callback(hwnd: HWND, lparam: LPARAM) -> BOOL {
     let data = unsafe { &mut *(lparam.0 as *mut EnumData) };
     let mut window_pid = 0u32;
     unsafe { GetWindowThreadProcessId(hwnd, Some(&mut window_pid)) };
 
-    if window_pid == data.target_pid && unsafe { IsWindowVisible(hwnd).as_bool() } {
+    if window_pid == data.target_pid
+        && unsafe { IsWindowVisible(hwnd).as_bool() }
+        && data.matches(hwnd)
+    {
         data.found = true;
         return BOOL::from(false); // Stop enumerating
     }
@@ -339,32 +1282,207 @@ unsafe extern "system" fn enum_windows_callback(hwnd: HWND, lparam: LPARAM) -> B
     BOOL::from(true) // Continue enumerating
 }
 
-#[allow(dead_code)]
-pub fn wait_for_window(ph: HANDLE) {
-    let target_pid = unsafe { GetProcessId(ph) };
-    debug!("Waiting for a visible window for PID {}...", target_pid);
+/// Returns false if the process is still busy after `timeout_ms`. Processes
+/// without a message queue count as idle.
+pub fn wait_for_input_idle(ph: HANDLE, timeout_ms: u32) -> bool {
+    unsafe { WaitForInputIdle(ph, timeout_ms) != WAIT_TIMEOUT.0 }
+}
 
-    let mut data = EnumData {
-        target_pid,
-        found: false,
-    };
+pub fn exit_code(ph: HANDLE) -> Option<u32> {
+    let mut code = 0;
+    unsafe { GetExitCodeProcess(ph, &mut code) }.ok()?;
+    Some(code)
+}
 
-    loop {
-        unsafe {
-            let _ = EnumWindows(
-                Some(enum_windows_callback),
-                LPARAM(&mut data as *mut EnumData as isize),
-            );
-        }
+pub fn wait_for_exit(ph: HANDLE, timeout_ms: u32) -> bool {
+    unsafe { WaitForSingleObject(ph, timeout_ms) == WAIT_OBJECT_0 }
+}
 
-        if data.found {
-            info!("Visible window found!");
-            break;
+/// Starts `executable` with `CREATE_SUSPENDED`, returns the process with its
+/// handle and the handle of its main thread.
+pub fn launch_suspended(
+    executable: &Path,
+    options: &LaunchOptions,
+) -> Result<(ProcessEntry, OwnedHandle, OwnedHandle), String> {
+    let invalid = |what: &str| format!("The {} contains a NUL character", what);
+    let application =
+        wide::to_wide_nul(executable.as_os_str()).ok_or_else(|| invalid("executable path"))?;
+    // The executable is repeated as the first argument, like a shell would
+    let mut command_line = OsString::from("\"");
+    command_line.push(executable);
+    command_line.push("\"");
+    if !options.arguments.is_empty() {
+        command_line.push(" ");
+        command_line.push(&options.arguments);
+    }
+    let mut command_line =
+        wide::to_wide_nul(&command_line).ok_or_else(|| invalid("command line"))?;
+    let working_dir = match options.working_dir() {
+        Some(dir) => {
+            Some(wide::to_wide_nul(dir.as_os_str()).ok_or_else(|| invalid("working directory"))?)
         }
-        thread::sleep(Duration::from_millis(200));
+        None => None,
+    };
+
+    let startup_info = STARTUPINFOW {
+        cb: mem::size_of::<STARTUPINFOW>() as u32,
+        ..Default::default()
+    };
+    let mut info = PROCESS_INFORMATION::default();
+    unsafe {
+        CreateProcessW(
+            PCWSTR(application.as_ptr()),
+            Some(PWSTR(command_line.as_mut_ptr())),
+            None,
+            None,
+            false,
+            CREATE_SUSPENDED,
+            None,
+            working_dir
+                .as_ref()
+                .map_or(PCWSTR::null(), |dir| PCWSTR(dir.as_ptr())),
+            &startup_info,
+            &mut info,
+        )
     }
+    .map_err(|e| format!("Failed to start {}: {}", executable.display(), e))?;
+    let (process, thread) = (OwnedHandle(info.hProcess), OwnedHandle(info.hThread));
+    info!(
+        "Started {} suspended (PID {})",
+        executable.display(),
+        info.dwProcessId
+    );
+
+    let mut entry = ProcessEntry {
+        pid: info.dwProcessId,
+        name: executable
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        parent_pid: unsafe { GetCurrentProcessId() },
+        ..Default::default()
+    };
+    read_process_details(&mut entry);
+    Ok((entry, process, thread))
 }
 
-pub fn wait_for_input_idle(ph: HANDLE, timeout_ms: u32) -> u32 {
-    unsafe { WaitForInputIdle(ph, timeout_ms) }
+pub fn resume_thread(thread: HANDLE) -> Result<(), String> {
+    if unsafe { ResumeThread(thread) } == u32::MAX {
+        return Err(last_error().to_string());
+    }
+    Ok(())
+}
+
+pub fn terminate_process(pid: u32) -> Result<(), String> {
+    let ph = unsafe { OpenProcess(PROCESS_TERMINATE | PROCESS_SYNCHRONIZE, false, pid) }
+        .map(OwnedHandle)
+        .map_err(|e| e.to_string())?;
+    unsafe { TerminateProcess(ph.as_raw(), 1) }.map_err(|e| e.to_string())?;
+    if !wait_for_exit(ph.as_raw(), TERMINATE_TIMEOUT_MS) {
+        return Err("The process did not exit in time".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Win32Backend;
+
+impl ProcessBackend for Win32Backend {
+    type Process = OwnedHandle;
+    type Thread = OwnedHandle;
+
+    fn find_processes(&self, matcher: &ProcessMatcher) -> Vec<ProcessEntry> {
+        find_processes(matcher)
+    }
+
+    fn open_process(&self, pid: u32) -> Option<OwnedHandle> {
+        open_process(pid)
+    }
+
+    fn list_processes(&self) -> Vec<ProcessEntry> {
+        list_processes()
+    }
+
+    fn has_window(&self, process: &OwnedHandle, title: Option<&str>, class: Option<&str>) -> bool {
+        has_window(process.as_raw(), title, class)
+    }
+
+    fn wait_for_input_idle(&self, process: &OwnedHandle, timeout_ms: u32) -> bool {
+        wait_for_input_idle(process.as_raw(), timeout_ms)
+    }
+
+    fn can_inject(&self, process: &OwnedHandle) -> bool {
+        can_inject(process.as_raw())
+    }
+
+    fn has_module(&self, process: &OwnedHandle, name: &str) -> bool {
+        has_module(process.as_raw(), name)
+    }
+
+    fn list_modules(&self, process: &OwnedHandle) -> Vec<LoadedModule> {
+        list_modules(unsafe { GetProcessId(process.as_raw()) }).unwrap_or_default()
+    }
+
+    fn process_directory(&self, process: &OwnedHandle) -> Option<PathBuf> {
+        get_process_directory(process.as_raw())
+    }
+
+    fn inject(
+        &self,
+        process: &OwnedHandle,
+        dll_path: &Path,
+        method: InjectionMethod,
+        options: &LoadOptions,
+    ) -> Result<LoadedModule, InjectError> {
+        let target = process_machine(process.as_raw()).unwrap_or_else(Machine::current);
+        pe::check_dll(dll_path, target).map_err(InjectError::InvalidDll)?;
+
+        unsafe { inject_dll_to_handle(process.as_raw(), dll_path, options, injector(method)) }
+    }
+
+    fn call_export(
+        &self,
+        process: &OwnedHandle,
+        module: &LoadedModule,
+        export: &str,
+        argument: Option<&[u8]>,
+        methods: &[InjectionMethod],
+    ) -> Result<u32, InjectError> {
+        unsafe { call_export(process.as_raw(), module, export, argument, methods) }
+    }
+
+    fn eject(
+        &self,
+        process: &OwnedHandle,
+        dll_path: &Path,
+        methods: &[InjectionMethod],
+        shutdown_export: Option<&str>,
+    ) -> Result<(), InjectError> {
+        unsafe { eject_dll_from_handle(process.as_raw(), dll_path, methods, shutdown_export) }
+    }
+
+    fn wait_for_exit(&self, process: &OwnedHandle, timeout_ms: u32) -> bool {
+        wait_for_exit(process.as_raw(), timeout_ms)
+    }
+
+    fn exit_code(&self, process: &OwnedHandle) -> Option<u32> {
+        exit_code(process.as_raw())
+    }
+
+    fn launch(
+        &self,
+        executable: &Path,
+        options: &LaunchOptions,
+    ) -> Result<(ProcessEntry, OwnedHandle, OwnedHandle), String> {
+        launch_suspended(executable, options)
+    }
+
+    fn resume(&self, thread: &OwnedHandle) -> Result<(), String> {
+        resume_thread(thread.as_raw())
+    }
+
+    fn terminate(&self, pid: u32) -> Result<(), String> {
+        terminate_process(pid)
+    }
 }